@@ -1,3 +1,6 @@
 fn main() {
-    embuild::espidf::sysenv::output();
+    // Only the firmware links against ESP-IDF, the simulator builds for the host
+    if std::env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("espidf") {
+        embuild::espidf::sysenv::output();
+    }
 }
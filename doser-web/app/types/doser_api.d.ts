@@ -66,3 +66,39 @@ export interface DoseSolutionReq {
 export interface OtaReq {
   uri: URL;
 }
+
+export interface MotorMotion {
+  job_id?: number;
+  motor_idx: number;
+  motor_id: number;
+  ml?: number; // Requested on start, dispensed on finish
+  error?: string;
+}
+
+export interface DosedAmount {
+  name: string;
+  motor_id: number;
+  ml_requested: number;
+  ml_dispensed: number;
+}
+
+export interface DoseRecord {
+  seq: number;
+  time: number | null; // Unix time, null if the doser's clock wasn't set
+  kind: string;
+  trigger: 'API' | 'SCHEDULE';
+  schedule?: string;
+  recipe?: string;
+  nutrients: DosedAmount[];
+  result: string;
+  error?: string;
+}
+
+export interface DoserEvents {
+  status?: (status: string) => void;
+  motor_started?: (motion: MotorMotion) => void;
+  motor_finished?: (motion: MotorMotion) => void;
+  dose?: (record: DoseRecord) => void;
+  ota_progress?: (percent: number) => void;
+  app_error?: (message: string) => void;
+}
@@ -1,4 +1,4 @@
-import type { DoserInfo } from '~~/shared/types/doser';
+import type { DiscoveredDoser, DoserInfo } from '~~/shared/types/doser';
 
 export async function get_dosers(): Promise<DoserInfo[]> {
   return await $fetch<DoserInfo[]>('/api/dosers');
@@ -17,3 +17,7 @@ export async function remove_doser(host: string) {
     body: { hostname: host },
   });
 }
+
+export async function discover_dosers(): Promise<DiscoveredDoser[]> {
+  return await $fetch<DiscoveredDoser[]>('/api/discover');
+}
@@ -2,6 +2,7 @@ import type {
   CalibrateReq,
   DebugStepReq,
   DispenseReq,
+  DoserEvents,
   DoseSolutionReq,
   OtaReq,
   StatusResp,
@@ -19,6 +20,24 @@ export async function status(url_base: string) {
   return (await $fetch<StatusResp>(`${url_base}/status`)).status;
 }
 
+/** Follows `/events` instead of polling `/status`, close the returned source when done */
+export function events(url_base: string, handlers: DoserEvents) {
+  const source = new EventSource(`${url_base}/events`);
+  const on = (name: keyof DoserEvents, pick: (data: any) => unknown) => {
+    const handler = handlers[name] as ((value: unknown) => void) | undefined;
+    if (handler) {
+      source.addEventListener(name, (e) => handler(pick(JSON.parse((e as MessageEvent).data))));
+    }
+  };
+  on('status', (d) => d.status);
+  on('motor_started', (d) => d);
+  on('motor_finished', (d) => d);
+  on('dose', (d) => d);
+  on('ota_progress', (d) => d.percent);
+  on('app_error', (d) => d.message);
+  return source;
+}
+
 export function dispense(url_base: string, req: DispenseReq) {
   return $fetch(`${url_base}/dispense`, {
     method: 'POST',
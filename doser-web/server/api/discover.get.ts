@@ -0,0 +1,6 @@
+import type { DiscoveredDoser } from '~~/shared/types/doser';
+import { browse } from '../utils/mdns';
+
+export default defineEventHandler(async (): Promise<DiscoveredDoser[]> => {
+  return await browse();
+});
@@ -0,0 +1,189 @@
+import { createSocket } from 'node:dgram';
+import type { DiscoveredDoser } from '~~/shared/types/doser';
+
+// Advertised by the firmware, see `src/discovery.rs`
+export const SERVICE = '_nutrient-doser._tcp.local';
+
+const MDNS_ADDR = '224.0.0.251';
+const MDNS_PORT = 5353;
+
+const TYPE_A = 1;
+const TYPE_PTR = 12;
+const TYPE_TXT = 16;
+const TYPE_SRV = 33;
+
+interface MdnsRecord {
+  name: string;
+  type: number;
+  data: Buffer;
+  offset: number; // of `data` in the packet, names inside it can point back
+}
+
+/** Reads a possibly compressed name, returns it and where the next field starts */
+function read_name(packet: Buffer, offset: number): [string, number] {
+  const labels: string[] = [];
+  let end = -1;
+  for (let jumps = 0; jumps < 16; ) {
+    const len = packet.readUInt8(offset);
+    if (len === 0) {
+      return [labels.join('.'), end < 0 ? offset + 1 : end];
+    }
+    if ((len & 0xc0) === 0xc0) {
+      if (end < 0) end = offset + 2;
+      offset = packet.readUInt16BE(offset) & 0x3fff;
+      jumps++;
+      continue;
+    }
+    labels.push(packet.toString('utf8', offset + 1, offset + 1 + len));
+    offset += len + 1;
+  }
+  throw new Error('Name compression loop');
+}
+
+/** Answers and additional records of a response, questions are skipped */
+export function parse_records(packet: Buffer): MdnsRecord[] {
+  const is_response = (packet.readUInt8(2) & 0x80) !== 0;
+  if (!is_response) return [];
+  const questions = packet.readUInt16BE(4);
+  const records = packet.readUInt16BE(6) + packet.readUInt16BE(8) + packet.readUInt16BE(10);
+
+  let offset = 12;
+  for (let i = 0; i < questions; i++) {
+    offset = read_name(packet, offset)[1] + 4;
+  }
+  const out: MdnsRecord[] = [];
+  for (let i = 0; i < records; i++) {
+    const [name, next] = read_name(packet, offset);
+    const type = packet.readUInt16BE(next);
+    const len = packet.readUInt16BE(next + 8);
+    const start = next + 10;
+    out.push({
+      name: name.toLowerCase(),
+      type,
+      data: packet.subarray(start, start + len),
+      offset: start,
+    });
+    offset = start + len;
+  }
+  return out;
+}
+
+/** PTR query for `service`, asking for unicast replies */
+export function build_query(service: string): Buffer {
+  const name = service.split('.').map((l) => {
+    const label = Buffer.from(l);
+    return Buffer.concat([Buffer.from([label.length]), label]);
+  });
+  return Buffer.concat([
+    Buffer.from([0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0]), // id 0, one question
+    ...name,
+    Buffer.from([0, 0, TYPE_PTR, 0x80, 1]), // PTR, IN with the unicast bit
+  ]);
+}
+
+function parse_txt(data: Buffer): { [key: string]: string } {
+  const txt: { [key: string]: string } = {};
+  for (let offset = 0; offset < data.length; ) {
+    const len = data.readUInt8(offset);
+    const entry = data.toString('utf8', offset + 1, offset + 1 + len);
+    const eq = entry.indexOf('=');
+    if (eq > 0) txt[entry.slice(0, eq)] = entry.slice(eq + 1);
+    offset += len + 1;
+  }
+  return txt;
+}
+
+/** Puts together every doser the replies describe, skipping ones missing their SRV record */
+export function collect_dosers(packets: Buffer[], service: string = SERVICE): DiscoveredDoser[] {
+  const instances = new Map<string, string>(); // Lowercased to how it's spelled
+  const srv = new Map<string, { host: string; port: number }>();
+  const txt = new Map<string, { [key: string]: string }>();
+  const addrs = new Map<string, string>();
+
+  // Every device on the LAN answers on the group, anything truncated or
+  // garbage is skipped a record at a time
+  for (const packet of packets) {
+    let records: MdnsRecord[];
+    try {
+      records = parse_records(packet);
+    } catch {
+      continue;
+    }
+    for (const r of records) {
+      try {
+        switch (r.type) {
+          case TYPE_PTR:
+            if (r.name === service.toLowerCase()) {
+              const instance = read_name(packet, r.offset)[0];
+              instances.set(instance.toLowerCase(), instance);
+            }
+            break;
+          case TYPE_SRV:
+            srv.set(r.name, {
+              port: r.data.readUInt16BE(4),
+              host: read_name(packet, r.offset + 6)[0].toLowerCase(),
+            });
+            break;
+          case TYPE_TXT:
+            txt.set(r.name, parse_txt(r.data));
+            break;
+          case TYPE_A:
+            if (r.data.length === 4) addrs.set(r.name, [...r.data].join('.'));
+            break;
+        }
+      } catch {
+        continue;
+      }
+    }
+  }
+
+  const dosers: DiscoveredDoser[] = [];
+  for (const [instance, spelled] of instances) {
+    const target = srv.get(instance);
+    if (!target) continue;
+    const info = txt.get(instance) ?? {};
+    const address = addrs.get(target.host);
+    const port = target.port === 80 ? '' : `:${target.port}`;
+    dosers.push({
+      name: spelled.slice(0, spelled.length - service.length - 1),
+      hostname: target.host,
+      url: `http://${address ?? target.host}${port}`,
+      id: info.id ?? '',
+      version: info.version ?? '',
+      motors: Number(info.motors ?? 0),
+    });
+  }
+  return dosers;
+}
+
+/** Asks the LAN for dosers and collects whatever answers within `wait_ms` */
+export function browse(wait_ms: number = 1500): Promise<DiscoveredDoser[]> {
+  return new Promise((resolve, reject) => {
+    const packets: Buffer[] = [];
+    const socket = createSocket({ type: 'udp4', reuseAddr: true });
+    let timer: ReturnType<typeof setTimeout> | undefined;
+    socket.on('message', (msg) => packets.push(msg));
+    socket.on('error', (err) => {
+      clearTimeout(timer);
+      socket.close();
+      reject(err);
+    });
+    // Responders may still answer on the multicast group
+    socket.bind(MDNS_PORT, () => {
+      try {
+        socket.addMembership(MDNS_ADDR);
+      } catch {
+        // Unicast replies still make it
+      }
+      socket.send(build_query(SERVICE), MDNS_PORT, MDNS_ADDR);
+      timer = setTimeout(() => {
+        socket.close();
+        try {
+          resolve(collect_dosers(packets));
+        } catch (err) {
+          reject(err);
+        }
+      }, wait_ms);
+    });
+  });
+}
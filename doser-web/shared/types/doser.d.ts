@@ -9,3 +9,13 @@ export interface DoserInfo {
   motors: MotorConfig[];
   chart: string;
 }
+
+// Doser answering the mDNS browse for `_nutrient-doser._tcp`
+export interface DiscoveredDoser {
+  name: string;
+  hostname: string;
+  url: string;
+  id: string;
+  version: string;
+  motors: number;
+}
@@ -1,75 +1,293 @@
-use std::{sync::Arc, time::Duration};
-
-use embedded_svc::http::{
-    client::{Client, Response},
-    Headers, Method,
-};
-use http::header::ACCEPT;
+use std::{collections::BTreeMap, convert::Infallible, fmt::Debug, sync::Arc, time::Duration};
 
 use axum::{
     body::Body,
-    extract::{FromRef, State},
-    http::{Request, StatusCode, Uri},
+    extract::State,
+    http::{header, Request, StatusCode},
     middleware::Next,
-    routing::{get, post},
-    Json, Router,
-};
-use esp_idf_svc::{
-    hal::reset::restart,
-    http::client::{Configuration, EspHttpConnection},
-    nvs::{EspCustomNvs, EspCustomNvsPartition},
-    ota::{EspOta, FirmwareInfo},
-    sys::{EspError, ESP_ERR_IMAGE_INVALID, ESP_ERR_INVALID_RESPONSE, ESP_FAIL},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse,
+    },
+    routing::{delete, get, post},
+    Router,
 };
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::hal::reset::restart;
 
-use log::{error, info};
-use mime::APPLICATION_OCTET_STREAM;
-use serde::{Deserialize, Serialize};
+use futures_util::{stream, Stream};
+use log::{error, info, warn};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use tokio::{
     net::TcpListener,
-    sync::{Mutex, RwLock, mpsc, oneshot}, time::interval,
+    sync::{broadcast, Mutex, MutexGuard, RwLock, mpsc}, time::{sleep_until, timeout, Instant},
 };
 use tower_http::cors::{self, CorsLayer};
 
-use crate::rmt_drv8825::DRV8825;
-
-#[macro_export]
-macro_rules! esp_err {
-    ($x:ident) => {
-        Err(EspError::from_infallible::<$x>())
-    };
-}
-
-const FIRMWARE_DOWNLOAD_CHUNK_SIZE: usize = 1024 * 20;
-const FIRMWARE_MAX_SIZE: usize  = 0x3f0000; // Max size of each app partition
-const FIRMWARE_MIN_SIZE: usize  = size_of::<FirmwareInfo>() + 1024;
+#[cfg(target_os = "espidf")]
+use crate::ota::do_ota;
+use crate::{
+    calibration::{self, Fit, Session, SessionPoint},
+    clock::{self, DEFAULT_TZ},
+    error::{ApiError, ErrorCode, Json, Path, Query, Violations},
+    feedchart::{self, check_strength, Grow, NutrientInfo, Recipe},
+    history::{self, DoseRecord, DosedAmount, History, Origin},
+    jobs::{Job, JobEntry, JobId, JobKind, JobState, JobStatus, Jobs},
+    labels::{MotorLabel, MotorSel},
+    mqtt::{self, MqttConfig},
+    network,
+    positions::{PositionLog, SavedPosition, FLUSH_DELAY},
+    scale::{LoadCell, Scale, ScaleCal},
+    schedule::{self, DoseSchedule, NextRun},
+    stepper::{MotionCtl, MotionProfile, Stepper},
+    storage::Storage,
+};
 
 const BIND_IP: &str = "0.0.0.0";
-const PORT: u16 = 80;
+pub const PORT: u16 = 80;
 
 pub const NVS_NS: &str = "storage";
 const NVS_TAG_MOTORS: &str = "motors";
+const NVS_TAG_POSITIONS: &str = "positions";
+const NVS_TAG_UNPRIME: &str = "unprime_policy";
+const NVS_TAG_TIMEZONE: &str = "timezone";
+const NVS_TAG_LABELS: &str = "labels";
+const NVS_TAG_LEVELS: &str = "level_policy";
+const NVS_TAG_SCALE: &str = "scale";
+const NVS_TAG_LIMITS: &str = "dose_limits";
+
+// NVS strings top out at 4000 bytes, including the terminating nul
+const MAX_STORED_LEN: usize = 3999;
+
+// Upper bound on how long the scheduler sleeps, so it notices the clock
+// getting set or jumping
+const SCHEDULE_POLL: Duration = Duration::from_secs(60);
+
+
+// Each listener on the events keeps a socket open, and the ESP32 only has a
+// handful. The MQTT connection counts as one.
+const MAX_EVENT_LISTENERS: usize = 4;
+
+/// When to unprime motors on their own after the last motion
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(default)]
+struct UnprimePolicy {
+    enabled: bool,
+    timeout_mins: u32,
+}
+
+impl Default for UnprimePolicy {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            timeout_mins: 30,
+        }
+    }
+}
+
+impl UnprimePolicy {
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_mins as u64 * 60)
+    }
+}
+
+/// When a bottle counts as running low, and whether to refuse doses that
+/// need more than what's left in it
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(default)]
+pub(crate) struct LevelPolicy {
+    pub(crate) low_ml: f64,
+    refuse_overdraw: bool, // Only warn if false
+}
+
+impl Default for LevelPolicy {
+    fn default() -> Self {
+        Self {
+            low_ml: 100.0,
+            refuse_overdraw: true,
+        }
+    }
+}
+
+/// Upper bounds on what a single request can ask of the motors, catching
+/// typos like an extra zero before anything moves
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(default)]
+pub(crate) struct DoseLimits {
+    pub(crate) max_motor_ml: f64,   // Most one motor may dispense in one request
+    max_request_ml: f64, // Most all motors together may dispense in one request
+    max_steps: f64,      // Longest move of a step or prime request, in full steps
+}
+
+impl Default for DoseLimits {
+    fn default() -> Self {
+        Self {
+            max_motor_ml: 500.0,
+            max_request_ml: 2000.0,
+            max_steps: 100_000.0,
+        }
+    }
+}
+
+/// Changes to the limits, anything left out stays as it is
+#[derive(Deserialize)]
+struct DoseLimitsUpdate {
+    max_motor_ml: Option<f64>,
+    max_request_ml: Option<f64>,
+    max_steps: Option<f64>,
+}
+
+impl DoseLimitsUpdate {
+    fn apply(&self, limits: DoseLimits) -> DoseLimits {
+        DoseLimits {
+            max_motor_ml: self.max_motor_ml.unwrap_or(limits.max_motor_ml),
+            max_request_ml: self.max_request_ml.unwrap_or(limits.max_request_ml),
+            max_steps: self.max_steps.unwrap_or(limits.max_steps),
+        }
+    }
+}
+
+impl DoseLimits {
+    fn validate(&self) -> Result<(), String> {
+        for (name, value) in [
+            ("max_motor_ml", self.max_motor_ml),
+            ("max_request_ml", self.max_request_ml),
+            ("max_steps", self.max_steps),
+        ] {
+            if !(value.is_finite() && value > 0.0) {
+                return Err(format!("{name} must be positive, got {value}"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the volumes of a request, given as `(entry, motor_idx, ml)`.
+    /// A motor that's over its limit is reported on its first entry.
+    fn check(&self, amounts: &[(usize, usize, f64)], labels: &[MotorLabel], violations: &mut Violations) {
+        let mut per_motor: BTreeMap<usize, (usize, f64)> = BTreeMap::new();
+        for &(entry, idx, ml) in amounts {
+            per_motor.entry(idx).or_insert((entry, 0.0)).1 += ml;
+        }
+        for (idx, (entry, ml)) in per_motor {
+            if ml > self.max_motor_ml {
+                let e = format!("Motor {} would dispense {ml:.1} mL, the limit is {} mL", labels[idx].id, self.max_motor_ml);
+                violations.add(Some(entry), ApiError::invalid_volume(e));
+            }
+        }
+        let total: f64 = amounts.iter().map(|(_, _, ml)| ml).sum();
+        if total > self.max_request_ml {
+            let e = format!("Request would dispense {total:.1} mL, the limit is {} mL", self.max_request_ml);
+            violations.add(None, ApiError::invalid_volume(e));
+        }
+    }
+
+    fn check_steps(&self, steps: f64) -> Result<(), ApiError> {
+        match steps.is_finite() && steps.abs() <= self.max_steps {
+            true => Ok(()),
+            false => Err(ApiError::invalid(format!("Can't move {steps} steps, the limit is {}", self.max_steps))),
+        }
+    }
+}
+
+/// Volumes have to be an actual amount, a negative one would run the motor backwards
+pub(crate) fn check_ml(ml: f64) -> Result<f64, ApiError> {
+    match ml.is_finite() && ml > 0.0 {
+        true => Ok(ml),
+        false => Err(ApiError::invalid_volume(format!("Volume must be a positive number of mL, got {ml}"))),
+    }
+}
+
+/// A calibration that isn't a positive amount per step would run the motor
+/// away or backwards on the next dose
+fn check_ml_per_step(ml_per_step: f64) -> Result<f64, ApiError> {
+    match ml_per_step.is_finite() && ml_per_step > 0.0 {
+        true => Ok(ml_per_step),
+        false => Err(ApiError::invalid(format!("Calibration must be a positive number of mL per step, got {ml_per_step}"))),
+    }
+}
+
+/// Retraction after a dose, pulling the liquid back from the nozzle so the
+/// pressure left in the tubing doesn't make it drip
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(default)]
+struct BackOff {
+    steps: f64,       // How far to retract, in full steps
+    ml: Option<f64>,  // Retract by volume instead, follows the calibration
+    readvance: bool,  // Push the retracted liquid back up to the nozzle before the next dose
+    dwell_ms: u64,    // Wait for the flow to settle before retracting
+}
+
+impl Default for BackOff {
+    fn default() -> Self {
+        Self {
+            steps: 200.0,
+            ml: None,
+            readvance: false,
+            dwell_ms: 0,
+        }
+    }
+}
+
+impl BackOff {
+    fn validate(&self) -> Result<(), String> {
+        if !(self.steps.is_finite() && self.steps >= 0.0) {
+            return Err(format!("steps must not be negative, got {}", self.steps));
+        }
+        match self.ml {
+            Some(ml) if !(ml.is_finite() && ml >= 0.0) => Err(format!("ml must not be negative, got {ml}")),
+            _ => Ok(()),
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize)]
-struct StepperMotor {
+#[serde(bound = "")] // the driver is skipped, don't require anything of S
+struct StepperMotor<S: Stepper> {
     id: u32, // using the EN pin # to identify motors
-    #[serde(skip)] driver: Option<DRV8825>,
+    #[serde(skip)] driver: Option<S>,
     ml_per_step: f64, // Estimation of the amount of liquid dispensed per full step
     prime_steps: u32, // Number of steps needed to pull liquid through all the tubing up to the nozzle
+    #[serde(default)] profile: MotionProfile,
+    #[serde(default)] ml_carry: f64, // Volume owed from earlier doses that didn't add up to a whole microstep
+    #[serde(default)] back_off: BackOff,
+    #[serde(default)] retracted_steps: f64, // How far the liquid currently sits back from the nozzle
+    #[serde(skip)] prime_unknown: bool, // Rebooted mid-motion, needs an unprime before dosing again
+    #[serde(default = "default_auto_unprime")] auto_unprime: bool, // Opt out of the auto-unprime policy
+    #[serde(default, serialize_with = "calibration::coefficients_only")] calibration: Option<Fit>, // Takes over from ml_per_step when set
 }
 
-impl Default for StepperMotor {
+fn default_auto_unprime() -> bool {
+    true
+}
+
+impl<S: Stepper> Default for StepperMotor<S> {
     fn default() -> Self {
         Self {
             id: Default::default(),
             driver: None,
             ml_per_step: 0.0032,  // From testing, this should be pretty close to start with
             prime_steps: 0,
+            profile: Default::default(),
+            ml_carry: 0.0,
+            back_off: Default::default(),
+            retracted_steps: 0.0,
+            prime_unknown: false,
+            auto_unprime: true,
+            calibration: None,
         }
     }
 }
 
-impl StepperMotor {
+impl<S: Stepper> StepperMotor<S> {
+    /// Hook up the driver for this config entry, applying the saved profile to it
+    fn attach(&mut self, mut drv: S) {
+        if let Err(e) = drv.set_profile(self.profile) {
+            error!("Saved motion profile for motor {} is invalid, using defaults: {e}", self.id);
+            self.profile = *drv.profile();
+        }
+        self.driver = Some(drv);
+    }
+
     fn is_primed(&self) -> bool {
         match &self.driver {
             Some(drv) => drv.get_position() > 0,
@@ -77,188 +295,1223 @@ impl StepperMotor {
         }
     }
 
-    async fn ensure_primed(&mut self) {
-        if !self.is_primed() {
+    fn prime_state(&self) -> PrimeState {
+        match (self.prime_unknown, self.is_primed()) {
+            (true, _) => PrimeState::Unknown,
+            (false, true) => PrimeState::Primed,
+            (false, false) => PrimeState::Unprimed,
+        }
+    }
+
+    fn saved_position(&self) -> SavedPosition {
+        SavedPosition {
+            id: self.id,
+            position: self.driver.as_ref().map_or(0, |drv| drv.get_position()),
+            moving: self.prime_unknown,
+        }
+    }
+
+    /// Picks up where it was before a reboot. A record written before a
+    /// motion that never finished doesn't say where the liquid is.
+    fn restore(&mut self, saved: &SavedPosition) {
+        if saved.moving {
+            error!("Motor {} was moving when the device went down, prime state unknown", self.id);
+            self.prime_unknown = true;
+        } else if let Some(drv) = &mut self.driver {
+            info!("Restored motor {} to position {}", self.id, saved.position);
+            drv.set_position(saved.position);
+        }
+    }
+
+    /// Liquid sitting in the tubing, which came out of the bottle but hasn't been dispensed
+    fn tube_ml(&self) -> f64 {
+        match self.is_primed() {
+            true => self.ml_for_steps((self.prime_steps as f64 - self.retracted_steps).max(0.0)),
+            false => 0.0,
+        }
+    }
+
+    /// Liquid it takes out of the bottle to fill the tubing up to the nozzle,
+    /// before anything comes out
+    fn fill_ml(&self) -> f64 {
+        self.ml_for_steps(self.prime_steps as f64) - self.tube_ml()
+    }
+
+    async fn ensure_primed(&mut self, ctl: &MotionCtl) -> Result<(), ApiError> {
+        if !self.is_primed() && !self.prime_unknown {
             if let Some(drv) = &mut self.driver {
                 info!("Priming motor {}", self.id);
-                drv.step_by(self.prime_steps as f64).await.unwrap()
+                drv.move_by(self.prime_steps as f64, ctl).await.map_err(fault(self.id))?;
             }
         }
+        Ok(())
     }
 
-    async fn unprime(&mut self) {
+    async fn unprime(&mut self, ctl: &MotionCtl) -> Result<(), ApiError> {
         info!("Unpriming motor {}", self.id);
         if let Some(drv) = &mut self.driver {
-            drv.step_by(-2.0 * self.prime_steps as f64).await.unwrap();
-            drv.reset_position();
+            drv.move_by(-2.0 * self.prime_steps as f64, ctl).await.map_err(fault(self.id))?;
+            if !ctl.is_cancelled() {
+                drv.reset_position();
+                self.retracted_steps = 0.0;
+                self.prime_unknown = false;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whole microsteps to move for `ml`, including what's carried over from
+    /// earlier doses. Without a readvance the dose also has to refill what the
+    /// last back-off pulled out of the tubing.
+    fn steps_for_ml(&self, ml: f64) -> f64 {
+        let refill = match self.back_off.readvance {
+            true => 0.0,
+            false => self.retracted_steps,
+        };
+        self.steps_at(ml + self.ml_carry, self.profile.max_rpm) + refill
+    }
+
+    fn steps_at(&self, ml: f64, rpm: f64) -> f64 {
+        let steps = match &self.calibration {
+            Some(fit) => fit.steps_for_ml(ml, rpm),
+            None => ml / self.ml_per_step,
+        };
+        self.profile.microsteps.quantize(steps)
+    }
+
+    fn ml_for_steps(&self, steps: f64) -> f64 {
+        match &self.calibration {
+            Some(fit) => fit.ml_for_steps(steps, self.profile.max_rpm),
+            None => steps * self.ml_per_step,
         }
     }
 
-    async fn dispense_ml(&mut self, ml: f64) {
-        self.ensure_primed().await;
+    /// Replace the single scalar calibration with a fitted one
+    fn set_calibration(&mut self, fit: Option<Fit>) {
+        if let Some(fit) = &fit {
+            self.ml_per_step = fit.ml_per_step_at(self.profile.max_rpm);
+        }
+        self.calibration = fit;
+    }
+
+    fn back_off_steps(&self) -> f64 {
+        match self.back_off.ml {
+            Some(ml) => self.steps_at(ml, self.profile.max_rpm),
+            None => self.profile.microsteps.quantize(self.back_off.steps),
+        }
+    }
+
+    /// Returns the volume actually dispensed, which falls short of `ml` if
+    /// `ctl` gets cancelled. Priming and re-advancing after the last back-off
+    /// don't count towards the progress in `ctl`.
+    async fn dispense_ml(&mut self, ml: f64, ctl: &MotionCtl) -> Result<f64, ApiError> {
+        check_ml(ml)?;
+        if self.prime_unknown {
+            return Err(ApiError::busy(format!("Motor {} doesn't know where the liquid is, unprime it first", self.id)));
+        }
+        let target = ml + self.ml_carry;
+        let (moved, refilled) = self.push_steps(self.steps_for_ml(ml), ctl).await?;
+
+        // Whatever didn't make up a whole microstep goes out with the
+        // next dose, a cancelled dose doesn't owe anything
+        let dispensed = self.ml_for_steps(moved - refilled);
+        self.ml_carry = match ctl.is_cancelled() {
+            true => 0.0,
+            false => (target - dispensed).max(0.0),
+        };
+        Ok(dispensed)
+    }
+
+    /// Dispense a fixed number of steps at `rpm` for calibration, returns how
+    /// many of them came out of the nozzle
+    async fn test_dispense(&mut self, steps: f64, rpm: f64, ctl: &MotionCtl) -> Result<f64, ApiError> {
+        if self.prime_unknown {
+            return Err(ApiError::busy(format!("Motor {} doesn't know where the liquid is, unprime it first", self.id)));
+        }
+        let Some(drv) = &mut self.driver else {
+            return Err(ApiError::new(ErrorCode::HardwareFault, format!("Motor {} has no driver", self.id)));
+        };
+        let profile = *drv.profile();
+        drv.set_profile(MotionProfile { max_rpm: rpm, ..profile }).map_err(ApiError::invalid)?;
+        let res = self.push_steps(steps, ctl).await;
         if let Some(drv) = &mut self.driver {
-            drv.step_by((ml / self.ml_per_step).floor() as f64)
-                .await
-                .unwrap();
+            drv.set_profile(profile).map_err(ApiError::invalid)?;
+        }
+        let (moved, refilled) = res?;
+        Ok(moved - refilled)
+    }
 
-            // Back off slightly to prevent extra liquid dripping out from pressure
-            drv.step_by(-200.0).await.unwrap();
+    /// Move `steps` forward with the back-off around it, returns how far it
+    /// moved and how much of that only refilled the tubing
+    async fn push_steps(&mut self, steps: f64, ctl: &MotionCtl) -> Result<(f64, f64), ApiError> {
+        self.ensure_primed(&ctl.child()).await?;
+        let back_off = self.back_off_steps();
+        let Some(drv) = &mut self.driver else {
+            return Ok((0.0, 0.0));
+        };
+
+        if self.back_off.readvance && self.retracted_steps > 0.0 && !ctl.is_cancelled() {
+            let moved = drv.move_by(self.retracted_steps, &ctl.child()).await.map_err(fault(self.id))?;
+            self.retracted_steps -= moved;
+        }
+        if ctl.is_cancelled() {
+            return Ok((0.0, 0.0));
+        }
+
+        // Anything still retracted only refills the tubing instead of coming out
+        let moved = drv.move_by(steps, ctl).await.map_err(fault(self.id))?;
+        let refilled = moved.min(self.retracted_steps);
+        self.retracted_steps -= refilled;
+
+        // Back off to prevent extra liquid dripping out from pressure, still
+        // wanted if the job was cancelled but not on an emergency stop
+        if self.back_off.dwell_ms > 0 && !ctl.is_stopped() {
+            tokio::time::sleep(Duration::from_millis(self.back_off.dwell_ms)).await;
         }
+        let retracted = drv.move_by(-back_off, &ctl.fork()).await.map_err(fault(self.id))?;
+        self.retracted_steps -= retracted;
+        Ok((moved, refilled))
     }
 }
 
-#[derive(Serialize, Clone, Copy)]
-enum AppStatus {
+/// Turns a driver error into the API's, naming the motor
+fn fault<E: Debug>(id: u32) -> impl FnOnce(E) -> ApiError {
+    move |e| ApiError::new(ErrorCode::HardwareFault, format!("Motor {id} failed: {e:?}"))
+}
+
+pub(crate) fn storage_error(key: &str, e: impl std::fmt::Display) -> ApiError {
+    error!("Failed to write {key} to nvs: {e}");
+    ApiError::new(ErrorCode::StorageError, format!("Couldn't save {key}: {e}"))
+}
+
+/// Refuses what wouldn't fit in a single NVS string, before anything is written
+fn check_stored_len(key: &str, value: &str) -> Result<(), ApiError> {
+    match value.len() <= MAX_STORED_LEN {
+        true => Ok(()),
+        false => Err(ApiError::invalid(format!(
+            "Too much to store in {key}, {} bytes out of {MAX_STORED_LEN}, remove something first",
+            value.len()
+        ))),
+    }
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "UPPERCASE")]
+pub(crate) enum PrimeState {
+    Primed,
+    Unprimed,
+    Unknown, // Rebooted while the motor was moving
+}
+
+// Named the way the API spells them
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Serialize, Clone, Copy, PartialEq)]
+pub(crate) enum AppStatus {
     IDLE,
     RUNNING,
-    OTA
+    #[cfg_attr(not(target_os = "espidf"), allow(dead_code))]
+    OTA,
+    STOPPED, // Emergency stop, latched until cleared
+}
+
+/// Changes pushed to whoever listens on `AppState::events`
+#[derive(Clone)]
+pub(crate) enum AppEvent {
+    Status(AppStatus),
+    Motors, // Prime state or bottle levels may have changed
+    MotorStarted(MotorMotion),
+    MotorFinished(MotorMotion),
+    #[cfg_attr(not(target_os = "espidf"), allow(dead_code))]
+    OtaProgress(u8), // percent
+    Error(String),
+    Dosed(DoseRecord),
+}
+
+impl AppEvent {
+    /// How `/events` sends it, if it does
+    fn sse(&self) -> Option<Event> {
+        let (name, data) = match self {
+            AppEvent::Status(status) => ("status", serde_json::json!({ "status": status })),
+            AppEvent::Motors => return None, // Only tells MQTT to publish the state again
+            AppEvent::MotorStarted(m) => ("motor_started", serde_json::json!(m)),
+            AppEvent::MotorFinished(m) => ("motor_finished", serde_json::json!(m)),
+            AppEvent::OtaProgress(percent) => ("ota_progress", serde_json::json!({ "percent": percent })),
+            // Not `error`, EventSource fires that for connection errors too
+            AppEvent::Error(message) => ("app_error", serde_json::json!({ "message": message })),
+            AppEvent::Dosed(record) => ("dose", serde_json::json!(record)),
+        };
+        Some(Event::default().event(name).data(data.to_string()))
+    }
+}
+
+/// One motor moving, on its own or as part of a job
+#[derive(Serialize, Clone)]
+pub(crate) struct MotorMotion {
+    job_id: Option<JobId>, // None outside of a job
+    motor_idx: usize,
+    motor_id: u32,
+    ml: Option<f64>, // Requested when starting, dispensed when finished. None if it isn't a dispense.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl MotorMotion {
+    fn new(job_id: Option<JobId>, motor_idx: usize, motor_id: u32, ml: Option<f64>) -> Self {
+        Self {
+            job_id,
+            motor_idx,
+            motor_id,
+            ml,
+            error: None,
+        }
+    }
+
+    /// Same motion, now that it's done
+    fn finished<T>(self, ml: Option<f64>, res: &Result<T, ApiError>) -> AppEvent {
+        AppEvent::MotorFinished(Self {
+            ml,
+            error: res.as_ref().err().map(|e| e.message.clone()),
+            ..self
+        })
+    }
 }
 
 // type SharedState = Arc<Mutex<AppState>>;
 // type SharedStatus = Arc<RwLock<AppStatus>>;
-#[derive(Clone, FromRef)]
-struct AppState {
-    motors: Arc<Mutex<Vec<StepperMotor>>>,
-    nvs: Arc<RwLock<EspCustomNvs>>,
-    status: Arc<RwLock<AppStatus>>,
+pub(crate) struct AppState<S: Stepper> {
+    motors: Arc<Mutex<Vec<StepperMotor<S>>>>,
+    snapshots: Arc<std::sync::Mutex<Vec<MotorSnapshot>>>, // as of the last change, readable while a job runs
+    num_motors: usize, // fixed after startup, lets requests be checked without waiting on the motors lock
+    pub(crate) nvs: Arc<RwLock<Box<dyn Storage>>>,
+    pub(crate) status: Arc<RwLock<AppStatus>>,
+    jobs: Arc<Mutex<Jobs>>,
+    pub(crate) motion: MotionCtl, // root every motion is forked from, carries the emergency stop
+    positions: Arc<Mutex<PositionLog>>,
+    positions_flush_tx: mpsc::Sender<()>,
+    unprime_policy: Arc<RwLock<UnprimePolicy>>,
+    next_unprime: Arc<RwLock<Option<Instant>>>,
     timer_reset_tx: mpsc::Sender<()>,
+    pub(crate) schedules: Arc<Mutex<Vec<DoseSchedule>>>,
+    pub(crate) schedule_tx: mpsc::Sender<()>,
+    timezone: Arc<RwLock<String>>,
+    pub(crate) recipes: Arc<RwLock<Vec<Recipe>>>,
+    pub(crate) grow: Arc<RwLock<Option<Grow>>>,
+    pub(crate) labels: Arc<RwLock<Vec<MotorLabel>>>, // same order as motors
+    pub(crate) level_policy: Arc<RwLock<LevelPolicy>>,
+    pub(crate) dose_limits: Arc<RwLock<DoseLimits>>,
+    history: Arc<Mutex<History>>,
+    pub(crate) calibration: Arc<Mutex<Option<Session>>>, // at most one motor gets calibrated at a time
+    pub(crate) scale: Option<Arc<Mutex<Scale>>>, // None without a load cell
+    pub(crate) device_id: Arc<str>,
+    pub(crate) events: broadcast::Sender<AppEvent>,
+    pub(crate) mqtt: Arc<RwLock<MqttConfig>>,
+    pub(crate) mqtt_connected: Arc<RwLock<bool>>,
+    pub(crate) mqtt_tx: mpsc::Sender<()>, // reconnects with the current config
+}
+
+// Can't derive this, it would require S: Clone even though only the Arcs are cloned
+impl<S: Stepper> Clone for AppState<S> {
+    fn clone(&self) -> Self {
+        Self {
+            motors: self.motors.clone(),
+            snapshots: self.snapshots.clone(),
+            num_motors: self.num_motors,
+            nvs: self.nvs.clone(),
+            status: self.status.clone(),
+            jobs: self.jobs.clone(),
+            motion: self.motion.clone(),
+            positions: self.positions.clone(),
+            positions_flush_tx: self.positions_flush_tx.clone(),
+            unprime_policy: self.unprime_policy.clone(),
+            next_unprime: self.next_unprime.clone(),
+            timer_reset_tx: self.timer_reset_tx.clone(),
+            schedules: self.schedules.clone(),
+            schedule_tx: self.schedule_tx.clone(),
+            timezone: self.timezone.clone(),
+            recipes: self.recipes.clone(),
+            grow: self.grow.clone(),
+            labels: self.labels.clone(),
+            level_policy: self.level_policy.clone(),
+            dose_limits: self.dose_limits.clone(),
+            history: self.history.clone(),
+            calibration: self.calibration.clone(),
+            scale: self.scale.clone(),
+            device_id: self.device_id.clone(),
+            events: self.events.clone(),
+            mqtt: self.mqtt.clone(),
+            mqtt_connected: self.mqtt_connected.clone(),
+            mqtt_tx: self.mqtt_tx.clone(),
+        }
+    }
 }
 
-impl AppState {
-    async fn create_config(&self, drivers: Vec<DRV8825>) {
+impl<S: Stepper> AppState<S> {
+    async fn create_config(&self, drivers: Vec<S>) {
         info!("Creating new motor config");
         for drv in drivers {
             self.add_config_entry(drv).await;
         }
     }
 
-    async fn add_config_entry(&self, drv: DRV8825) {
-        self.motors.lock().await.push(StepperMotor {
+    async fn add_config_entry(&self, drv: S) {
+        let mut motor = StepperMotor {
             id: drv.id(),
-            driver: Some(drv),
             ..Default::default()
-        });
+        };
+        motor.attach(drv);
+        self.motors.lock().await.push(motor);
     }
 
-    async fn save_state(&self) {
-        match serde_json::to_string(&*self.motors.lock().await) {
-            Ok(state_str) => {
-                info!("Writing state to nvs: {state_str}");
-                if let Err(e) = self
-                    .nvs
-                    .write()
-                    .await
-                    .set_str(NVS_TAG_MOTORS, state_str.as_str())
-                {
-                    error!("Failed to write state to nvs: {e}");
+    /// Lets go of the motors before waiting on the storage
+    async fn save_state(&self, motors: MutexGuard<'_, Vec<StepperMotor<S>>>) -> Result<(), ApiError> {
+        let state = serde_json::to_string(&*motors);
+        self.motors_changed(&motors);
+        drop(motors);
+        self.write_state(state).await
+    }
+
+    /// Motors for changing their config. A job holds them for its whole
+    /// length, so this doesn't wait for one to finish.
+    fn idle_motors(&self) -> Result<MutexGuard<'_, Vec<StepperMotor<S>>>, ApiError> {
+        self.motors
+            .try_lock()
+            .map_err(|_| ApiError::busy("Motors are running, try again once they're done"))
+    }
+
+    /// Write motors that were already serialized, for when the motors lock
+    /// is held by someone else or is about to be
+    async fn write_state(&self, state: serde_json::Result<String>) -> Result<(), ApiError> {
+        let state_str = state.map_err(|e| storage_error(NVS_TAG_MOTORS, e))?;
+        check_stored_len(NVS_TAG_MOTORS, &state_str)?;
+        info!("Writing state to nvs: {state_str}");
+        self.nvs
+            .write()
+            .await
+            .set_str(NVS_TAG_MOTORS, state_str.as_str())
+            .map_err(|e| storage_error(NVS_TAG_MOTORS, e))
+    }
+
+    /// Deserialize what `store` wrote into `key`, `None` if there's nothing
+    /// there. Failures are logged, the caller keeps its defaults.
+    pub(crate) async fn load<T: DeserializeOwned>(&self, key: &str) -> Option<T> {
+        match self.nvs.read().await.get_str(key) {
+            Ok(Some(value)) => serde_json::from_str(&value)
+                .map_err(|e| error!("Failed to parse {key}, using defaults: {e}"))
+                .ok(),
+            Ok(None) => None,
+            Err(e) => {
+                error!("Failed to read {key} from nvs: {e}");
+                None
+            }
+        }
+    }
+
+    /// Serialize `value` into `key`, failures are logged and returned for the response
+    pub(crate) async fn store<T: Serialize + ?Sized>(&self, key: &str, value: &T) -> Result<(), ApiError> {
+        let value_str = serde_json::to_string(value).map_err(|e| storage_error(key, e))?;
+        check_stored_len(key, &value_str)?;
+        info!("Writing {key} to nvs: {value_str}");
+        self.nvs
+            .write()
+            .await
+            .set_str(key, &value_str)
+            .map_err(|e| storage_error(key, e))
+    }
+
+    async fn write_positions(&self, record: &[SavedPosition]) {
+        match serde_json::to_string(record) {
+            Ok(record_str) => {
+                info!("Writing positions to nvs: {record_str}");
+                if let Err(e) = self.nvs.write().await.set_str(NVS_TAG_POSITIONS, &record_str) {
+                    error!("Failed to write positions to nvs: {e}");
                 }
             }
-            Err(e) => error!("Failed to serialize state: {e}"),
+            Err(e) => error!("Failed to serialize positions: {e}"),
+        }
+    }
+
+    /// Must be called with the motors lock held before moving any of `idxs`,
+    /// so a reboot in the middle of the motion leaves their prime state unknown.
+    /// Takes `&mut` only because S isn't Sync, a shared slice would make the future !Send.
+    async fn begin_motion(&self, motors: &mut [StepperMotor<S>], idxs: impl IntoIterator<Item = usize>) {
+        let ids: Vec<_> = idxs.into_iter().filter_map(|idx| motors.get(idx)).map(|m| m.id).collect();
+        let record = self
+            .positions
+            .lock()
+            .await
+            .begin(ids, motors.iter().map(|m| m.saved_position()));
+        if let Some(record) = record {
+            self.write_positions(&record).await;
+        }
+    }
+
+    /// The final positions are written once no motion happened for a while.
+    /// Call it before letting go of the motors lock.
+    fn end_motion(&self, motors: &[StepperMotor<S>]) {
+        self.positions_flush_tx.try_send(()).ok();
+        self.motors_changed(motors);
+    }
+
+    /// Keeps a copy of the motors for status and MQTT, which can't wait on
+    /// the motors lock for a whole job to read them
+    fn motors_changed(&self, motors: &[StepperMotor<S>]) {
+        let snapshots = motors.iter().map(MotorSnapshot::new).collect();
+        *self.snapshots.lock().unwrap_or_else(|e| e.into_inner()) = snapshots;
+        self.emit(AppEvent::Motors);
+    }
+
+    pub(crate) fn motor_snapshots(&self) -> Vec<MotorSnapshot> {
+        self.snapshots.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// A single motor as `/full-status` shows it, the response of endpoints that change one
+    async fn motor_status(&self, idx: usize) -> Json<MotorStatus> {
+        let motor = self.motor_snapshots().swap_remove(idx);
+        let label = self.labels.read().await[idx].clone();
+        let low_ml = self.level_policy.read().await.low_ml;
+        Json(MotorStatus::new(idx, motor, label, low_ml))
+    }
+
+    async fn flush_positions(&self) {
+        let saved: Vec<_> = self.motors.lock().await.iter().map(|m| m.saved_position()).collect();
+        self.flush_saved(saved).await;
+    }
+
+    async fn flush_saved(&self, saved: Vec<SavedPosition>) {
+        let record = self.positions.lock().await.finish(saved);
+        if let Some(record) = record {
+            self.write_positions(&record).await;
+        }
+    }
+
+    async fn restore_positions(&self) {
+        let Some(saved) = self.load::<Vec<SavedPosition>>(NVS_TAG_POSITIONS).await else {
+            return;
         };
+
+        let mut motors = self.motors.lock().await;
+        for p in &saved {
+            if let Some(motor) = motors.iter_mut().find(|m| m.id == p.id) {
+                motor.restore(p);
+            }
+        }
+        self.positions.lock().await.restored(saved);
     }
 
     async fn set_status(&self, status: AppStatus) {
-        *self.status.write().await = status;
+        let mut current = self.status.write().await;
+        if !matches!(*current, AppStatus::STOPPED) && *current != status {
+            *current = status;
+            self.emit(AppEvent::Status(status));
+        }
+    }
+
+    /// Nobody listening is fine
+    fn emit(&self, event: AppEvent) {
+        self.events.send(event).ok();
+    }
+
+    /// Back to IDLE after a task died in the middle of a motion, unless
+    /// something else has started moving since
+    async fn recover_status(&self) {
+        if self.motors.try_lock().is_ok() {
+            self.set_status(AppStatus::IDLE).await;
+        }
+    }
+
+    pub(crate) fn is_stopped(&self) -> bool {
+        self.motion.is_stopped()
+    }
+
+    /// Restart the auto-unprime countdown, a reset that's already pending covers this one
+    fn reset_timer(&self) {
+        self.timer_reset_tx.try_send(()).ok();
+    }
+
+    async fn load_unprime_policy(&self) {
+        if let Some(policy) = self.load(NVS_TAG_UNPRIME).await {
+            *self.unprime_policy.write().await = policy;
+        }
+    }
+
+    async fn save_unprime_policy(&self) -> Result<(), ApiError> {
+        let policy = *self.unprime_policy.read().await;
+        self.store(NVS_TAG_UNPRIME, &policy).await
+    }
+
+    async fn load_level_policy(&self) {
+        if let Some(policy) = self.load(NVS_TAG_LEVELS).await {
+            *self.level_policy.write().await = policy;
+        }
+    }
+
+    async fn save_level_policy(&self) -> Result<(), ApiError> {
+        let policy = *self.level_policy.read().await;
+        self.store(NVS_TAG_LEVELS, &policy).await
+    }
+
+    async fn load_dose_limits(&self) {
+        if let Some(limits) = self.load(NVS_TAG_LIMITS).await {
+            *self.dose_limits.write().await = limits;
+        }
+    }
+
+    async fn save_dose_limits(&self) -> Result<(), ApiError> {
+        let limits = *self.dose_limits.read().await;
+        self.store(NVS_TAG_LIMITS, &limits).await
+    }
+
+    /// Take liquid out of a motor's bottle, a negative amount puts it back.
+    /// Returns whether the bottle's level is being tracked.
+    async fn draw_liquid(&self, idx: usize, ml: f64) -> bool {
+        let low_ml = self.level_policy.read().await.low_ml;
+        let mut labels = self.labels.write().await;
+        let Some(label) = labels.get_mut(idx) else {
+            return false;
+        };
+        let Some(level) = label.level_ml else {
+            return false;
+        };
+        let new_level = (level - ml).clamp(0.0, label.bottle_ml.unwrap_or(f64::MAX));
+        if level >= low_ml && new_level < low_ml {
+            warn!("Motor {} is running low, {new_level:.1} mL left", label.id);
+        }
+        label.level_ml = Some(new_level);
+        true
+    }
+
+    /// Checks that every bottle has enough left for `entries`, only warns
+    /// about it unless the policy says to refuse. Jobs ahead of them can
+    /// still change that, they're checked again when they start.
+    pub(crate) async fn check_levels(&self, entries: &[JobEntry]) -> Result<(), ApiError> {
+        // A running job holds the motors, its tubing isn't settled yet anyway
+        let fill_ml = match self.motors.try_lock() {
+            Ok(motors) => motors.iter().map(|m| m.fill_ml()).collect(),
+            Err(_) => Vec::new(),
+        };
+        let draws: Vec<_> = entries.iter().map(|e| (e.motor_idx, e.ml_requested)).collect();
+        self.check_draws(&draws, &fill_ml).await
+    }
+
+    /// `draws` pairs a motor index with the mL to dispense from it, `fill_ml`
+    /// is what each motor takes to fill its tubing first
+    async fn check_draws(&self, draws: &[(usize, f64)], fill_ml: &[f64]) -> Result<(), ApiError> {
+        let policy = *self.level_policy.read().await;
+        let labels = self.labels.read().await;
+        for (idx, label) in labels.iter().enumerate() {
+            let Some(level) = label.level_ml else {
+                continue;
+            };
+            let ml: f64 = draws.iter().filter(|(i, _)| *i == idx).map(|(_, ml)| ml).sum();
+            if ml <= 0.0 {
+                continue;
+            }
+            let needed = ml + fill_ml.get(idx).copied().unwrap_or(0.0);
+            if needed > level {
+                let e = format!("Motor {} needs {needed:.1} mL but only has {level:.1} mL left", label.id);
+                if policy.refuse_overdraw {
+                    return Err(ApiError::new(ErrorCode::NotEnoughLiquid, e));
+                }
+                warn!("{e}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs one test dispense for calibration on its own, outside of any
+    /// job. Returns the full steps that came out of the nozzle.
+    pub(crate) async fn calibration_dispense(&self, idx: usize, point: &SessionPoint, ctl: &MotionCtl) -> Result<f64, ApiError> {
+        if self.is_stopped() {
+            return Err(ApiError::stopped());
+        }
+        let mut motors = self.motors.lock().await;
+        self.begin_motion(&mut motors, [idx]).await;
+        self.reset_timer();
+        self.set_status(AppStatus::RUNNING).await;
+        let tube_ml = motors[idx].tube_ml();
+        let steps = motors[idx].steps_at(point.ml, point.rpm);
+        let motion = MotorMotion::new(None, idx, motors[idx].id, Some(point.ml));
+        self.emit(AppEvent::MotorStarted(motion.clone()));
+        let res = motors[idx].test_dispense(steps, point.rpm, &ctl.child()).await;
+        let dispensed = res.as_ref().ok().map(|&delivered| motors[idx].ml_for_steps(delivered));
+        self.emit(motion.finished(dispensed, &res));
+        let drawn = match dispensed {
+            Some(ml) => ml + motors[idx].tube_ml() - tube_ml,
+            None => 0.0,
+        };
+        let motors_state = serde_json::to_string(&*motors);
+        self.end_motion(&motors);
+        drop(motors);
+        self.set_status(AppStatus::IDLE).await;
+        self.write_state(motors_state).await?;
+        if self.draw_liquid(idx, drawn).await {
+            self.save_labels(&self.labels.read().await).await?;
+        }
+
+        let delivered = res?;
+        if ctl.is_cancelled() {
+            return Err(ApiError::new(ErrorCode::Stopped, "Stopped before the dispense finished"));
+        }
+        Ok(delivered)
+    }
+
+    /// Store a finished calibration with its motor
+    pub(crate) async fn apply_calibration(&self, idx: usize, fit: Fit) -> Result<(), ApiError> {
+        let mut motors = self.idle_motors()?;
+        let motor = &mut motors[idx];
+        let max_rpm = motor.profile.max_rpm;
+        if check_ml_per_step(fit.ml_per_step_at(max_rpm)).is_err() {
+            return Err(ApiError::invalid(format!("Fit doesn't hold at {max_rpm} rpm, measure closer to it")));
+        }
+        info!("Motor {} calibrated, {:.4} mL RMS error over {} points", motor.id, fit.rms_ml, fit.samples.len());
+        motor.set_calibration(Some(fit));
+        self.save_state(motors).await
+    }
+
+    async fn load_scale(&self) {
+        let Some(scale) = &self.scale else {
+            return;
+        };
+        match self.load(NVS_TAG_SCALE).await {
+            Some(cal) => scale.lock().await.cal = cal,
+            None => warn!("Scale isn't calibrated yet"),
+        }
+    }
+
+    async fn save_scale(&self, cal: &ScaleCal) -> Result<(), ApiError> {
+        self.store(NVS_TAG_SCALE, cal).await
+    }
+
+    async fn load_timezone(&self) {
+        let tz = match self.nvs.read().await.get_str(NVS_TAG_TIMEZONE) {
+            Ok(Some(tz)) => tz,
+            Ok(None) => DEFAULT_TZ.to_owned(),
+            Err(e) => {
+                error!("Failed to read timezone from nvs: {e}");
+                DEFAULT_TZ.to_owned()
+            }
+        };
+        if let Err(e) = clock::set_timezone(&tz) {
+            error!("{e}, falling back to {DEFAULT_TZ}");
+            clock::set_timezone(DEFAULT_TZ).ok();
+            *self.timezone.write().await = DEFAULT_TZ.to_owned();
+            return;
+        }
+        *self.timezone.write().await = tz;
+    }
+
+    /// Job entries for a dispense, checked against the limits
+    pub(crate) async fn dispense_entries(&self, req: &DispenseReq) -> Result<Vec<JobEntry>, ApiError> {
+        let limits = *self.dose_limits.read().await;
+        req.entries(&self.labels.read().await, &limits)
+    }
+
+    /// Job entries for a dose, looking up its recipe if it names one. Also
+    /// returns where the amounts came from, for the history.
+    pub(crate) async fn dose_entries(
+        &self,
+        req: &DoseSolutionReq,
+    ) -> Result<(Vec<JobEntry>, Option<String>), ApiError> {
+        let limits = *self.dose_limits.read().await;
+        let labels = self.labels.read().await;
+        let mut recipe = req.recipe.clone();
+        let entries = match &req.recipe {
+            Some(_) if !req.nutrients.is_empty() => {
+                Err(ApiError::invalid("Give either a recipe or a list of nutrients, not both"))
+            }
+            Some(name) => match self.recipes.read().await.iter().find(|r| &r.name == name) {
+                Some(recipe) => req.entries(&recipe.nutrients, &labels, &limits),
+                None => return Err(ApiError::not_found(format!("No recipe named {name:?}"))),
+            },
+            None if req.nutrients.is_empty() => {
+                if let Some(now) = clock::now() {
+                    self.advance_grow(now).await;
+                }
+                match self.grow.read().await.as_ref() {
+                    Some(grow) => {
+                        info!("Dosing for stage {} of {}", grow.current().name, grow.chart);
+                        recipe = Some(format!("{}: {}", grow.chart, grow.current().name));
+                        req.entries(&grow.current().nutrients, &labels, &limits)
+                    }
+                    None => Err(ApiError::invalid("No nutrients given and no feed chart loaded")),
+                }
+            }
+            None => req.entries(&req.nutrients, &labels, &limits),
+        };
+        drop(labels);
+        let entries = entries?;
+        self.check_levels(&entries).await?;
+        Ok((entries, recipe))
+    }
+
+    /// Labels for the attached motors, matched up by id
+    async fn load_labels(&self) {
+        let saved: Vec<MotorLabel> = self.load(NVS_TAG_LABELS).await.unwrap_or_default();
+        *self.labels.write().await = self
+            .motors
+            .lock()
+            .await
+            .iter()
+            .map(|m| match saved.iter().find(|l| l.id == m.id) {
+                Some(label) => label.clone(),
+                None => MotorLabel {
+                    id: m.id,
+                    ..Default::default()
+                },
+            })
+            .collect();
     }
 
-    async fn reset_timer(&self) {
-        self.timer_reset_tx.send(()).await.unwrap();
+    async fn save_labels(&self, labels: &[MotorLabel]) -> Result<(), ApiError> {
+        self.emit(AppEvent::Motors);
+        self.store(NVS_TAG_LABELS, labels).await
     }
+
+    /// Index of the motor a request points at
+    pub(crate) async fn motor_idx(&self, sel: &MotorSel) -> Result<usize, ApiError> {
+        sel.resolve(&self.labels.read().await)
+    }
+
+    /// Unprime every motor that opted into the policy and isn't unprimed already
+    async fn auto_unprime(&self) {
+        let idxs: Vec<_> = self
+            .motors
+            .lock()
+            .await
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| m.auto_unprime && m.prime_state() != PrimeState::Unprimed)
+            .map(|(idx, _)| idx)
+            .collect();
+        if !idxs.is_empty() {
+            info!("Auto-unpriming motors {idxs:?} due to inactivity");
+            self.start_unprime(idxs).await;
+        }
+    }
+
+    /// Queue a job and run it in the background
+    pub(crate) async fn start_job(&self, kind: JobKind, entries: Vec<JobEntry>, origin: Origin) -> JobId {
+        let id = {
+            let mut jobs = self.jobs.lock().await;
+            let job = jobs.create(kind, self.motion.fork());
+            job.origin = origin;
+            for mut entry in entries {
+                entry.progress = job.ctl.child();
+                job.entries.push(entry);
+            }
+            job.id
+        };
+        info!("Queued job {id}");
+
+        // A job that panics still has to leave the job list and status consistent
+        let state = self.clone();
+        let job = tokio::spawn(async move { state.run_job(id).await });
+        let state = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = job.await {
+                error!("Job {id} died: {e}");
+                state.emit(AppEvent::Error(format!("Job {id} died unexpectedly")));
+                state
+                    .update_job(id, |job| {
+                        job.state = JobState::Failed;
+                        job.error = Some("Job died unexpectedly".to_owned());
+                    })
+                    .await;
+                state.recover_status().await;
+            }
+        });
+        id
+    }
+
+    async fn start_unprime(&self, idxs: impl IntoIterator<Item = usize>) -> JobId {
+        let entries = idxs
+            .into_iter()
+            .map(|idx| JobEntry::steps(format!("#{idx}"), idx, 0.0))
+            .collect();
+        self.start_job(JobKind::Unprime, entries, Origin::default()).await
+    }
+
+    async fn load_history(&self) {
+        let mut slots = Vec::new();
+        for slot in 0..history::SLOTS {
+            slots.push(self.load(&history::slot_key(slot)).await.unwrap_or_default());
+        }
+        *self.history.lock().await = History::restore(slots);
+    }
+
+    /// Add a finished job to the dose history
+    async fn record_history(&self, id: JobId) {
+        let labels = self.labels.read().await.clone();
+        let Some(record) = self.jobs.lock().await.get(id).map(|job| DoseRecord {
+            seq: 0,
+            time: clock::now(),
+            kind: job.kind,
+            origin: job.origin.clone(),
+            nutrients: job
+                .entries
+                .iter()
+                .map(|e| DosedAmount {
+                    name: e.name.clone(),
+                    motor_id: labels.get(e.motor_idx).map_or(0, |l| l.id),
+                    ml_requested: e.ml_requested,
+                    ml_dispensed: e.ml_dispensed.unwrap_or(0.0),
+                })
+                .collect(),
+            result: job.state,
+            error: job.error.clone(),
+        }) else {
+            return;
+        };
+
+        let mut history = self.history.lock().await;
+        let (slot, contents) = history.push(record);
+        if let Some(record) = history.last() {
+            self.emit(AppEvent::Dosed(record.clone()));
+        }
+        drop(history);
+        if let Err(e) = self.nvs.write().await.set_str(&history::slot_key(slot), &contents) {
+            error!("Failed to write history to nvs: {e}");
+        }
+    }
+
+    async fn update_job(&self, id: JobId, f: impl FnOnce(&mut Job)) {
+        if let Some(job) = self.jobs.lock().await.get_mut(id) {
+            f(job);
+        }
+    }
+
+    async fn run_job(&self, id: JobId) {
+        // Jobs run one at a time, in the order they were queued
+        let mut motors = self.motors.lock().await;
+        let Some((kind, ctl, entries)) = self.jobs.lock().await.get_mut(id).map(|job| {
+            job.state = JobState::Running;
+            let entries: Vec<_> = job
+                .entries
+                .iter()
+                .map(|e| (e.name.clone(), e.motor_idx, e.ml_requested, e.steps_total, e.progress.clone()))
+                .collect();
+            (job.kind, job.ctl.clone(), entries)
+        }) else {
+            return;
+        };
+
+        info!("Starting job {id}");
+        self.begin_motion(&mut motors, entries.iter().map(|e| e.1)).await;
+        self.reset_timer();
+        self.set_status(AppStatus::RUNNING).await;
+        let mut levels_changed = false;
+        let mut failed = false;
+        if matches!(kind, JobKind::Dispense | JobKind::Dose) {
+            // Jobs that ran since this one was queued drew from the same bottles
+            let draws: Vec<_> = entries.iter().map(|e| (e.1, e.2)).collect();
+            let fill_ml: Vec<_> = motors.iter().map(|m| m.fill_ml()).collect();
+            if let Err(e) = self.check_draws(&draws, &fill_ml).await {
+                error!("Job {id} failed: {e}");
+                self.emit(AppEvent::Error(format!("Job {id} failed: {e}")));
+                self.update_job(id, |job| job.error = Some(e.message)).await;
+                failed = true;
+            }
+        }
+        for (i, (name, motor_idx, ml, steps, progress)) in entries.into_iter().enumerate() {
+            if ctl.is_cancelled() || failed {
+                break;
+            }
+            let Some(motor) = motors.get_mut(motor_idx) else {
+                continue;
+            };
+
+            let tube_ml = motor.tube_ml();
+            let mut dispensed = 0.0;
+            let is_dispense = matches!(kind, JobKind::Dispense | JobKind::Dose);
+            let motion = MotorMotion::new(Some(id), motor_idx, motor.id, is_dispense.then_some(ml));
+            self.emit(AppEvent::MotorStarted(motion.clone()));
+            let res = match kind {
+                JobKind::Dispense | JobKind::Dose => {
+                    let (steps_total, ml_per_step) = (motor.steps_for_ml(ml), motor.ml_per_step);
+                    self.update_job(id, |job| {
+                        job.entries[i].steps_total = steps_total;
+                        job.entries[i].ml_per_step = ml_per_step;
+                    })
+                    .await;
+
+                    info!("Dispensing {ml}mL of {name}");
+                    motor.dispense_ml(ml, &progress).await.map(|ml| {
+                        dispensed = ml;
+                    })
+                }
+                JobKind::Unprime => {
+                    let steps_total = 2.0 * motor.prime_steps as f64;
+                    self.update_job(id, |job| job.entries[i].steps_total = steps_total)
+                        .await;
+                    motor.unprime(&progress).await
+                }
+                JobKind::Prime => match motor.unprime(&progress.child()).await {
+                    Ok(()) if !ctl.is_cancelled() => {
+                        motor.prime_steps = steps as u32;
+                        motor.ensure_primed(&progress).await
+                    }
+                    res => res,
+                },
+            };
+            if is_dispense {
+                self.update_job(id, |job| job.entries[i].ml_dispensed = Some(dispensed))
+                    .await;
+            }
+            self.emit(motion.finished(is_dispense.then_some(dispensed), &res));
+            if let Err(e) = res {
+                error!("Job {id} failed: {e}");
+                self.emit(AppEvent::Error(format!("Job {id} failed: {e}")));
+                self.update_job(id, |job| job.error = Some(e.message)).await;
+                failed = true;
+            }
+            levels_changed |= self.draw_liquid(motor_idx, dispensed + motor.tube_ml() - tube_ml).await;
+        }
+        // Later jobs are already waiting on the motors, don't queue up behind them
+        let motors_state = serde_json::to_string(&*motors);
+        self.end_motion(&motors);
+        drop(motors);
+        if levels_changed {
+            self.save_labels(&self.labels.read().await).await.ok();
+        }
+
+        // Every kind of job changes the prime, carry over or retraction state
+        self.write_state(motors_state).await.ok();
+        let stopped = self.is_stopped();
+        self.update_job(id, |job| {
+            job.state = match (failed, job.ctl.is_cancelled()) {
+                (true, _) => JobState::Failed,
+                (false, true) => JobState::Cancelled,
+                (false, false) => JobState::Done,
+            };
+            if stopped {
+                job.error.get_or_insert_with(|| "Emergency stop".to_owned());
+            }
+        })
+        .await;
+        if matches!(kind, JobKind::Dispense | JobKind::Dose) {
+            self.record_history(id).await;
+        }
+        self.set_status(AppStatus::IDLE).await;
+        self.reset_timer(); // count inactivity from the end of long jobs too
+        info!("Finished job {id}");
+    }
+
 }
 
-pub async fn run(drivers: Vec<DRV8825>) -> anyhow::Result<()> {
+pub async fn run<S: Stepper>(
+    drivers: Vec<S>,
+    scale: Option<Box<dyn LoadCell>>,
+    nvs: Box<dyn Storage>,
+    device_id: String,
+    port: u16,
+) -> anyhow::Result<()> {
     info!("Starting app...");
 
     let (timer_reset_tx, mut timer_reset_rx) = mpsc::channel::<()>(1);
+    let (positions_flush_tx, mut positions_flush_rx) = mpsc::channel::<()>(1);
+    let (schedule_tx, mut schedule_rx) = mpsc::channel::<()>(1);
+    let (mqtt_tx, mqtt_rx) = mpsc::channel::<()>(1);
 
-    let state = AppState {
+    let mut state = AppState {
         motors: Arc::new(Mutex::new(Vec::new())),
-        nvs: Arc::new(RwLock::new(EspCustomNvs::new(
-            EspCustomNvsPartition::take("nvs")?,
-            NVS_NS,
-            true,
-        )?)),
+        snapshots: Arc::new(std::sync::Mutex::new(Vec::new())),
+        num_motors: 0,
+        nvs: Arc::new(RwLock::new(nvs)),
         status: Arc::new(RwLock::new(AppStatus::IDLE)),
+        jobs: Arc::new(Mutex::new(Jobs::default())),
+        motion: MotionCtl::default(),
+        positions: Arc::new(Mutex::new(PositionLog::default())),
+        positions_flush_tx,
+        unprime_policy: Arc::new(RwLock::new(UnprimePolicy::default())),
+        next_unprime: Arc::new(RwLock::new(None)),
         timer_reset_tx,
+        schedules: Arc::new(Mutex::new(Vec::new())),
+        schedule_tx,
+        timezone: Arc::new(RwLock::new(DEFAULT_TZ.to_owned())),
+        recipes: Arc::new(RwLock::new(Vec::new())),
+        grow: Arc::new(RwLock::new(None)),
+        labels: Arc::new(RwLock::new(Vec::new())),
+        level_policy: Arc::new(RwLock::new(LevelPolicy::default())),
+        dose_limits: Arc::new(RwLock::new(DoseLimits::default())),
+        history: Arc::new(Mutex::new(History::default())),
+        calibration: Arc::new(Mutex::new(None)),
+        scale: scale.map(|cell| Arc::new(Mutex::new(Scale::new(cell)))),
+        device_id: device_id.into(),
+        events: broadcast::channel(16).0,
+        mqtt: Arc::new(RwLock::new(MqttConfig::default())),
+        mqtt_connected: Arc::new(RwLock::new(false)),
+        mqtt_tx,
     };
 
     // Load motor config if it exists, or create it
-    match state.nvs.read().await.str_len(NVS_TAG_MOTORS) {
-        Ok(Some(len)) => {
+    let stored_config = state.nvs.read().await.get_str(NVS_TAG_MOTORS);
+    match stored_config {
+        Ok(Some(config)) => {
             info!("Loading existing motor config");
-            let mut buf = vec![0_u8; len];
-            match state
-                .nvs
-                .read()
-                .await
-                .get_str(NVS_TAG_MOTORS, buf.as_mut_slice())
-            {
-                Ok(Some(config)) => {
-                    info!("Read config from nvs: {config}");
-                    match serde_json::from_str::<Vec<StepperMotor>>(config) {
-                        Ok(loaded_motors) => {
-                            *state.motors.lock().await = loaded_motors;
-                            for drv in drivers {
-                                if let Some(m) = state
-                                    .motors
-                                    .lock()
-                                    .await
-                                    .iter_mut()
-                                    .find(|m| m.id == drv.id())
-                                {
-                                    info!("Matched motor {}", m.id);
-                                    m.driver = Some(drv);
-                                    continue;
-                                }
-                                info!("Adding config entry for new motor: {}", drv.id());
-                                state.add_config_entry(drv).await;
-                            }
-                            state.motors.lock().await.retain(|m| m.driver.is_some());
+            info!("Read config from nvs: {config}");
+            match serde_json::from_str::<Vec<StepperMotor<S>>>(&config) {
+                Ok(loaded_motors) => {
+                    *state.motors.lock().await = loaded_motors;
+                    for drv in drivers {
+                        if let Some(m) = state
+                            .motors
+                            .lock()
+                            .await
+                            .iter_mut()
+                            .find(|m| m.id == drv.id())
+                        {
+                            info!("Matched motor {}", m.id);
+                            m.attach(drv);
+                            continue;
                         }
-                        _ => state.create_config(drivers).await,
-                    };
+                        info!("Adding config entry for new motor: {}", drv.id());
+                        state.add_config_entry(drv).await;
+                    }
+                    state.motors.lock().await.retain(|m| m.driver.is_some());
                 }
                 _ => state.create_config(drivers).await,
             };
         }
         _ => state.create_config(drivers).await,
     };
-    state.save_state().await;
+    state.num_motors = state.motors.lock().await.len();
+    state.load_labels().await;
+    state.save_state(state.motors.lock().await).await.ok();
+    state.restore_positions().await;
+    state.motors_changed(&state.motors.lock().await);
+    state.load_unprime_policy().await;
+    state.load_level_policy().await;
+    state.load_dose_limits().await;
+    state.load_timezone().await;
+    state.load_recipes().await;
+    state.load_grow().await;
+    state.load_schedules().await;
+    state.load_history().await;
+    state.load_scale().await;
+    state.load_mqtt().await;
+
+    // Write positions once motion has settled for a bit
+    let _state = state.clone();
+    tokio::spawn(async move {
+        while positions_flush_rx.recv().await.is_some() {
+            while let Ok(Some(())) = timeout(FLUSH_DELAY, positions_flush_rx.recv()).await {}
+            _state.flush_positions().await;
+        }
+    });
 
-    // Start timer to periodically unprime all motors
+    // Start timer to unprime motors after a period of inactivity
     let _state = state.clone();
     tokio::spawn(async move {
-        let mut timer = interval(Duration::from_mins(30));
-        timer.reset();
         loop {
+        let policy = *_state.unprime_policy.read().await;
+        let deadline = policy.enabled.then(|| Instant::now() + policy.timeout());
+        *_state.next_unprime.write().await = deadline;
         tokio::select! {
             _ = timer_reset_rx.recv() => {
                 info!("Resetting unprime timer");
-                timer.reset();
             }
-            _ = timer.tick() => {
-                info!("Auto-unpriming all motors due to inactivity");
-                unprime_all(State(_state.clone())).await;
+            _ = async {
+                match deadline {
+                    Some(deadline) => sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                if _state.is_stopped() {
+                    continue;
+                }
+                _state.auto_unprime().await;
             }
         };
     }});
 
+    // Run schedules once the clock has been set
+    let _state = state.clone();
+    tokio::spawn(async move {
+        loop {
+            let wait = match clock::now() {
+                Some(now) => {
+                    _state.advance_grow(now).await;
+                    _state.run_due_schedules(now).await;
+                    match _state.next_schedule(now).await {
+                        Some(next) => Duration::from_secs((next.at - now).max(0) as u64).min(SCHEDULE_POLL),
+                        None => SCHEDULE_POLL,
+                    }
+                }
+                None => SCHEDULE_POLL,
+            };
+            timeout(wait, schedule_rx.recv()).await.ok();
+        }
+    });
+
+    // Stay connected to the MQTT broker, if there is one
+    let _state = state.clone();
+    tokio::spawn(async move { _state.run_mqtt(mqtt_rx).await });
+
     info!("Config loaded, starting app...");
     let app = Router::new()
         .route("/", get(root))
-        .route("/full-status", get(get_full_status))
-        .route("/status", get(get_status))
-        .route("/debug/step", post(debug_step))
-        .route("/debug/calibrate", post(debug_calibrate))
-        .route("/debug/clear-config", post(debug_clear_config))
-        .route("/dispense", post(dispense))
-        .route("/update-prime", post(update_prime))
-        .route("/unprime", post(unprime))
-        .route("/unprime-all", post(unprime_all))
-        .route("/calibrate", post(calibrate))
-        .route("/dose", post(dose_solution))
-        .route("/reboot", get(reboot))
-        .route("/ota", post(handle_ota))
+        .route("/full-status", get(get_full_status::<S>))
+        .route("/status", get(get_status::<S>))
+        .route("/events", get(events::<S>))
+        .route("/debug/step", post(debug_step::<S>))
+        .route("/debug/calibrate", post(debug_calibrate::<S>))
+        .route("/debug/clear-config", post(debug_clear_config::<S>))
+        .route("/dispense", post(dispense::<S>))
+        .route("/update-prime", post(update_prime::<S>))
+        .route("/unprime", post(unprime::<S>))
+        .route("/unprime-all", post(unprime_all::<S>))
+        .route("/update-unprime-policy", post(update_unprime_policy::<S>))
+        .route("/update-auto-unprime", post(update_auto_unprime::<S>))
+        .route("/calibrate", post(calibrate::<S>))
+        .route(
+            "/calibration",
+            get(calibration::get_calibration::<S>)
+                .post(calibration::start_calibration::<S>)
+                .delete(calibration::cancel_calibration::<S>),
+        )
+        .route("/calibration/run", post(calibration::run_calibration::<S>))
+        .route("/calibration/measure", post(calibration::measure_calibration::<S>))
+        .route("/calibration/finish", post(calibration::finish_calibration::<S>))
+        .route("/calibration/auto", post(calibration::auto_calibrate::<S>))
+        .route("/scale", get(get_scale::<S>))
+        .route("/scale/tare", post(tare_scale::<S>))
+        .route("/scale/calibrate", post(calibrate_scale::<S>))
+        .route("/update-profile", post(update_profile::<S>))
+        .route("/update-back-off", post(update_back_off::<S>))
+        .route("/update-label", post(update_label::<S>))
+        .route("/refill", post(refill::<S>))
+        .route("/update-level-policy", post(update_level_policy::<S>))
+        .route("/update-dose-limits", post(update_dose_limits::<S>))
+        .route("/dose", post(dose_solution::<S>))
+        .route("/stop", post(emergency_stop::<S>))
+        .route("/clear-stop", post(clear_stop::<S>))
+        .route("/recipes", get(feedchart::list_recipes::<S>).post(feedchart::save_recipe::<S>))
+        .route(
+            "/recipes/{name}",
+            get(feedchart::get_recipe::<S>).delete(feedchart::delete_recipe::<S>),
+        )
+        .route(
+            "/grow",
+            get(feedchart::get_grow::<S>)
+                .post(feedchart::load_grow::<S>)
+                .delete(feedchart::delete_grow::<S>),
+        )
+        .route("/grow/stage", post(feedchart::set_grow_stage::<S>))
+        .route("/schedules", get(schedule::list_schedules::<S>).post(schedule::create_schedule::<S>))
+        .route("/schedules/{id}", delete(schedule::delete_schedule::<S>))
+        .route("/update-timezone", post(update_timezone::<S>))
+        .route("/config/network", get(network::get_network_config::<S>).put(network::put_network_config::<S>))
+        .route("/config/mqtt", get(mqtt::get_mqtt_config::<S>).put(mqtt::put_mqtt_config::<S>))
+        .route("/history", get(get_history::<S>))
+        .route("/history.csv", get(export_history::<S>))
+        .route("/jobs", get(list_jobs::<S>))
+        .route("/jobs/{id}", get(get_job::<S>).delete(cancel_job::<S>))
+        .route("/reboot", get(reboot::<S>));
+
+    #[cfg(target_os = "espidf")]
+    let app = app.route("/ota", post(handle_ota::<S>));
+
+    let app = app
         .with_state(state.clone())
         .layer(
             CorsLayer::new()
@@ -266,15 +1519,29 @@ pub async fn run(drivers: Vec<DRV8825>) -> anyhow::Result<()> {
                 .allow_methods(cors::Any)
                 .allow_headers(cors::Any),
         )
-        .route_layer(axum::middleware::from_fn(
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
             // this spawns every route request to protect against cancellation
-            |req: Request<Body>, next: Next| async move {
-                tokio::task::spawn(next.run(req)).await.unwrap()
+            |State(state): State<AppState<S>>, req: Request<Body>, next: Next| async move {
+                match tokio::task::spawn(next.run(req)).await {
+                    Ok(resp) => resp,
+                    Err(e) => {
+                        // It may have died in the middle of a motion, same as a job
+                        error!("Request handler died: {e}");
+                        state.emit(AppEvent::Error("Request handler died unexpectedly".to_owned()));
+                        // Nothing else is moving if the motors are free
+                        if let Ok(motors) = state.motors.try_lock() {
+                            state.end_motion(&motors);
+                        }
+                        state.recover_status().await;
+                        ApiError::new(ErrorCode::HardwareFault, "Request handler failed").into_response()
+                    }
+                }
             },
         ));
 
-    info!("Binding to {BIND_IP}:{PORT}...");
-    let listener = TcpListener::bind(format!("{BIND_IP}:{PORT}")).await?;
+    info!("Binding to {BIND_IP}:{port}...");
+    let listener = TcpListener::bind(format!("{BIND_IP}:{port}")).await?;
     axum::serve(listener, app).await?;
     Ok(())
 }
@@ -283,14 +1550,68 @@ async fn root() -> Json<String> {
     Json(format!("Nutrient doser {}", env!("CARGO_PKG_VERSION")))
 }
 
-#[derive(Serialize)]
-struct MotorStatus {
-    idx: usize,
-    id: u32,
-    position: i32,
+#[derive(Serialize)]
+pub(crate) struct MotorStatus {
+    idx: usize,
+    nutrient: Option<String>,
+    color: Option<String>,
+    bottle_ml: Option<f64>,
+    level_ml: Option<f64>, // None until the bottle is refilled through the API
+    level_low: bool,
+    notes: String,
+    #[serde(flatten)]
+    motor: MotorSnapshot,
+}
+
+impl MotorStatus {
+    fn new(idx: usize, motor: MotorSnapshot, label: MotorLabel, low_ml: f64) -> Self {
+        Self {
+            idx,
+            nutrient: label.nutrient,
+            color: label.color,
+            bottle_ml: label.bottle_ml,
+            level_ml: label.level_ml,
+            level_low: label.level_ml.is_some_and(|ml| ml < low_ml),
+            notes: label.notes,
+            motor,
+        }
+    }
+}
+
+/// What the status shows of a motor, copied whenever it changes
+#[derive(Clone, Serialize)]
+pub(crate) struct MotorSnapshot {
+    pub(crate) id: u32,
+    position: f64, // full steps
     is_primed: bool,
     prime_steps: u32,
     ml_per_step: f64,
+    ml_carry: f64,
+    calibration: Option<Fit>,
+    pub(crate) prime_state: PrimeState,
+    auto_unprime: bool,
+    pub(crate) profile: MotionProfile,
+    back_off: BackOff,
+    retracted_steps: f64,
+}
+
+impl MotorSnapshot {
+    fn new<S: Stepper>(m: &StepperMotor<S>) -> Self {
+        Self {
+            id: m.id,
+            position: m.driver.as_ref().map_or(0.0, |d| d.position_steps()),
+            is_primed: m.is_primed(),
+            prime_steps: m.prime_steps,
+            ml_per_step: m.ml_per_step,
+            ml_carry: m.ml_carry,
+            calibration: m.calibration.clone(),
+            prime_state: m.prime_state(),
+            auto_unprime: m.auto_unprime,
+            profile: m.profile,
+            back_off: m.back_off,
+            retracted_steps: m.retracted_steps,
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -299,209 +1620,563 @@ struct FullStatus {
     motors: Vec<MotorStatus>,
     version: &'static str,
     status: AppStatus,
+    unprime_policy: UnprimePolicy,
+    level_policy: LevelPolicy,
+    dose_limits: DoseLimits,
+    next_unprime_secs: Option<u64>, // None while auto-unprime is disabled
+    time: Option<String>, // Local time, None until the clock is set
+    timezone: String,
+    next_schedule: Option<NextRun>,
+    grow_stage: Option<String>, // Current stage of the loaded feed chart
 }
 
-async fn get_full_status(State(state): State<AppState>) -> Json<FullStatus> {
-    let _motors = state.motors.lock().await;
+async fn get_full_status<S: Stepper>(State(state): State<AppState<S>>) -> Json<FullStatus> {
+    let now = clock::now();
+    let next_schedule = match now {
+        Some(now) => state.next_schedule(now).await,
+        None => None,
+    };
+    let grow_stage = state.grow.read().await.as_ref().map(|g| g.current().name.clone());
+    let labels = state.labels.read().await.clone();
+    let level_policy = *state.level_policy.read().await;
+    let motors = state.motor_snapshots();
     Json(FullStatus {
-        num_motors: _motors.len(),
-        motors: _motors
-            .iter()
+        num_motors: motors.len(),
+        motors: motors
+            .into_iter()
             .enumerate()
-            .map(|(idx, m)| MotorStatus {
-                idx,
-                id: m.id,
-                position: m.driver.as_ref().unwrap().get_position(),
-                is_primed: m.is_primed(),
-                prime_steps: m.prime_steps,
-                ml_per_step: m.ml_per_step,
-            })
+            .zip(labels)
+            .map(|((idx, motor), label)| MotorStatus::new(idx, motor, label, level_policy.low_ml))
             .collect(),
         version: env!("CARGO_PKG_VERSION"),
         status: *state.status.read().await,
+        unprime_policy: *state.unprime_policy.read().await,
+        level_policy,
+        dose_limits: *state.dose_limits.read().await,
+        next_unprime_secs: state
+            .next_unprime
+            .read()
+            .await
+            .map(|at| at.saturating_duration_since(Instant::now()).as_secs()),
+        time: now.map(|now| clock::to_local(now).to_string()),
+        timezone: state.timezone.read().await.clone(),
+        next_schedule,
+        grow_stage,
     })
 }
 
 #[derive(Serialize)]
-struct Status {
+pub(crate) struct Status {
     status: AppStatus,
 }
 
-async fn get_status(State(state): State<AppState>) -> Json<Status> {
+async fn get_status<S: Stepper>(State(state): State<AppState<S>>) -> Json<Status> {
     Json(Status {
         status: *state.status.read().await,
     })
 }
 
-#[derive(Deserialize)]
-struct DispenseSingle {
-    motor_idx: usize,
+/// Server-sent events as things happen, starting with the current status.
+/// A `lagged` event means some were missed and it's time to fetch the status.
+async fn events<S: Stepper>(
+    State(state): State<AppState<S>>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    // Counted after subscribing, so two at once can't both get the last place
+    let rx = state.events.subscribe();
+    if state.events.receiver_count() > MAX_EVENT_LISTENERS {
+        return Err(ApiError::busy("Too many event listeners, close one first"));
+    }
+    let first = AppEvent::Status(*state.status.read().await).sse();
+    let events = stream::unfold((first, rx), |(first, mut rx)| async move {
+        if let Some(event) = first {
+            return Some((Ok(event), (None, rx)));
+        }
+        loop {
+            let event = match rx.recv().await {
+                Ok(event) => match event.sse() {
+                    Some(event) => event,
+                    None => continue,
+                },
+                Err(broadcast::error::RecvError::Lagged(missed)) => Event::default()
+                    .event("lagged")
+                    .data(serde_json::json!({ "missed": missed }).to_string()),
+                Err(broadcast::error::RecvError::Closed) => return None,
+            };
+            return Some((Ok(event), (None, rx)));
+        }
+    });
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct DispenseSingle {
+    #[serde(flatten)]
+    pub(crate) motor: MotorSel,
     ml: f64,
 }
 
-#[derive(Deserialize)]
-struct DispenseReq {
-    reqs: Vec<DispenseSingle>,
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct DispenseReq {
+    pub(crate) reqs: Vec<DispenseSingle>,
 }
 
-async fn dispense(
-    State(state): State<AppState>,
-    Json(req): Json<DispenseReq>
-) -> StatusCode {
-    let mut res = StatusCode::OK;
-    let mut motors = state.motors.lock().await;
-    for r in req.reqs.iter() {
-        match motors.get_mut(r.motor_idx) {
-            Some(motor) => {
-                info!("Dispensing {}ml of liquid #{}", r.ml, r.motor_idx);
-                state.reset_timer().await;
-                state.set_status(AppStatus::RUNNING).await;
-                motor.dispense_ml(r.ml).await;
-                state.set_status(AppStatus::IDLE).await;
+impl DispenseReq {
+    /// Refuses the whole request if anything is wrong with any of its entries
+    fn entries(&self, labels: &[MotorLabel], limits: &DoseLimits) -> Result<Vec<JobEntry>, ApiError> {
+        if self.reqs.is_empty() {
+            return Err(ApiError::invalid("Nothing to dispense"));
+        }
+        let mut violations = Violations::default();
+        let mut amounts = Vec::new();
+        for (i, r) in self.reqs.iter().enumerate() {
+            let idx = violations.check(i, r.motor.resolve(labels));
+            let ml = violations.check(i, check_ml(r.ml));
+            if let (Some(idx), Some(ml)) = (idx, ml) {
+                amounts.push((i, idx, ml));
             }
-            None => res = StatusCode::BAD_REQUEST,
         }
+        limits.check(&amounts, labels, &mut violations);
+        violations.into_result()?;
+
+        let entries = amounts.into_iter().map(|(_, idx, ml)| {
+            let name = match &labels[idx].nutrient {
+                Some(nutrient) => nutrient.clone(),
+                None => format!("liquid #{idx}"),
+            };
+            JobEntry::dispense(name, idx, ml)
+        });
+        Ok(entries.collect())
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct JobCreated {
+    pub(crate) job_id: JobId,
+}
+
+pub(crate) type JobResponse = Result<(StatusCode, Json<JobCreated>), ApiError>;
+
+fn job_accepted(job_id: JobId) -> JobResponse {
+    Ok((StatusCode::ACCEPTED, Json(JobCreated { job_id })))
+}
+
+/// Motion requests are refused until an emergency stop is cleared
+fn check_stopped<S: Stepper>(state: &AppState<S>) -> Result<(), ApiError> {
+    match state.is_stopped() {
+        true => Err(ApiError::stopped()),
+        false => Ok(()),
     }
-    res
+}
+
+pub(crate) async fn dispense<S: Stepper>(
+    State(state): State<AppState<S>>,
+    Json(req): Json<DispenseReq>
+) -> JobResponse {
+    check_stopped(&state)?;
+    let entries = state.dispense_entries(&req).await?;
+    state.check_levels(&entries).await?;
+    job_accepted(state.start_job(JobKind::Dispense, entries, Origin::default()).await)
 }
 
 #[derive(Deserialize)]
 struct DebugStepReq {
-    motor_idx: usize,
+    #[serde(flatten)]
+    motor: MotorSel,
     steps: f64,
 }
 
-async fn debug_step(
-    State(state): State<AppState>,
+async fn debug_step<S: Stepper>(
+    State(state): State<AppState<S>>,
     Json(req): Json<DebugStepReq>
-) -> StatusCode {
-    let res = match state.motors.lock().await.get_mut(req.motor_idx) {
-        Some(motor) => {
-            if let Some(drv) = &mut motor.driver {
-                state.reset_timer().await;
-                state.set_status(AppStatus::RUNNING).await;
-                drv.step_by(req.steps).await.unwrap();
-                state.set_status(AppStatus::IDLE).await;
-            }
-            StatusCode::OK
-        }
-        None => StatusCode::BAD_REQUEST,
-    };
+) -> Result<Json<MotorStatus>, ApiError> {
+    check_stopped(&state)?;
+    state.dose_limits.read().await.check_steps(req.steps)?;
+    let idx = state.motor_idx(&req.motor).await?;
+    let mut motors = state.motors.lock().await;
+    state.begin_motion(&mut motors, [idx]).await;
+    let id = motors[idx].id;
+    let mut res = Ok(());
+    if let Some(drv) = &mut motors[idx].driver {
+        state.reset_timer();
+        state.set_status(AppStatus::RUNNING).await;
+        let motion = MotorMotion::new(None, idx, id, None);
+        state.emit(AppEvent::MotorStarted(motion.clone()));
+        res = drv.move_by(req.steps, &state.motion.fork()).await.map(|_| ()).map_err(fault(id));
+        state.emit(motion.finished(None, &res));
+        state.set_status(AppStatus::IDLE).await;
+    }
 
-    res
+    state.end_motion(&motors);
+    drop(motors);
+    res?;
+    Ok(state.motor_status(idx).await)
 }
 
 #[derive(Deserialize)]
 struct DebugCalibrateReq {
-    motor_idx: usize,
+    #[serde(flatten)]
+    motor: MotorSel,
     value: f64,
 }
 
-async fn debug_calibrate(
-    State(state): State<AppState>,
+async fn debug_calibrate<S: Stepper>(
+    State(state): State<AppState<S>>,
     Json(req): Json<DebugCalibrateReq>
-) -> StatusCode {
-    let res = match state.motors.lock().await.get_mut(req.motor_idx) {
-        Some(motor) => {
-            motor.ml_per_step = req.value;
-            StatusCode::OK
-        }
-        None => StatusCode::BAD_REQUEST,
-    };
-
-    state.save_state().await;
-    res
+) -> Result<Json<MotorStatus>, ApiError> {
+    let idx = state.motor_idx(&req.motor).await?;
+    let ml_per_step = check_ml_per_step(req.value)?;
+    let mut motors = state.idle_motors()?;
+    let motor = &mut motors[idx];
+    motor.set_calibration(None);
+    motor.ml_per_step = ml_per_step;
+    state.save_state(motors).await?;
+    Ok(state.motor_status(idx).await)
 }
 
 
-async fn debug_clear_config(State(state): State<AppState>) {
-    state.motors.lock().await.clear();
-    state.save_state().await;
+async fn debug_clear_config<S: Stepper>(State(state): State<AppState<S>>) {
+    let mut motors = state.motors.lock().await;
+    motors.clear();
+    state.save_state(motors).await.ok();
+    if let Err(e) = state.nvs.write().await.remove(NVS_TAG_POSITIONS) {
+        error!("Failed to clear positions from nvs: {e}");
+    }
     restart();
 }
 
 #[derive(Deserialize)]
 struct UpdatePrimeReq {
-    motor_idx: usize,
+    #[serde(flatten)]
+    motor: MotorSel,
     prime_steps: u32,
 }
 
-async fn update_prime(
-    State(state): State<AppState>,
+async fn update_prime<S: Stepper>(
+    State(state): State<AppState<S>>,
     Json(req): Json<UpdatePrimeReq>,
-) -> StatusCode {
-    let res = match state.motors.lock().await.get_mut(req.motor_idx) {
-        Some(motor) => {
-            state.reset_timer().await;
-            state.set_status(AppStatus::RUNNING).await;
-            motor.unprime().await;
-            motor.prime_steps = req.prime_steps;
-            motor.ensure_primed().await;
-            state.set_status(AppStatus::IDLE).await;
-            StatusCode::OK
-        }
-        None => StatusCode::BAD_REQUEST,
-    };
-    state.save_state().await;
-    res
+) -> JobResponse {
+    check_stopped(&state)?;
+    state.dose_limits.read().await.check_steps(req.prime_steps as f64)?;
+    let idx = state.motor_idx(&req.motor).await?;
+
+    let entry = JobEntry::steps(format!("#{idx}"), idx, req.prime_steps as f64);
+    job_accepted(state.start_job(JobKind::Prime, vec![entry], Origin::default()).await)
 }
 
 #[derive(Deserialize)]
-struct UnprimeReq {
-    motor_idx: usize,
+pub(crate) struct UnprimeReq {
+    #[serde(flatten)]
+    motor: MotorSel,
 }
 
-async fn unprime(
-    State(state): State<AppState>,
+pub(crate) async fn unprime<S: Stepper>(
+    State(state): State<AppState<S>>,
     Json(req): Json<UnprimeReq>
-) -> StatusCode {
-    match state.motors.lock().await.get_mut(req.motor_idx) {
-        Some(motor) => {
-            state.reset_timer().await;
-            state.set_status(AppStatus::RUNNING).await;
-            motor.unprime().await;
-            state.set_status(AppStatus::IDLE).await;
-            StatusCode::OK
-        }
-        None => StatusCode::BAD_REQUEST,
+) -> Result<Json<MotorStatus>, ApiError> {
+    check_stopped(&state)?;
+    let idx = state.motor_idx(&req.motor).await?;
+    let mut motors = state.motors.lock().await;
+    state.begin_motion(&mut motors, [idx]).await;
+    state.reset_timer();
+    state.set_status(AppStatus::RUNNING).await;
+    let tube_ml = motors[idx].tube_ml();
+    let motion = MotorMotion::new(None, idx, motors[idx].id, None);
+    state.emit(AppEvent::MotorStarted(motion.clone()));
+    let res = motors[idx].unprime(&state.motion.fork()).await;
+    state.emit(motion.finished(None, &res));
+    let returned = tube_ml - motors[idx].tube_ml();
+    state.end_motion(&motors);
+    drop(motors);
+    state.set_status(AppStatus::IDLE).await;
+    if state.draw_liquid(idx, -returned).await {
+        state.save_labels(&state.labels.read().await).await?;
     }
+    res?;
+    Ok(state.motor_status(idx).await)
 }
 
-async fn unprime_all(State(state): State<AppState>) -> StatusCode {
-    state.reset_timer().await;
-    state.set_status(AppStatus::RUNNING).await;
-    for m in state.motors.lock().await.iter_mut() {
-        m.unprime().await;
+pub(crate) async fn unprime_all<S: Stepper>(State(state): State<AppState<S>>) -> JobResponse {
+    check_stopped(&state)?;
+    job_accepted(state.start_unprime(0..state.num_motors).await)
+}
+
+async fn update_level_policy<S: Stepper>(
+    State(state): State<AppState<S>>,
+    Json(req): Json<LevelPolicy>,
+) -> Result<Json<LevelPolicy>, ApiError> {
+    if !(req.low_ml.is_finite() && req.low_ml >= 0.0) {
+        return Err(ApiError::invalid_volume("low_ml can't be negative"));
     }
-    state.set_status(AppStatus::IDLE).await;
-    StatusCode::OK
+    *state.level_policy.write().await = req;
+    state.save_level_policy().await?;
+    Ok(Json(req))
+}
+
+async fn update_dose_limits<S: Stepper>(
+    State(state): State<AppState<S>>,
+    Json(req): Json<DoseLimitsUpdate>,
+) -> Result<Json<DoseLimits>, ApiError> {
+    let updated = {
+        let mut limits = state.dose_limits.write().await;
+        let updated = req.apply(*limits);
+        updated.validate().map_err(ApiError::invalid)?;
+        *limits = updated;
+        updated
+    };
+    state.save_dose_limits().await?;
+    Ok(Json(updated))
+}
+
+#[derive(Deserialize)]
+struct RefillReq {
+    #[serde(flatten)]
+    motor: MotorSel,
+    ml: Option<f64>, // Defaults to a full bottle
+}
+
+/// Sets how much is in a motor's bottle, which starts tracking its level
+async fn refill<S: Stepper>(
+    State(state): State<AppState<S>>,
+    Json(req): Json<RefillReq>,
+) -> Result<Json<MotorStatus>, ApiError> {
+    let idx = state.motor_idx(&req.motor).await?;
+    let mut labels = state.labels.write().await;
+    let mut updated = labels.clone();
+    let label = &mut updated[idx];
+    let Some(ml) = req.ml.or(label.bottle_ml) else {
+        return Err(ApiError::invalid_volume("Give the amount, the bottle size isn't set"));
+    };
+    if !(ml.is_finite() && ml >= 0.0) || label.bottle_ml.is_some_and(|bottle| ml > bottle) {
+        return Err(ApiError::invalid_volume(format!("Invalid amount {ml} mL")));
+    }
+    info!("Motor {} refilled to {ml} mL", label.id);
+    label.level_ml = Some(ml);
+    state.save_labels(&updated).await?;
+    *labels = updated;
+    drop(labels);
+    Ok(state.motor_status(idx).await)
+}
+
+async fn update_unprime_policy<S: Stepper>(
+    State(state): State<AppState<S>>,
+    Json(req): Json<UnprimePolicy>,
+) -> Result<Json<UnprimePolicy>, ApiError> {
+    if req.timeout_mins == 0 {
+        return Err(ApiError::invalid("timeout_mins must be at least 1"));
+    }
+    *state.unprime_policy.write().await = req;
+    state.save_unprime_policy().await?;
+    state.reset_timer(); // picks up the new timeout
+    Ok(Json(req))
+}
+
+#[derive(Deserialize)]
+struct UpdateAutoUnprimeReq {
+    #[serde(flatten)]
+    motor: MotorSel,
+    enabled: bool,
+}
+
+async fn update_auto_unprime<S: Stepper>(
+    State(state): State<AppState<S>>,
+    Json(req): Json<UpdateAutoUnprimeReq>,
+) -> Result<Json<MotorStatus>, ApiError> {
+    let idx = state.motor_idx(&req.motor).await?;
+    let mut motors = state.idle_motors()?;
+    motors[idx].auto_unprime = req.enabled;
+    state.save_state(motors).await?;
+    Ok(state.motor_status(idx).await)
 }
 
 #[derive(Deserialize)]
 struct CalibrateReq {
-    motor_idx: usize,
+    #[serde(flatten)]
+    motor: MotorSel,
     expected: f64,
     actual: f64,
 }
 
-async fn calibrate(
-    State(state): State<AppState>,
+async fn calibrate<S: Stepper>(
+    State(state): State<AppState<S>>,
     Json(req): Json<CalibrateReq>
-) -> StatusCode {
-    let res = match state.motors.lock().await.get_mut(req.motor_idx) {
-        Some(motor) => {
-            let orig_steps = req.expected / motor.ml_per_step;
-            motor.ml_per_step = req.actual / orig_steps;
-            StatusCode::OK
-        }
-        None => StatusCode::BAD_REQUEST,
+) -> Result<Json<MotorStatus>, ApiError> {
+    let idx = state.motor_idx(&req.motor).await?;
+    check_ml(req.expected)?;
+    check_ml(req.actual)?;
+    let mut motors = state.idle_motors()?;
+    let motor = &mut motors[idx];
+    let orig_steps = req.expected / motor.ml_per_step;
+    let ml_per_step = check_ml_per_step(req.actual / orig_steps)?;
+    motor.set_calibration(None);
+    motor.ml_per_step = ml_per_step;
+    state.save_state(motors).await?;
+    Ok(state.motor_status(idx).await)
+}
+
+pub(crate) fn no_scale() -> ApiError {
+    ApiError::not_found("No scale attached")
+}
+
+pub(crate) fn scale_error(e: anyhow::Error) -> ApiError {
+    ApiError::new(ErrorCode::Unavailable, e.to_string())
+}
+
+#[derive(Serialize)]
+struct ScaleStatus {
+    #[serde(flatten)]
+    cal: ScaleCal,
+    calibrated: bool,
+    grams: Option<f64>,
+    error: Option<String>, // Why there's no reading
+}
+
+async fn get_scale<S: Stepper>(State(state): State<AppState<S>>) -> Result<Json<ScaleStatus>, ApiError> {
+    let mut scale = state.scale.as_ref().ok_or_else(no_scale)?.lock().await;
+    let (grams, error) = match scale.weigh().await {
+        Ok(grams) => (Some(grams), None),
+        Err(e) => (None, Some(e.to_string())),
+    };
+    Ok(Json(ScaleStatus {
+        cal: scale.cal,
+        calibrated: scale.cal.is_calibrated(),
+        grams,
+        error,
+    }))
+}
+
+/// Zeroes the scale, with the empty cup on it
+async fn tare_scale<S: Stepper>(State(state): State<AppState<S>>) -> Result<Json<ScaleCal>, ApiError> {
+    let cal = {
+        let mut scale = state.scale.as_ref().ok_or_else(no_scale)?.lock().await;
+        scale.tare().await.map_err(scale_error)?;
+        scale.cal
+    };
+    state.save_scale(&cal).await?;
+    Ok(Json(cal))
+}
+
+#[derive(Deserialize)]
+struct CalibrateScaleReq {
+    grams: f64, // Reference weight sitting on the tared scale
+}
+
+async fn calibrate_scale<S: Stepper>(
+    State(state): State<AppState<S>>,
+    Json(req): Json<CalibrateScaleReq>,
+) -> Result<Json<ScaleCal>, ApiError> {
+    let cal = {
+        let mut scale = state.scale.as_ref().ok_or_else(no_scale)?.lock().await;
+        scale.calibrate(req.grams).await.map_err(scale_error)?;
+        scale.cal
+    };
+    state.save_scale(&cal).await?;
+    Ok(Json(cal))
+}
+
+#[derive(Deserialize)]
+struct UpdateProfileReq {
+    #[serde(flatten)]
+    motor: MotorSel,
+    #[serde(flatten)]
+    profile: MotionProfile,
+}
+
+async fn update_profile<S: Stepper>(
+    State(state): State<AppState<S>>,
+    Json(req): Json<UpdateProfileReq>,
+) -> Result<Json<MotorStatus>, ApiError> {
+    let idx = state.motor_idx(&req.motor).await?;
+    let mut motors = state.idle_motors()?;
+    let res = {
+        let motor = &mut motors[idx];
+        if motor.calibration.as_ref().is_some_and(|fit| check_ml_per_step(fit.ml_per_step_at(req.profile.max_rpm)).is_err()) {
+            return Err(ApiError::invalid(format!("Calibration doesn't hold at {} rpm, recalibrate first", req.profile.max_rpm)));
+        }
+        match motor.driver.as_mut().map(|drv| drv.set_profile(req.profile)) {
+            Some(Err(e)) => Err(ApiError::invalid(e)),
+            _ => {
+                motor.profile = req.profile;
+                if let Some(fit) = &motor.calibration {
+                    motor.ml_per_step = fit.ml_per_step_at(req.profile.max_rpm);
+                }
+                Ok(())
+            }
+        }
     };
 
-    state.save_state().await;
-    res
+    state.save_state(motors).await?;
+    res?;
+    Ok(state.motor_status(idx).await)
+}
+
+#[derive(Deserialize)]
+struct UpdateBackOffReq {
+    #[serde(flatten)]
+    motor: MotorSel,
+    #[serde(flatten)]
+    back_off: BackOff,
+}
+
+async fn update_back_off<S: Stepper>(
+    State(state): State<AppState<S>>,
+    Json(req): Json<UpdateBackOffReq>,
+) -> Result<Json<MotorStatus>, ApiError> {
+    req.back_off.validate().map_err(ApiError::invalid)?;
+    let idx = state.motor_idx(&req.motor).await?;
+    let mut motors = state.idle_motors()?;
+    motors[idx].back_off = req.back_off;
+    state.save_state(motors).await?;
+    Ok(state.motor_status(idx).await)
 }
 
 #[derive(Deserialize)]
+struct UpdateLabelReq {
+    #[serde(flatten)]
+    motor: MotorSel,
+    nutrient: Option<String>,
+    color: Option<String>,
+    bottle_ml: Option<f64>,
+    #[serde(default)]
+    notes: String,
+}
+
+/// Sets what a motor is hooked up to, replacing its previous label
+async fn update_label<S: Stepper>(
+    State(state): State<AppState<S>>,
+    Json(req): Json<UpdateLabelReq>,
+) -> Result<Json<MotorStatus>, ApiError> {
+    let idx = state.motor_idx(&req.motor).await?;
+    let mut labels = state.labels.write().await;
+    if let Some(nutrient) = &req.nutrient {
+        if nutrient.is_empty() {
+            return Err(ApiError::invalid("Nutrient name can't be empty"));
+        }
+        let taken = labels
+            .iter()
+            .enumerate()
+            .any(|(i, l)| i != idx && l.nutrient.as_ref() == Some(nutrient));
+        if taken {
+            return Err(ApiError::busy(format!("Another motor already holds {nutrient}")));
+        }
+    }
+    if req.bottle_ml.is_some_and(|ml| !(ml.is_finite() && ml > 0.0)) {
+        return Err(ApiError::invalid_volume("bottle_ml must be positive"));
+    }
+
+    let mut updated = labels.clone();
+    let label = &mut updated[idx];
+    label.nutrient = req.nutrient;
+    label.color = req.color;
+    label.bottle_ml = req.bottle_ml;
+    label.notes = req.notes;
+    info!("Motor {} now holds {:?}", label.id, label.nutrient);
+    state.save_labels(&updated).await?;
+    *labels = updated;
+    drop(labels);
+    Ok(state.motor_status(idx).await)
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
 enum VolUnit {
     #[serde(alias = "ml", alias = "mL")]
     Ml,
@@ -527,167 +2202,711 @@ impl VolUnit {
     }
 }
 
-#[derive(Deserialize)]
-struct NutrientInfo {
-    name: String,
-    motor_idx: usize,
-    ml_per_gal: f64,
-}
-
-#[derive(Deserialize)]
-struct DoseSolutionReq {
-    nutrients: Vec<NutrientInfo>,
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct DoseSolutionReq {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) nutrients: Vec<NutrientInfo>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) recipe: Option<String>, // Name of a stored recipe to use instead of `nutrients`
+    // Neither uses the current stage of the loaded feed chart
     target_amount: f64,
     target_unit: VolUnit,
 }
 
-async fn dose_solution(
-    State(state): State<AppState>,
-    Json(req): Json<DoseSolutionReq>
-) -> StatusCode {
-    let solution_ml = req.target_amount * req.target_unit.scale_to_ml();
-    let solution_gal = solution_ml / VolUnit::Gal.scale_to_ml();
-    info!("Dosing solution for {solution_gal} gallons of water ({solution_ml} mL)");
-    state.reset_timer().await;
-    state.set_status(AppStatus::RUNNING).await;
-    let mut motors = state.motors.lock().await;
-    for nutrient in req.nutrients {
-        if let Some(motor) = motors.get_mut(nutrient.motor_idx) {
-            let ml_needed = solution_gal * nutrient.ml_per_gal;
-            if ml_needed > 0.0 {
-                info!("Dispensing {ml_needed}mL of {}", nutrient.name);
-                motor.dispense_ml(ml_needed).await;
+impl DoseSolutionReq {
+    /// Refuses the whole dose if anything is wrong with any of the nutrients,
+    /// e.g. one that would come out of the wrong bottle
+    fn entries(
+        &self,
+        nutrients: &[NutrientInfo],
+        labels: &[MotorLabel],
+        limits: &DoseLimits,
+    ) -> Result<Vec<JobEntry>, ApiError> {
+        if nutrients.is_empty() {
+            return Err(ApiError::invalid("No nutrients to dose"));
+        }
+        let mut violations = Violations::default();
+        let solution_ml = self.target_amount * self.target_unit.scale_to_ml();
+        if check_ml(solution_ml).is_err() {
+            let e = format!("Invalid amount of water {}", self.target_amount);
+            violations.add(None, ApiError::invalid_volume(e));
+        }
+        let solution_gal = solution_ml / VolUnit::Gal.scale_to_ml();
+        let mut amounts = Vec::new();
+        for (i, n) in nutrients.iter().enumerate() {
+            let idx = violations.check(i, n.motor.resolve_nutrient(&n.name, labels));
+            let strength = violations.check(i, check_strength(n));
+            if let (Some(idx), Some(())) = (idx, strength) {
+                amounts.push((i, idx, solution_gal * n.ml_per_gal));
             }
         }
+        // Nutrients left out of this stage are at 0 mL/gal
+        amounts.retain(|(_, _, ml)| *ml > 0.0);
+        limits.check(&amounts, labels, &mut violations);
+        violations.into_result()?;
+        if amounts.is_empty() {
+            return Err(ApiError::invalid_volume("Nothing to dose, every nutrient is at 0 mL/gal"));
+        }
+
+        info!("Dosing solution for {solution_gal} gallons of water ({solution_ml} mL)");
+        let entries = amounts
+            .into_iter()
+            .map(|(i, idx, ml)| JobEntry::dispense(nutrients[i].name.clone(), idx, ml));
+        Ok(entries.collect())
     }
-    state.set_status(AppStatus::IDLE).await;
-    StatusCode::OK
 }
 
-async fn reboot() {
+pub(crate) async fn dose_solution<S: Stepper>(
+    State(state): State<AppState<S>>,
+    Json(req): Json<DoseSolutionReq>
+) -> JobResponse {
+    check_stopped(&state)?;
+    let (entries, recipe) = state.dose_entries(&req).await?;
+    let origin = Origin {
+        recipe,
+        ..Default::default()
+    };
+    job_accepted(state.start_job(JobKind::Dose, entries, origin).await)
+}
+
+/// Stored requests keep their motors by id, indexes shift when motors are removed
+pub(crate) fn pin_motors<'a>(sels: impl Iterator<Item = &'a mut MotorSel>, labels: &[MotorLabel]) -> Result<(), ApiError> {
+    for sel in sels {
+        if sel.motor_idx.is_some() {
+            *sel = MotorSel::id(labels[sel.resolve(labels)?].id);
+        }
+    }
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize)]
+struct TimezoneBody {
+    tz: String, // POSIX TZ string, e.g. "EST5EDT,M3.2.0,M11.1.0"
+}
+
+async fn update_timezone<S: Stepper>(
+    State(state): State<AppState<S>>,
+    Json(req): Json<TimezoneBody>,
+) -> Result<Json<TimezoneBody>, ApiError> {
+    clock::set_timezone(&req.tz).map_err(ApiError::invalid)?;
+    state
+        .nvs
+        .write()
+        .await
+        .set_str(NVS_TAG_TIMEZONE, &req.tz)
+        .map_err(|e| storage_error(NVS_TAG_TIMEZONE, e))?;
+    info!("Timezone set to {}", req.tz);
+    *state.timezone.write().await = req.tz.clone();
+    state.schedule_tx.try_send(()).ok(); // local times moved
+    Ok(Json(req))
+}
+
+/// Ramps down whatever motor is running, cancels every queued job and refuses
+/// any further motion until `/clear-stop` is called
+pub(crate) async fn emergency_stop<S: Stepper>(State(state): State<AppState<S>>) -> Result<Json<Status>, ApiError> {
+    error!("Emergency stop requested!");
+    state.motion.stop();
+    *state.status.write().await = AppStatus::STOPPED;
+    state.emit(AppEvent::Status(AppStatus::STOPPED));
+    state.jobs.lock().await.cancel_all();
+    Ok(Json(Status {
+        status: AppStatus::STOPPED,
+    }))
+}
+
+async fn clear_stop<S: Stepper>(State(state): State<AppState<S>>) -> Result<Json<Status>, ApiError> {
+    if !state.is_stopped() {
+        return Ok(get_status(State(state)).await);
+    }
+
+    // The stopped motor has to finish ramping down before accepting motion again
+    let _motors = state
+        .motors
+        .try_lock()
+        .map_err(|_| ApiError::busy("Motors are still stopping, try again"))?;
+    info!("Clearing emergency stop");
+    state.motion.clear_stop();
+    *state.status.write().await = AppStatus::IDLE;
+    state.emit(AppEvent::Status(AppStatus::IDLE));
+    Ok(Json(Status {
+        status: AppStatus::IDLE,
+    }))
+}
+
+async fn list_jobs<S: Stepper>(State(state): State<AppState<S>>) -> Json<Vec<JobStatus>> {
+    Json(state.jobs.lock().await.iter().map(|j| j.status()).collect())
+}
+
+fn no_job(id: JobId) -> ApiError {
+    ApiError::not_found(format!("No job {id}"))
+}
+
+async fn get_job<S: Stepper>(
+    State(state): State<AppState<S>>,
+    Path(id): Path<JobId>,
+) -> Result<Json<JobStatus>, ApiError> {
+    match state.jobs.lock().await.get(id) {
+        Some(job) => Ok(Json(job.status())),
+        None => Err(no_job(id)),
+    }
+}
+
+#[derive(Deserialize)]
+struct HistoryQuery {
+    since: Option<i64>, // Unix time
+    after: Option<u32>, // `next` from the previous page
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct HistoryPage {
+    records: Vec<DoseRecord>,
+    next: Option<u32>, // Pass as `after` to get the next page, None on the last one
+}
+
+async fn get_history<S: Stepper>(
+    State(state): State<AppState<S>>,
+    Query(query): Query<HistoryQuery>,
+) -> Json<HistoryPage> {
+    let limit = query.limit.unwrap_or(20).clamp(1, history::MAX_PAGE);
+    let (records, next) = state.history.lock().await.query(query.since, query.after, limit);
+    Json(HistoryPage { records, next })
+}
+
+/// The whole history as CSV, `since` works the same as for `/history`
+async fn export_history<S: Stepper>(
+    State(state): State<AppState<S>>,
+    Query(query): Query<HistoryQuery>,
+) -> impl IntoResponse {
+    let (records, _) = state.history.lock().await.query(query.since, query.after, usize::MAX);
+    let csv = history::to_csv(&records, |t| clock::to_local(t).to_string());
+    ([(header::CONTENT_TYPE, "text/csv")], csv)
+}
+
+/// Stops the job's current motion and skips whatever it hasn't started yet,
+/// the volume each motor delivered before stopping is kept in the job
+async fn cancel_job<S: Stepper>(
+    State(state): State<AppState<S>>,
+    Path(id): Path<JobId>,
+) -> Result<Json<JobStatus>, ApiError> {
+    match state.jobs.lock().await.get(id) {
+        Some(job) if job.state.is_finished() => Err(ApiError::busy(format!("Job {id} already finished"))),
+        Some(job) => {
+            info!("Cancelling job {id}");
+            job.ctl.cancel();
+            Ok(Json(job.status()))
+        }
+        None => Err(no_job(id)),
+    }
+}
+
+async fn reboot<S: Stepper>(State(state): State<AppState<S>>) {
+    // A motion still running was written when it began, it restores as unknown
+    let saved = state
+        .motors
+        .try_lock()
+        .map(|motors| motors.iter().map(|m| m.saved_position()).collect::<Vec<_>>());
+    if let Ok(saved) = saved {
+        state.flush_saved(saved).await;
+    }
     restart();
 }
 
+/// Nothing to reboot in the simulator, exit so whatever supervises it can restart it
+#[cfg(not(target_os = "espidf"))]
+fn restart() -> ! {
+    info!("Reboot requested, exiting simulator");
+    std::process::exit(0)
+}
+
+#[cfg(target_os = "espidf")]
 #[derive(Deserialize)]
 struct OtaReq {
     #[serde(with = "http_serde_ext::uri")]
-    uri: Uri,
+    uri: http::Uri,
 }
 
-async fn handle_ota(State(state): State<AppState>, Json(req): Json<OtaReq>) -> StatusCode {
+#[cfg(target_os = "espidf")]
+async fn handle_ota<S: Stepper>(
+    State(state): State<AppState<S>>,
+    Json(req): Json<OtaReq>,
+) -> Result<Json<Status>, ApiError> {
     state.set_status(AppStatus::OTA).await;
-    match do_ota(req.uri).await {
+    let events = state.events.clone();
+    let progress = move |percent| {
+        events.send(AppEvent::OtaProgress(percent)).ok();
+    };
+    match do_ota(req.uri, progress).await {
         Ok(_) => {
             info!("OTA download successful! rebooting to new image...");
+            state.flush_positions().await;
             restart();
         }
         Err(e) => {
             error!("OTA failed! - {e} {e:?}");
+            state.emit(AppEvent::Error(format!("OTA failed: {e}")));
 
             state.set_status(AppStatus::IDLE).await;
-            return StatusCode::INTERNAL_SERVER_ERROR;
+            Err(ApiError::new(ErrorCode::Unavailable, format!("OTA failed: {e}")))
         }
-    };
+    }
 }
 
-fn handle_ota_resp(mut resp: Response<&mut EspHttpConnection>) -> Result<(), EspError> {
-    if resp.status() != 200 {
-        error!("Unexpected HTTP response: {}", resp.status());
-        return esp_err!(ESP_ERR_INVALID_RESPONSE);
-    }
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
 
-    // Check firmware size
-    let file_size = resp.content_len().unwrap_or(0) as usize;
-    if file_size <= FIRMWARE_MIN_SIZE {
-        error!("Firmware size ({file_size}) is too small!");
-        return esp_err!(ESP_ERR_IMAGE_INVALID);
+    use super::*;
+    use crate::{feedchart::NVS_TAG_RECIPES, sim_stepper::SimStepper, stepper::steps_to_position};
+
+    fn labels() -> Vec<MotorLabel> {
+        ["FloraGro", "FloraMicro", "FloraBloom"]
+            .iter()
+            .enumerate()
+            .map(|(i, nutrient)| MotorLabel {
+                id: 4 + i as u32,
+                nutrient: Some(nutrient.to_string()),
+                ..Default::default()
+            })
+            .collect()
     }
-    if file_size > FIRMWARE_MAX_SIZE {
-        error!("Firmware size ({file_size}) is too large!");
-        return esp_err!(ESP_ERR_IMAGE_INVALID);
+
+    fn dispense(reqs: serde_json::Value) -> Result<Vec<JobEntry>, ApiError> {
+        let req: DispenseReq = serde_json::from_value(json!({ "reqs": reqs })).unwrap();
+        req.entries(&labels(), &DoseLimits::default())
     }
 
-    // Start OTA
-    let mut ota = EspOta::new()?;
+    fn dose(req: serde_json::Value) -> Result<Vec<JobEntry>, ApiError> {
+        let req: DoseSolutionReq = serde_json::from_value(req).unwrap();
+        req.entries(&req.nutrients, &labels(), &DoseLimits::default())
+    }
 
-    // For some reason the call to EspOta::get_firmware_info inside these raises ESP_ERR_INVALID_SIZE now???
-    // just logging these, so commenting out for now
-    // info!(
-    //     "CURRENT SLOTS (BOOT, RUN, UPD): ({}, {}, {})",
-    //     ota.get_boot_slot()?.label,
-    //     ota.get_running_slot()?.label,
-    //     ota.get_update_slot()?.label
-    // );
+    /// (entry, code) of every violation
+    fn violations(err: &ApiError) -> Vec<(Option<usize>, ErrorCode)> {
+        err.violations.iter().map(|v| (v.entry, v.code)).collect()
+    }
 
-    let mut upd = ota.initiate_update()?;
-    let mut buf = vec![0; FIRMWARE_DOWNLOAD_CHUNK_SIZE];
-    let mut total: usize = 0;
-    let ota_res = loop {
-        let n = resp.read(&mut buf).unwrap_or_default();
-        total += n;
+    #[test]
+    fn dispense_entries() {
+        let entries = dispense(json!([
+            { "motor_idx": 0, "ml": 2.5 },
+            { "motor_id": 6, "ml": 1 },
+            { "motor": "FloraMicro", "ml": 0.5 },
+        ]))
+        .unwrap();
+        let entries: Vec<_> = entries.iter().map(|e| (e.name.as_str(), e.motor_idx, e.ml_requested)).collect();
+        assert_eq!(entries, [("FloraGro", 0, 2.5), ("FloraBloom", 2, 1.0), ("FloraMicro", 1, 0.5)]);
+    }
 
-        if n > 0 {
-            if let Err(e) = upd.write(&buf[..n]) {
-                error!("Failed to write OTA chunk: {e:?}");
-                break Err(e);
-            }
-            info!(
-                "OTA progress: {:.2}%",
-                100.0 * total as f32 / file_size as f32
-            );
+    #[test]
+    fn dispense_rejects_bad_volumes() {
+        let err = dispense(json!([
+            { "motor_idx": 0, "ml": -1 },
+            { "motor_idx": 1, "ml": 0 },
+            { "motor_idx": 2, "ml": 2 },
+        ]))
+        .unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidVolume);
+        assert_eq!(violations(&err), [(Some(0), ErrorCode::InvalidVolume), (Some(1), ErrorCode::InvalidVolume)]);
+
+        // NaN and infinity don't make it through JSON, but a recipe or a computed dose might
+        for ml in [f64::NAN, f64::INFINITY] {
+            let req = DispenseReq {
+                reqs: vec![DispenseSingle { motor: MotorSel::id(4), ml }],
+            };
+            let err = req.entries(&labels(), &DoseLimits::default()).unwrap_err();
+            assert_eq!(err.code, ErrorCode::InvalidVolume);
         }
+    }
+
+    #[test]
+    fn refuses_what_wouldnt_fit_in_storage() {
+        assert!(check_stored_len(NVS_TAG_RECIPES, &"x".repeat(MAX_STORED_LEN)).is_ok());
+        let err = check_stored_len(NVS_TAG_RECIPES, &"x".repeat(MAX_STORED_LEN + 1)).unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidRequest);
+    }
 
-        if total >= file_size {
-            break Ok(());
+    #[test]
+    fn calibrations_have_to_move_forward() {
+        assert_eq!(check_ml_per_step(0.0032).unwrap(), 0.0032);
+        for ml_per_step in [0.0, -0.0032, f64::NAN, f64::INFINITY] {
+            assert_eq!(check_ml_per_step(ml_per_step).unwrap_err().code, ErrorCode::InvalidRequest);
         }
-    };
+    }
+
+    #[test]
+    fn dispense_enforces_limits() {
+        // Over the motor's and the request's limit at once
+        let err = dispense(json!([{ "motor_idx": 0, "ml": 1e9 }])).unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidVolume);
+        assert_eq!(violations(&err), [(Some(0), ErrorCode::InvalidVolume), (None, ErrorCode::InvalidVolume)]);
+
+        // Every entry is within the limit, but they add up past it on one motor
+        let err = dispense(json!([
+            { "motor_idx": 1, "ml": 100 },
+            { "motor_idx": 0, "ml": 300 },
+            { "motor": "FloraGro", "ml": 300 },
+        ]))
+        .unwrap_err();
+        assert_eq!(violations(&err), [(Some(1), ErrorCode::InvalidVolume)]);
+        assert!(err.message.contains("Motor 4"), "{}", err.message);
+
+        // Every motor is within its limit, but not the request as a whole
+        let limits = DoseLimits {
+            max_request_ml: 500.0,
+            ..Default::default()
+        };
+        let req: DispenseReq = serde_json::from_value(json!({ "reqs": [
+            { "motor_idx": 0, "ml": 300 },
+            { "motor_idx": 1, "ml": 300 },
+        ]}))
+        .unwrap();
+        let err = req.entries(&labels(), &limits).unwrap_err();
+        assert_eq!(violations(&err), [(None, ErrorCode::InvalidVolume)]);
+    }
 
-    // TODO: checksum
+    #[test]
+    fn dispense_rejects_unknown_motors() {
+        let err = dispense(json!([{ "motor_idx": 3, "ml": 1 }])).unwrap_err();
+        assert_eq!(err.code, ErrorCode::UnknownMotor);
+        assert_eq!(err.code.status(), StatusCode::NOT_FOUND);
+
+        let err = dispense(json!([
+            { "motor_id": 99, "ml": 1 },
+            { "motor": "CalMag", "ml": 1 },
+            { "motor_idx": 0, "ml": 1 },
+        ]))
+        .unwrap_err();
+        assert_eq!(err.code, ErrorCode::UnknownMotor);
+        assert_eq!(err.message, "2 problems with the request");
+        assert_eq!(violations(&err), [(Some(0), ErrorCode::UnknownMotor), (Some(1), ErrorCode::UnknownMotor)]);
+
+        let err = dispense(json!([{ "motor_idx": 0, "motor_id": 4, "ml": 1 }])).unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidRequest);
+    }
 
-    if ota_res.is_err() || total < file_size {
-        error!("Error while writing OTA, aborting");
-        error!("Total of {total} out of {file_size} bytes received");
-        return upd.abort();
+    #[test]
+    fn mixed_violations_are_invalid_requests() {
+        let err = dispense(json!([
+            { "motor_idx": 7, "ml": 1 },
+            { "motor_idx": 0, "ml": -1 },
+        ]))
+        .unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidRequest);
+        assert_eq!(err.code.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(violations(&err), [(Some(0), ErrorCode::UnknownMotor), (Some(1), ErrorCode::InvalidVolume)]);
+
+        // Reported as is when there's just the one
+        let err = dispense(json!([{ "motor_idx": 0, "ml": -1 }])).unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidVolume);
+        assert!(err.message.contains("positive"));
+        assert!(Violations::default().into_result().is_ok());
     }
 
-    // OTA was successful if we reach this
-    upd.complete()
-}
+    #[test]
+    fn dispense_rejects_empty_request() {
+        let err = dispense(json!([])).unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidRequest);
+        assert!(err.violations.is_empty());
+    }
+
+    #[test]
+    fn dose_entries() {
+        let entries = dose(json!({
+            "nutrients": [
+                { "name": "FloraGro", "ml_per_gal": 2 },
+                { "name": "FloraMicro", "motor_idx": 1, "ml_per_gal": 0 },
+                { "name": "FloraBloom", "ml_per_gal": 1 },
+            ],
+            "target_amount": 2,
+            "target_unit": "gal",
+        }))
+        .unwrap();
+        // Nutrients at 0 mL/gal are skipped
+        let entries: Vec<_> = entries.iter().map(|e| (e.name.as_str(), e.motor_idx, e.ml_requested)).collect();
+        assert_eq!(entries, [("FloraGro", 0, 4.0), ("FloraBloom", 2, 2.0)]);
+    }
+
+    #[test]
+    fn dose_rejects_bad_requests() {
+        let err = dose(json!({
+            "nutrients": [
+                { "name": "FloraGro", "ml_per_gal": -2 },
+                { "name": "FloraBloom", "motor_idx": 0, "ml_per_gal": 1 },
+                { "name": "CalMag", "ml_per_gal": 1 },
+            ],
+            "target_amount": -1,
+            "target_unit": "L",
+        }))
+        .unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidRequest);
+        assert_eq!(
+            violations(&err),
+            [
+                (None, ErrorCode::InvalidVolume),
+                (Some(0), ErrorCode::InvalidVolume),
+                (Some(1), ErrorCode::InvalidRequest), // From the wrong bottle
+                (Some(2), ErrorCode::UnknownMotor),
+            ]
+        );
+
+        let err = dose(json!({ "nutrients": [], "target_amount": 1, "target_unit": "gal" })).unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidRequest);
+
+        let err = dose(json!({
+            "nutrients": [{ "name": "FloraGro", "ml_per_gal": 0 }],
+            "target_amount": 1,
+            "target_unit": "gal",
+        }))
+        .unwrap_err();
+        assert_eq!(err.code, ErrorCode::InvalidVolume);
+    }
+
+    #[test]
+    fn dose_enforces_limits() {
+        // 1000 gal at 1 mL/gal is over the motor limit
+        let err = dose(json!({
+            "nutrients": [
+                { "name": "FloraGro", "ml_per_gal": 1 },
+                { "name": "FloraMicro", "ml_per_gal": 0.1 },
+            ],
+            "target_amount": 1000,
+            "target_unit": "gal",
+        }))
+        .unwrap_err();
+        assert_eq!(violations(&err), [(Some(0), ErrorCode::InvalidVolume)]);
+
+        // The same nutrient listed twice adds up on its motor
+        let err = dose(json!({
+            "nutrients": [
+                { "name": "FloraGro", "ml_per_gal": 1 },
+                { "name": "FloraGro", "ml_per_gal": 1 },
+            ],
+            "target_amount": 300,
+            "target_unit": "gal",
+        }))
+        .unwrap_err();
+        assert_eq!(violations(&err), [(Some(0), ErrorCode::InvalidVolume)]);
+    }
+
+    #[test]
+    fn dose_limit_updates_keep_what_is_left_out() {
+        let current = DoseLimits {
+            max_motor_ml: 50.0,
+            max_request_ml: 120.0,
+            max_steps: 5000.0,
+        };
+        let update: DoseLimitsUpdate = serde_json::from_value(json!({ "max_request_ml": 200 })).unwrap();
+        let updated = update.apply(current);
+        assert_eq!(
+            (updated.max_motor_ml, updated.max_request_ml, updated.max_steps),
+            (50.0, 200.0, 5000.0)
+        );
+
+        let update: DoseLimitsUpdate = serde_json::from_value(json!({ "max_motor_ml": -5 })).unwrap();
+        assert!(update.apply(current).validate().is_err());
+        assert!(current.validate().is_ok());
+        assert!(current.check_steps(5000.0).is_ok());
+        assert!(current.check_steps(-5000.5).is_err());
+        assert!(current.check_steps(f64::NAN).is_err());
+    }
+
+    fn block_on<F: std::future::Future>(f: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(f)
+    }
 
-async fn do_ota(uri: Uri) -> Result<(), EspError> {
-    let (signal_tx, signal_rx) = oneshot::channel();
-    let req_task = tokio::task::spawn_blocking(move || -> Result<(), EspError> {
-        let mut client = Client::wrap(EspHttpConnection::new(&Configuration {
-            buffer_size: Some(4096),
+    fn motor() -> StepperMotor<SimStepper> {
+        let mut motor = StepperMotor {
+            id: 4,
+            prime_steps: 100,
             ..Default::default()
-        })?);
-
-        let uri_str = uri.to_string();
-        let headers = [(ACCEPT.as_str(), APPLICATION_OCTET_STREAM.as_ref())];
-        let res = match client.request(Method::Get, &uri_str, &headers) {
-            Ok(req) => match req.submit() {
-                Ok(resp) => handle_ota_resp(resp),
-                Err(e) => {
-                    error!("Failed to send request! {e:?}");
-                    esp_err!(ESP_FAIL)
-                }
-            },
-            Err(e) => {
-                error!("Failed to build request! {e:?}");
-                esp_err!(ESP_FAIL)
-            }
         };
+        motor.attach(SimStepper::new(4));
+        motor
+    }
 
-        // Signal the outer await to exit before this thread is done
-        signal_tx.send(res).unwrap();
-        res
-    });
+    #[test]
+    fn restores_settled_positions() {
+        let mut log = PositionLog::default();
+        let mut m = motor();
+        m.driver.as_mut().unwrap().set_position(steps_to_position(100.0));
+        assert!(log.begin([4], [m.saved_position()]).unwrap()[0].moving);
+        let record = log.finish([m.saved_position()]).unwrap();
+        assert!(!record[0].moving);
+
+        let mut rebooted = motor();
+        assert_eq!(rebooted.prime_state(), PrimeState::Unprimed);
+        rebooted.restore(&record[0]);
+        assert_eq!(rebooted.prime_state(), PrimeState::Primed);
+        assert_eq!(rebooted.saved_position(), record[0]);
+    }
+
+    #[test]
+    fn restores_motion_that_never_finished() {
+        let mut log = PositionLog::default();
+        let mut m = motor();
+        m.driver.as_mut().unwrap().set_position(steps_to_position(100.0));
+        let record = log.begin([4], [m.saved_position()]).unwrap();
+
+        let mut rebooted = motor();
+        rebooted.restore(&record[0]);
+        assert_eq!(rebooted.prime_state(), PrimeState::Unknown);
+        assert_eq!(rebooted.driver.as_ref().unwrap().get_position(), 0, "the saved position isn't trusted");
+        assert!(rebooted.saved_position().moving, "still unknown after another reboot");
+
+        // Nothing comes out until it's been unprimed
+        block_on(async {
+            let ctl = MotionCtl::default();
+            assert_eq!(rebooted.dispense_ml(1.0, &ctl).await.unwrap_err().code, ErrorCode::Busy);
+            assert_eq!(rebooted.test_dispense(100.0, 100.0, &ctl).await.unwrap_err().code, ErrorCode::Busy);
+            assert!(rebooted.driver.as_ref().unwrap().moves().is_empty());
+            rebooted.unprime(&ctl).await.unwrap();
+        });
+        assert_eq!(rebooted.prime_state(), PrimeState::Unprimed);
+        assert!(!rebooted.saved_position().moving);
+    }
+
+    /// Motor that's already primed, with short moves that don't take long
+    fn fast_motor() -> StepperMotor<SimStepper> {
+        let mut m = motor();
+        m.prime_steps = 0;
+        let drv = m.driver.as_mut().unwrap();
+        drv.set_profile(MotionProfile { max_accel: 20_000.0, ..*drv.profile() }).unwrap();
+        m
+    }
+
+    /// Every move the motor made, leaving out the empty ones
+    fn moves(m: &StepperMotor<SimStepper>) -> Vec<f64> {
+        let moves = m.driver.as_ref().unwrap().moves().iter().map(|m| m.steps);
+        moves.filter(|&steps| steps != 0.0).collect()
+    }
+
+    #[test]
+    fn carries_what_doesnt_make_a_microstep() {
+        let mut m = fast_motor();
+        m.back_off.steps = 0.0;
+        let microstep_ml = m.ml_per_step / m.profile.microsteps.scale(1.0);
+        let dose = 0.01234; // 123.4 microsteps
+
+        block_on(async {
+            let ctl = MotionCtl::default();
+            let mut dispensed = 0.0;
+            for i in 1..=50 {
+                dispensed += m.dispense_ml(dose, &ctl).await.unwrap();
+                assert!((0.0..microstep_ml).contains(&m.ml_carry), "carry {} after {i} doses", m.ml_carry);
+                assert!((dispensed + m.ml_carry - dose * i as f64).abs() < 1e-9);
+            }
+            // Within a microstep of one big dose, rounding can land either side
+            let position = m.driver.as_ref().unwrap().get_position();
+            let expected = steps_to_position(50.0 * dose / m.ml_per_step);
+            assert!((position - expected).abs() <= steps_to_position(microstep_ml / m.ml_per_step));
+
+            // Less than a microstep only goes out once enough has built up
+            m.ml_carry = 0.0;
+            assert_eq!(m.dispense_ml(0.6 * microstep_ml, &ctl).await.unwrap(), 0.0);
+            assert!((m.dispense_ml(0.6 * microstep_ml, &ctl).await.unwrap() - microstep_ml).abs() < 1e-12);
+            assert!((m.ml_carry - 0.2 * microstep_ml).abs() < 1e-12);
+
+            // A cancelled dose doesn't owe anything
+            let cancelled = ctl.fork();
+            cancelled.cancel();
+            assert_eq!(m.dispense_ml(dose, &cancelled).await.unwrap(), 0.0);
+            assert_eq!(m.ml_carry, 0.0);
+        });
+    }
+
+    #[test]
+    fn back_off_refills_the_tubing_first() {
+        let mut m = fast_motor();
+        assert!(!m.back_off.readvance);
+        m.back_off.steps = 20.0;
+        block_on(async {
+            let ctl = MotionCtl::default();
+            assert_eq!(m.push_steps(50.0, &ctl).await.unwrap(), (50.0, 0.0));
+            assert_eq!(m.retracted_steps, 20.0);
+
+            // The first 20 steps only bring the liquid back up to the nozzle
+            assert_eq!(m.push_steps(50.0, &ctl).await.unwrap(), (50.0, 20.0));
+            assert_eq!(m.retracted_steps, 20.0);
+
+            // Too short to reach the nozzle, it ends up further back
+            assert_eq!(m.push_steps(10.0, &ctl).await.unwrap(), (10.0, 10.0));
+            assert_eq!(m.retracted_steps, 30.0);
+            assert_eq!(m.test_dispense(40.0, 400.0, &ctl).await.unwrap(), 10.0);
+            assert_eq!(m.retracted_steps, 20.0);
+        });
+        assert_eq!(moves(&m), [50.0, -20.0, 50.0, -20.0, 10.0, -20.0, 40.0, -20.0]);
+        let position = m.driver.as_ref().unwrap().get_position();
+        assert_eq!(position, steps_to_position(150.0 - 80.0));
+    }
 
-    // await a signal instead of waiting on the thread so other tasks can keep running
-    let ota_success = signal_rx.await.unwrap();
-    if let Err(e) = req_task.await {
-        error!("Blocking OTA task didn't join properly ???: {e:?}");
+    #[test]
+    fn doses_refill_what_the_back_off_retracted() {
+        let mut m = fast_motor();
+        let back_off = m.back_off_steps();
+        assert!(back_off > 0.5 / m.ml_per_step);
+        block_on(async {
+            let ctl = MotionCtl::default();
+            let dispensed = m.dispense_ml(0.5, &ctl).await.unwrap() + m.dispense_ml(0.5, &ctl).await.unwrap();
+            assert!((dispensed + m.ml_carry - 1.0).abs() < 1e-9);
+            assert!(m.ml_carry < m.ml_per_step);
+        });
+        // All of it came out of the nozzle, the liquid ends up backed off
+        let position = m.driver.as_ref().unwrap().get_position();
+        let expected = steps_to_position(1.0 / m.ml_per_step - back_off);
+        assert!((position - expected).abs() <= steps_to_position(1.0));
+        assert_eq!(m.retracted_steps, back_off);
+    }
+
+    #[test]
+    fn tubing_volumes_follow_the_fit() {
+        let mut m = motor();
+        let fit = Fit {
+            ml_per_step: 0.004,
+            ml_per_step_per_rpm: -1e-6,
+            offset_ml: 0.05,
+            samples: Vec::new(),
+            residuals: Vec::new(),
+            rms_ml: 0.0,
+        };
+        m.set_calibration(Some(fit.clone()));
+        m.back_off.ml = Some(0.5);
+        let rpm = m.profile.max_rpm;
+        assert_eq!(m.fill_ml(), fit.ml_for_steps(100.0, rpm));
+        assert_eq!(m.back_off_steps(), m.profile.microsteps.quantize(fit.steps_for_ml(0.5, rpm)));
+
+        m.driver.as_mut().unwrap().set_position(steps_to_position(100.0));
+        m.retracted_steps = 20.0;
+        assert_eq!(m.tube_ml(), fit.ml_for_steps(80.0, rpm));
+        assert_eq!(m.fill_ml(), fit.ml_for_steps(100.0, rpm) - fit.ml_for_steps(80.0, rpm));
     }
 
-    ota_success
+    #[test]
+    fn readvance_pushes_the_liquid_back_up() {
+        let mut m = fast_motor();
+        m.back_off = BackOff {
+            steps: 20.0,
+            readvance: true,
+            ..Default::default()
+        };
+        block_on(async {
+            let ctl = MotionCtl::default();
+            assert_eq!(m.push_steps(50.0, &ctl).await.unwrap(), (50.0, 0.0));
+            assert_eq!(m.push_steps(50.0, &ctl).await.unwrap(), (50.0, 0.0));
+            assert_eq!(m.retracted_steps, 20.0);
+            let ml = m.dispense_ml(10.0 * m.ml_per_step, &ctl).await.unwrap();
+            assert!((ml - 10.0 * m.ml_per_step).abs() < 1e-12);
+
+            // Stopped before it could push anything out, still retracted
+            let stopped = MotionCtl::default();
+            stopped.stop();
+            assert_eq!(m.push_steps(50.0, &stopped).await.unwrap(), (0.0, 0.0));
+            assert_eq!(m.retracted_steps, 20.0);
+        });
+        assert_eq!(moves(&m), [50.0, -20.0, 20.0, 50.0, -20.0, 20.0, 10.0, -20.0]);
+    }
 }
@@ -0,0 +1,53 @@
+//! Runs the doser HTTP API on the host with simulated motors, so the web app
+//! can be developed and tested without a board.
+//!
+//! Configured through the environment:
+//! - `DOSER_SIM_PORT`: port to listen on (default 8080)
+//! - `DOSER_SIM_STORAGE`: file the config is persisted to (default `doser-sim.json`)
+//! - `DOSER_SIM_MOTORS`: number of motors (default 5, same as the board)
+//! - `DOSER_SIM_ID`: device id, used in the MQTT topics (default `sim`)
+
+use std::env;
+
+use log::info;
+use nutrient_doser::{app, sim_stepper::SimStepper, storage::FileStorage};
+
+// EN pins of the motors on the board, used as ids so a config pulled from a
+// real device can be loaded as-is
+const BOARD_MOTOR_IDS: [u32; 5] = [4, 6, 0, 23, 21];
+
+const DEFAULT_PORT: u16 = 8080;
+const DEFAULT_STORAGE: &str = "doser-sim.json";
+const DEFAULT_ID: &str = "sim";
+
+fn env_or<T: std::str::FromStr>(key: &str, default: T) -> anyhow::Result<T> {
+    match env::var(key) {
+        Ok(v) => v
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Invalid value for {key}: {v}")),
+        Err(_) => Ok(default),
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let port = env_or("DOSER_SIM_PORT", DEFAULT_PORT)?;
+    let storage_path = env_or("DOSER_SIM_STORAGE", DEFAULT_STORAGE.to_owned())?;
+    let num_motors = env_or("DOSER_SIM_MOTORS", BOARD_MOTOR_IDS.len())?;
+    let device_id = env_or("DOSER_SIM_ID", DEFAULT_ID.to_owned())?;
+
+    info!("Simulating {num_motors} motors, storing config in {storage_path}");
+    let drivers = (0..num_motors)
+        .map(|i| {
+            let id = BOARD_MOTOR_IDS.get(i).copied().unwrap_or(100 + i as u32);
+            SimStepper::new(id)
+        })
+        .collect();
+    let nvs = FileStorage::open(storage_path)?;
+
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?
+        .block_on(app::run(drivers, None, Box::new(nvs), device_id, port))
+}
@@ -0,0 +1,539 @@
+use axum::extract::State;
+use log::{info, warn};
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::{
+    app::{check_ml, no_scale, scale_error, AppState},
+    error::{ApiError, ErrorCode, Json},
+    labels::MotorSel,
+    stepper::{MotionCtl, Stepper},
+};
+
+/// A single test dispense, how far the motor moved and what came out
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub struct Sample {
+    pub steps: f64, // full steps
+    pub rpm: f64,
+    pub ml: f64,
+}
+
+/// Volume delivered by a dispense of `steps` at `rpm`, modelled as
+/// `steps * (ml_per_step + ml_per_step_per_rpm * rpm) + offset_ml`. The
+/// offset covers what each dispense gains or loses regardless of its size,
+/// e.g. from tubing that relaxes when the pump stops.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Fit {
+    pub ml_per_step: f64,
+    pub ml_per_step_per_rpm: f64,
+    pub offset_ml: f64,
+    #[serde(default)]
+    pub samples: Vec<Sample>, // Not kept across reboots, see `coefficients_only`
+    #[serde(default)]
+    pub residuals: Vec<f64>, // measured - predicted mL, one per sample
+    pub rms_ml: f64,
+}
+
+/// Serializes just the model of a fit, for storing it with its motor. The
+/// samples behind it would soon outgrow the motors' NVS string.
+pub fn coefficients_only<S: Serializer>(fit: &Option<Fit>, serializer: S) -> Result<S::Ok, S::Error> {
+    fit.as_ref()
+        .map(|fit| Fit {
+            samples: Vec::new(),
+            residuals: Vec::new(),
+            ..*fit
+        })
+        .serialize(serializer)
+}
+
+impl Fit {
+    pub fn ml_per_step_at(&self, rpm: f64) -> f64 {
+        self.ml_per_step + self.ml_per_step_per_rpm * rpm
+    }
+
+    pub fn ml_for_steps(&self, steps: f64, rpm: f64) -> f64 {
+        match steps > 0.0 {
+            true => (steps * self.ml_per_step_at(rpm) + self.offset_ml).max(0.0),
+            false => 0.0,
+        }
+    }
+
+    pub fn steps_for_ml(&self, ml: f64, rpm: f64) -> f64 {
+        match ml > 0.0 {
+            true => ((ml - self.offset_ml) / self.ml_per_step_at(rpm)).max(0.0),
+            false => 0.0,
+        }
+    }
+
+    /// Least squares fit through `samples`. Terms the samples can't pin down
+    /// are left out, a single speed gets no speed term and a single volume
+    /// gets no offset.
+    pub fn new(samples: Vec<Sample>) -> Result<Self, String> {
+        if samples.is_empty() {
+            return Err("Nothing measured yet".to_owned());
+        }
+        if let Some(s) = samples.iter().find(|s| !(s.steps > 0.0 && s.rpm > 0.0 && s.ml >= 0.0)) {
+            return Err(format!("Invalid sample {s:?}"));
+        }
+
+        // Columns of the model: steps, steps * rpm, 1
+        let terms: [&[usize]; 4] = [&[0, 1, 2], &[0, 2], &[0, 1], &[0]];
+        let coeffs = terms
+            .iter()
+            .filter(|t| t.len() <= samples.len())
+            .find_map(|t| least_squares(&samples, t))
+            .ok_or("Samples don't determine a fit")?;
+
+        let mut fit = Self {
+            ml_per_step: coeffs[0],
+            ml_per_step_per_rpm: coeffs[1],
+            offset_ml: coeffs[2],
+            residuals: Vec::new(),
+            rms_ml: 0.0,
+            samples,
+        };
+        if let Some(s) = fit.samples.iter().find(|s| fit.ml_per_step_at(s.rpm) <= 0.0) {
+            return Err(format!("Fit doesn't move any liquid at {} rpm", s.rpm));
+        }
+        fit.residuals = fit
+            .samples
+            .iter()
+            .map(|s| s.ml - (s.steps * fit.ml_per_step_at(s.rpm) + fit.offset_ml))
+            .collect();
+        fit.rms_ml = (fit.residuals.iter().map(|r| r * r).sum::<f64>() / fit.residuals.len() as f64).sqrt();
+        Ok(fit)
+    }
+}
+
+fn column(s: &Sample, term: usize) -> f64 {
+    match term {
+        0 => s.steps,
+        1 => s.steps * s.rpm,
+        _ => 1.0,
+    }
+}
+
+/// Solves the normal equations for the given model terms, `None` if they're
+/// singular. Coefficients of left out terms are 0.
+fn least_squares(samples: &[Sample], terms: &[usize]) -> Option<[f64; 3]> {
+    let n = terms.len();
+    let mut a = [[0.0; 4]; 3]; // augmented with the right hand side
+    for s in samples {
+        for (i, &ti) in terms.iter().enumerate() {
+            for (j, &tj) in terms.iter().enumerate() {
+                a[i][j] += column(s, ti) * column(s, tj);
+            }
+            a[i][n] += column(s, ti) * s.ml;
+        }
+    }
+
+    // Gaussian elimination with partial pivoting
+    for col in 0..n {
+        let pivot = (col..n).max_by(|&x, &y| a[x][col].abs().total_cmp(&a[y][col].abs()))?;
+        let scale = (0..n).map(|r| a[r][col].abs()).fold(0.0, f64::max);
+        if a[pivot][col].abs() <= scale * 1e-9 {
+            return None;
+        }
+        a.swap(col, pivot);
+        for row in 0..n {
+            if row != col {
+                let factor = a[row][col] / a[col][col];
+                for k in col..=n {
+                    a[row][k] -= factor * a[col][k];
+                }
+            }
+        }
+    }
+
+    let mut coeffs = [0.0; 3];
+    for (i, &t) in terms.iter().enumerate() {
+        coeffs[t] = a[i][n] / a[i][i];
+    }
+    Some(coeffs)
+}
+
+/// One planned test dispense of a calibration session
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SessionPoint {
+    pub ml: f64, // Rough volume to aim for, using the current calibration
+    pub rpm: f64,
+    pub steps: Option<f64>, // Set once it has run
+    pub measured_ml: Option<f64>,
+}
+
+/// Calibration of one motor in progress
+#[derive(Serialize, Clone, Debug)]
+pub struct Session {
+    pub motor_id: u32,
+    pub points: Vec<SessionPoint>,
+    #[serde(skip)]
+    pub ctl: MotionCtl, // Cancels a test dispense that's running when the session goes away
+}
+
+impl Session {
+    pub fn samples(&self) -> Vec<Sample> {
+        self.points
+            .iter()
+            .filter_map(|p| match (p.steps, p.measured_ml) {
+                (Some(steps), Some(ml)) => Some(Sample { steps, rpm: p.rpm, ml }),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+// Default plan of a calibration session, each volume runs at full and at half speed
+const CALIBRATION_ML: [f64; 2] = [2.0, 10.0];
+
+// More test dispenses than anyone would measure by hand
+const MAX_CALIBRATION_POINTS: usize = 16;
+
+#[derive(Deserialize)]
+pub(crate) struct CalibrationPlanPoint {
+    ml: f64,
+    rpm: Option<f64>, // Defaults to the motor's max_rpm
+}
+
+#[derive(Deserialize)]
+pub(crate) struct StartCalibrationReq {
+    #[serde(flatten)]
+    motor: MotorSel,
+    #[serde(default)]
+    points: Vec<CalibrationPlanPoint>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct CalibrationPointReq {
+    point: usize,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct MeasureReq {
+    point: usize,
+    ml: Option<f64>,
+    grams: Option<f64>, // Weighed instead, converted with density
+    #[serde(default = "default_density")]
+    density: f64, // g/mL
+}
+
+fn default_density() -> f64 {
+    1.0
+}
+
+fn no_session() -> ApiError {
+    ApiError::not_found("No calibration in progress")
+}
+
+pub(crate) async fn get_calibration<S: Stepper>(
+    State(state): State<AppState<S>>,
+) -> Result<Json<Session>, ApiError> {
+    state.calibration.lock().await.clone().map(Json).ok_or_else(no_session)
+}
+
+/// New session for the motor in `req`, also returns the motor's index
+async fn plan_calibration<S: Stepper>(
+    state: &AppState<S>,
+    req: &StartCalibrationReq,
+) -> Result<(usize, Session), ApiError> {
+    let idx = state.motor_idx(&req.motor).await?;
+    let (motor_id, max_rpm) = {
+        let motor = &state.motor_snapshots()[idx];
+        (motor.id, motor.profile.max_rpm)
+    };
+    let plan: Vec<_> = match req.points.is_empty() {
+        true => [max_rpm, max_rpm / 2.0]
+            .into_iter()
+            .flat_map(|rpm| CALIBRATION_ML.map(|ml| (ml, rpm)))
+            .collect(),
+        false => req.points.iter().map(|p| (p.ml, p.rpm.unwrap_or(max_rpm))).collect(),
+    };
+    if plan.len() > MAX_CALIBRATION_POINTS {
+        return Err(ApiError::invalid(format!("At most {MAX_CALIBRATION_POINTS} points per calibration, got {}", plan.len())));
+    }
+    let max_ml = state.dose_limits.read().await.max_motor_ml;
+    let valid = |ml: f64, rpm: f64| check_ml(ml).is_ok() && ml <= max_ml && rpm.is_finite() && rpm > 0.0;
+    if let Some((ml, rpm)) = plan.iter().find(|(ml, rpm)| !valid(*ml, *rpm)) {
+        return Err(ApiError::invalid_volume(format!("Invalid point {ml} mL at {rpm} rpm")));
+    }
+
+    let session = Session {
+        motor_id,
+        points: plan
+            .into_iter()
+            .map(|(ml, rpm)| SessionPoint { ml, rpm, steps: None, measured_ml: None })
+            .collect(),
+        ctl: state.motion.fork(),
+    };
+    Ok((idx, session))
+}
+
+/// Starts calibrating a motor, replacing any session in progress
+pub(crate) async fn start_calibration<S: Stepper>(
+    State(state): State<AppState<S>>,
+    Json(req): Json<StartCalibrationReq>,
+) -> Result<Json<Session>, ApiError> {
+    let (_, session) = plan_calibration(&state, &req).await?;
+    if let Some(old) = state.calibration.lock().await.replace(session.clone()) {
+        warn!("Dropping the calibration of motor {} in progress", old.motor_id);
+        old.ctl.cancel();
+    }
+    Ok(Json(session))
+}
+
+/// Runs one test dispense of the session, it can be rerun before finishing
+pub(crate) async fn run_calibration<S: Stepper>(
+    State(state): State<AppState<S>>,
+    Json(req): Json<CalibrationPointReq>,
+) -> Result<Json<Session>, ApiError> {
+    let (motor_id, point, ctl) = {
+        let session = state.calibration.lock().await;
+        let Some(session) = session.as_ref() else {
+            return Err(no_session());
+        };
+        let Some(point) = session.points.get(req.point) else {
+            return Err(ApiError::not_found(format!("No point {}", req.point)));
+        };
+        (session.motor_id, point.clone(), session.ctl.clone())
+    };
+    // The session isn't locked while the motor runs, so it can still be
+    // looked at, or cancelled which stops the dispense
+    let idx = state.motor_idx(&MotorSel::id(motor_id)).await?;
+    let delivered = state.calibration_dispense(idx, &point, &ctl).await?;
+
+    let mut session = state.calibration.lock().await;
+    match session.as_mut() {
+        Some(session) if session.ctl.is_same(&ctl) => {
+            let point = &mut session.points[req.point];
+            point.steps = Some(delivered);
+            point.measured_ml = None;
+            Ok(Json(session.clone()))
+        }
+        _ => Err(no_session()),
+    }
+}
+
+/// Records what a test dispense actually delivered
+pub(crate) async fn measure_calibration<S: Stepper>(
+    State(state): State<AppState<S>>,
+    Json(req): Json<MeasureReq>,
+) -> Result<Json<Session>, ApiError> {
+    let ml = match (req.ml, req.grams) {
+        (Some(ml), None) => ml,
+        (None, Some(grams)) if req.density.is_finite() && req.density > 0.0 => grams / req.density,
+        (None, Some(_)) => return Err(ApiError::invalid("density must be positive")),
+        _ => return Err(ApiError::invalid("Give either ml or grams")),
+    };
+    if !(ml.is_finite() && ml >= 0.0) {
+        return Err(ApiError::invalid_volume("Measurement can't be negative"));
+    }
+
+    let mut session = state.calibration.lock().await;
+    let Some(session) = session.as_mut() else {
+        return Err(no_session());
+    };
+    match session.points.get_mut(req.point) {
+        Some(point) if point.steps.is_some() => point.measured_ml = Some(ml),
+        Some(_) => return Err(ApiError::busy(format!("Point {} hasn't run yet", req.point))),
+        None => return Err(ApiError::not_found(format!("No point {}", req.point))),
+    }
+    Ok(Json(session.clone()))
+}
+
+/// Fits the measured points and stores the fit with the motor
+pub(crate) async fn finish_calibration<S: Stepper>(
+    State(state): State<AppState<S>>,
+) -> Result<Json<Fit>, ApiError> {
+    let mut session = state.calibration.lock().await;
+    let Some(current) = session.as_ref() else {
+        return Err(no_session());
+    };
+    let fit = Fit::new(current.samples()).map_err(ApiError::invalid)?;
+    let idx = state.motor_idx(&MotorSel::id(current.motor_id)).await?;
+    state.apply_calibration(idx, fit.clone()).await?;
+    *session = None;
+    Ok(Json(fit))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct AutoCalibrateReq {
+    #[serde(flatten)]
+    plan: StartCalibrationReq,
+    #[serde(default = "default_density")]
+    density: f64, // g/mL
+}
+
+/// Calibrates a motor without anyone measuring, each test dispense gets
+/// weighed in a cup on the scale
+pub(crate) async fn auto_calibrate<S: Stepper>(
+    State(state): State<AppState<S>>,
+    Json(req): Json<AutoCalibrateReq>,
+) -> Result<Json<Fit>, ApiError> {
+    let Some(scale) = &state.scale else {
+        return Err(no_scale());
+    };
+    if !(req.density.is_finite() && req.density > 0.0) {
+        return Err(ApiError::invalid("density must be positive"));
+    }
+    let (idx, mut session) = plan_calibration(&state, &req.plan).await?;
+    // Registered like a manual session, so it shows its progress and can be cancelled
+    if let Some(old) = state.calibration.lock().await.replace(session.clone()) {
+        warn!("Dropping the calibration of motor {} in progress", old.motor_id);
+        old.ctl.cancel();
+    }
+
+    let res = async {
+        let mut scale = scale.lock().await;
+        for i in 0..session.points.len() {
+            let point = &mut session.points[i];
+            let (delivered, grams) = scale
+                .weigh_dispense(state.calibration_dispense(idx, point, &session.ctl))
+                .await
+                .map_err(scale_error)?;
+            point.steps = Some(delivered?);
+            point.measured_ml = Some(grams / req.density);
+            info!("Motor {}: {:.3} g from {:.2} steps at {} rpm", session.motor_id, grams, point.steps.unwrap_or_default(), point.rpm);
+
+            match state.calibration.lock().await.as_mut() {
+                Some(current) if current.ctl.is_same(&session.ctl) => current.points[i] = point.clone(),
+                _ => return Err(ApiError::new(ErrorCode::Stopped, "Calibration was cancelled")),
+            }
+        }
+        drop(scale);
+        Fit::new(session.samples()).map_err(ApiError::invalid)
+    }
+    .await;
+
+    let mut current = state.calibration.lock().await;
+    if current.as_ref().is_some_and(|s| s.ctl.is_same(&session.ctl)) {
+        *current = None;
+    }
+    drop(current);
+    let fit = res?;
+    state.apply_calibration(idx, fit.clone()).await?;
+    Ok(Json(fit))
+}
+
+pub(crate) async fn cancel_calibration<S: Stepper>(State(state): State<AppState<S>>) -> Result<Json<Session>, ApiError> {
+    match state.calibration.lock().await.take() {
+        Some(session) => {
+            session.ctl.cancel();
+            Ok(Json(session))
+        }
+        None => Err(no_session()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(steps: f64, rpm: f64, ml: f64) -> Sample {
+        Sample { steps, rpm, ml }
+    }
+
+    /// Samples of the model at every combination of `steps` and `rpms`
+    fn model(ml_per_step: f64, per_rpm: f64, offset: f64, steps: &[f64], rpms: &[f64]) -> Vec<Sample> {
+        let mut samples = Vec::new();
+        for &rpm in rpms {
+            for &steps in steps {
+                samples.push(sample(steps, rpm, steps * (ml_per_step + per_rpm * rpm) + offset));
+            }
+        }
+        samples
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "{actual} != {expected}");
+    }
+
+    #[test]
+    fn fits_exact_linear_data() {
+        let fit = Fit::new(model(0.003, 0.0, 0.0, &[500.0, 2000.0, 8000.0], &[100.0, 400.0])).unwrap();
+        assert_close(fit.ml_per_step, 0.003);
+        assert_close(fit.ml_per_step_per_rpm, 0.0);
+        assert_close(fit.offset_ml, 0.0);
+        assert!(fit.rms_ml < 1e-9);
+        assert_eq!(fit.residuals.len(), 6);
+        assert_close(fit.steps_for_ml(3.0, 250.0), 1000.0);
+    }
+
+    #[test]
+    fn stores_only_the_coefficients() {
+        let fit = Fit::new(model(0.003, -1e-6, 0.2, &[500.0, 2000.0, 8000.0], &[100.0, 400.0])).unwrap();
+        let mut stored = Vec::new();
+        coefficients_only(&Some(fit.clone()), &mut serde_json::Serializer::new(&mut stored)).unwrap();
+        assert!(stored.len() < 200);
+        let restored: Option<Fit> = serde_json::from_slice(&stored).unwrap();
+        let restored = restored.unwrap();
+        assert!(restored.samples.is_empty());
+        assert_eq!(restored.steps_for_ml(3.0, 250.0), fit.steps_for_ml(3.0, 250.0));
+        assert_eq!(restored.rms_ml, fit.rms_ml);
+    }
+
+    #[test]
+    fn fits_offset_and_speed() {
+        let fit = Fit::new(model(0.003, -1e-6, 0.2, &[500.0, 2000.0, 8000.0], &[100.0, 400.0])).unwrap();
+        assert_close(fit.ml_per_step, 0.003);
+        assert_close(fit.ml_per_step_per_rpm, -1e-6);
+        assert_close(fit.offset_ml, 0.2);
+        assert!(fit.rms_ml < 1e-9);
+        assert_close(fit.ml_for_steps(fit.steps_for_ml(5.0, 300.0), 300.0), 5.0);
+    }
+
+    #[test]
+    fn single_speed_has_no_speed_term() {
+        let fit = Fit::new(model(0.003, 0.0, 0.2, &[500.0, 2000.0, 8000.0], &[400.0])).unwrap();
+        assert_eq!(fit.ml_per_step_per_rpm, 0.0);
+        assert_close(fit.ml_per_step, 0.003);
+        assert_close(fit.offset_ml, 0.2);
+    }
+
+    #[test]
+    fn single_volume_has_no_offset() {
+        let fit = Fit::new(model(0.003, 1e-6, 0.0, &[2000.0], &[100.0, 250.0, 400.0])).unwrap();
+        assert_eq!(fit.offset_ml, 0.0);
+        assert_close(fit.ml_per_step, 0.003);
+        assert_close(fit.ml_per_step_per_rpm, 1e-6);
+    }
+
+    #[test]
+    fn noisy_data_has_residuals() {
+        let samples = vec![sample(1000.0, 400.0, 3.1), sample(2000.0, 400.0, 5.9), sample(3000.0, 400.0, 9.1)];
+        let fit = Fit::new(samples).unwrap();
+        let sum: f64 = fit.residuals.iter().sum();
+        assert!(sum.abs() < 1e-9, "residuals of a fit with an offset sum to 0");
+        let rms = (fit.residuals.iter().map(|r| r * r).sum::<f64>() / 3.0).sqrt();
+        assert_close(fit.rms_ml, rms);
+        assert!(fit.rms_ml > 0.05);
+    }
+
+    #[test]
+    fn singular_samples_fall_back() {
+        // The same dispense over and over only pins down the volume per step
+        let samples = vec![sample(1000.0, 400.0, 3.0), sample(1000.0, 400.0, 3.2), sample(1000.0, 400.0, 2.8)];
+        assert!(least_squares(&samples, &[0, 1, 2]).is_none());
+        assert!(least_squares(&samples, &[0, 2]).is_none());
+        assert!(least_squares(&samples, &[0, 1]).is_none());
+
+        let fit = Fit::new(samples).unwrap();
+        assert_close(fit.ml_per_step, 0.003);
+        assert_eq!(fit.ml_per_step_per_rpm, 0.0);
+        assert_eq!(fit.offset_ml, 0.0);
+    }
+
+    #[test]
+    fn rejects_negative_slope() {
+        let samples = vec![sample(1000.0, 400.0, 3.0), sample(2000.0, 400.0, 1.0)];
+        assert!(Fit::new(samples).is_err());
+        // Slows down so much with speed that nothing comes out at the top
+        let samples = model(0.003, -1e-5, 0.0, &[2000.0], &[100.0, 400.0]);
+        assert!(Fit::new(samples).is_err());
+    }
+
+    #[test]
+    fn rejects_invalid_samples() {
+        assert!(Fit::new(Vec::new()).is_err());
+        assert!(Fit::new(vec![sample(0.0, 400.0, 1.0)]).is_err());
+        assert!(Fit::new(vec![sample(1000.0, 400.0, f64::NAN)]).is_err());
+        assert!(Fit::new(vec![sample(1000.0, -1.0, 1.0)]).is_err());
+    }
+}
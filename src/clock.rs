@@ -0,0 +1,99 @@
+use std::{
+    fmt::{self, Display},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// The C library does the timezone math on both targets, so POSIX TZ strings
+// behave the same in the simulator as on the device
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::sys as libc;
+
+extern "C" {
+    // Not in every set of bindings, but every C library has it
+    fn tzset();
+}
+
+pub const DEFAULT_TZ: &str = "UTC0";
+
+// Anything earlier means the clock was never set, the ESP32 boots at 1970
+const MIN_VALID_TIME: i64 = 1_704_067_200; // 2024-01-01
+
+// The C library reads TZ while converting, changing it at the same time is
+// undefined behaviour, so every conversion and change goes through this
+static TZ_LOCK: Mutex<()> = Mutex::new(());
+
+fn tz_lock() -> std::sync::MutexGuard<'static, ()> {
+    // Nothing it guards can be left half done
+    TZ_LOCK.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Unix time in seconds, `None` until the clock has been set by SNTP
+pub fn now() -> Option<i64> {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs() as i64;
+    (secs >= MIN_VALID_TIME).then_some(secs)
+}
+
+/// Apply a POSIX TZ string like `EST5EDT,M3.2.0,M11.1.0` to all local time conversions
+pub fn set_timezone(tz: &str) -> Result<(), String> {
+    if tz.is_empty() || tz.len() > 64 || !tz.is_ascii() || tz.contains('\0') {
+        return Err(format!("Invalid timezone: {tz:?}"));
+    }
+    let _lock = tz_lock();
+    std::env::set_var("TZ", tz);
+    unsafe { tzset() };
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct LocalTime {
+    pub year: i32,
+    pub month: u32, // 1-12
+    pub day: u32,
+    pub weekday: u32, // days since Sunday
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+}
+
+impl Display for LocalTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+pub fn to_local(time: i64) -> LocalTime {
+    let t = time as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    {
+        let _lock = tz_lock();
+        unsafe { libc::localtime_r(&t, &mut tm) };
+    }
+    LocalTime {
+        year: tm.tm_year as i32 + 1900,
+        month: tm.tm_mon as u32 + 1,
+        day: tm.tm_mday as u32,
+        weekday: tm.tm_wday as u32,
+        hour: tm.tm_hour as u32,
+        minute: tm.tm_min as u32,
+        second: tm.tm_sec as u32,
+    }
+}
+
+/// Unix time of a local wall clock time, `day` may run past the end of the
+/// month (or below 1) and gets normalized
+pub fn from_local(year: i32, month: u32, day: i32, hour: u32, minute: u32) -> i64 {
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    tm.tm_year = (year - 1900) as _;
+    tm.tm_mon = (month as i32 - 1) as _;
+    tm.tm_mday = day as _;
+    tm.tm_hour = hour as _;
+    tm.tm_min = minute as _;
+    tm.tm_isdst = -1; // let the library figure out DST
+    let _lock = tz_lock();
+    unsafe { libc::mktime(&mut tm) as i64 }
+}
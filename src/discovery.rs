@@ -0,0 +1,25 @@
+// DNS-SD service the doser advertises over mDNS, `_nutrient-doser._tcp.local`
+pub const SERVICE_TYPE: &str = "_nutrient-doser";
+pub const SERVICE_PROTO: &str = "_tcp";
+
+/// Stable id for a board, its station MAC
+pub fn device_id(mac: [u8; 6]) -> String {
+    mac.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// mDNS hostname, the configured one or one made unique by the end of the MAC
+pub fn mdns_hostname(hostname: Option<&str>, device_id: &str) -> String {
+    match hostname {
+        Some(hostname) => hostname.to_owned(),
+        None => format!("nutrient-doser-{}", &device_id[device_id.len().saturating_sub(4)..]),
+    }
+}
+
+/// TXT records describing the board, so it can be told apart without asking it
+pub fn txt_records(device_id: &str, motors: usize) -> Vec<(&'static str, String)> {
+    vec![
+        ("version", env!("CARGO_PKG_VERSION").to_owned()),
+        ("motors", motors.to_string()),
+        ("id", device_id.to_owned()),
+    ]
+}
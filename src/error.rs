@@ -0,0 +1,184 @@
+use std::fmt;
+
+use axum::{
+    extract::{
+        rejection::{JsonRejection, PathRejection, QueryRejection},
+        FromRequest, FromRequestParts,
+    },
+    http::StatusCode,
+    response::{IntoResponse, Response},
+};
+use log::warn;
+use serde::Serialize;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    UnknownMotor,
+    InvalidVolume,
+    InvalidRequest,
+    NotFound,
+    NotEnoughLiquid,
+    Busy, // Something else is in the way, e.g. a nutrient that's already on another motor
+    Stopped, // Emergency stop is active
+    Unavailable, // Clock isn't set, scale isn't responding, ...
+    HardwareFault,
+    StorageError,
+}
+
+impl ErrorCode {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ErrorCode::UnknownMotor | ErrorCode::NotFound => StatusCode::NOT_FOUND,
+            ErrorCode::InvalidVolume | ErrorCode::InvalidRequest => StatusCode::BAD_REQUEST,
+            ErrorCode::NotEnoughLiquid | ErrorCode::Busy | ErrorCode::Stopped => StatusCode::CONFLICT,
+            ErrorCode::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ErrorCode::HardwareFault | ErrorCode::StorageError => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Error body of every endpoint, `{"code": "UNKNOWN_MOTOR", "message": "..."}`.
+/// Requests with a list of entries also get every problem found in them.
+#[derive(Serialize, Clone, Debug)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub violations: Vec<Violation>,
+}
+
+/// One problem with a request
+#[derive(Serialize, Clone, Debug)]
+pub struct Violation {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub entry: Option<usize>, // Index into the request's list, None if it's about the whole request
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            violations: Vec::new(),
+        }
+    }
+
+    pub fn invalid(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::InvalidRequest, message)
+    }
+
+    pub fn invalid_volume(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::InvalidVolume, message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::NotFound, message)
+    }
+
+    pub fn busy(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Busy, message)
+    }
+
+    pub fn stopped() -> Self {
+        Self::new(ErrorCode::Stopped, "Emergency stop is active")
+    }
+
+    pub fn no_clock() -> Self {
+        Self::new(ErrorCode::Unavailable, "Clock isn't set yet")
+    }
+}
+
+/// Collects everything wrong with a request instead of stopping at the first
+/// problem, so it can all be fixed in one go
+#[derive(Default)]
+pub struct Violations(Vec<Violation>);
+
+impl Violations {
+    pub fn add(&mut self, entry: Option<usize>, err: ApiError) {
+        self.0.push(Violation {
+            entry,
+            code: err.code,
+            message: err.message,
+        });
+    }
+
+    /// Value of an entry's result, recording its error if it failed
+    pub fn check<T>(&mut self, entry: usize, res: Result<T, ApiError>) -> Option<T> {
+        res.map_err(|e| self.add(Some(entry), e)).ok()
+    }
+
+    /// A single problem is reported as is, several share their code if
+    /// they agree on one
+    pub fn into_result(self) -> Result<(), ApiError> {
+        let Some(first) = self.0.first() else {
+            return Ok(());
+        };
+        let code = match self.0.iter().all(|v| v.code == first.code) {
+            true => first.code,
+            false => ErrorCode::InvalidRequest,
+        };
+        let message = match self.0.len() {
+            1 => first.message.clone(),
+            n => format!("{n} problems with the request"),
+        };
+        Err(ApiError {
+            code,
+            message,
+            violations: self.0,
+        })
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        warn!("Request failed: {self}");
+        (self.code.status(), axum::Json(self)).into_response()
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        Self::invalid(rejection.body_text())
+    }
+}
+
+impl From<PathRejection> for ApiError {
+    fn from(rejection: PathRejection) -> Self {
+        Self::invalid(rejection.body_text())
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        Self::invalid(rejection.body_text())
+    }
+}
+
+// axum's extractors, with malformed requests answered the same way as any other error
+
+#[derive(FromRequest)]
+#[from_request(via(axum::Json), rejection(ApiError))]
+pub struct Json<T>(pub T);
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
+}
+
+#[derive(FromRequestParts)]
+#[from_request(via(axum::extract::Path), rejection(ApiError))]
+pub struct Path<T>(pub T);
+
+#[derive(FromRequestParts)]
+#[from_request(via(axum::extract::Query), rejection(ApiError))]
+pub struct Query<T>(pub T);
@@ -0,0 +1,628 @@
+use std::{collections::BTreeMap, fmt, marker::PhantomData};
+
+use serde::{
+    de::{MapAccess, Visitor},
+    ser::SerializeMap,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use axum::extract::State;
+use log::{error, info, warn};
+
+use crate::{
+    app::{pin_motors, storage_error, AppState},
+    clock,
+    error::{ApiError, ErrorCode, Json, Path, Violations},
+    labels::{MotorLabel, MotorSel},
+    schedule::ScheduledAction,
+    stepper::Stepper,
+};
+
+const DAY: i64 = 24 * 60 * 60;
+
+pub(crate) const NVS_TAG_RECIPES: &str = "recipes";
+const NVS_TAG_GROW: &str = "grow";
+
+/// JSON object that keeps its keys in order, stages of a feed schedule only
+/// make sense in the order the chart lists them
+#[derive(Clone, Debug)]
+pub struct OrderedMap<V>(pub Vec<(String, V)>);
+
+impl<V> OrderedMap<V> {
+    pub fn get(&self, key: &str) -> Option<&V> {
+        self.0.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+}
+
+impl<V: Serialize> Serialize for OrderedMap<V> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (k, v) in &self.0 {
+            map.serialize_entry(k, v)?;
+        }
+        map.end()
+    }
+}
+
+impl<'de, V: Deserialize<'de>> Deserialize<'de> for OrderedMap<V> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct OrderedVisitor<V>(PhantomData<V>);
+
+        impl<'de, V: Deserialize<'de>> Visitor<'de> for OrderedVisitor<V> {
+            type Value = OrderedMap<V>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a map")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> Result<Self::Value, A::Error> {
+                let mut entries = Vec::with_capacity(access.size_hint().unwrap_or(0));
+                while let Some(entry) = access.next_entry()? {
+                    entries.push(entry);
+                }
+                Ok(OrderedMap(entries))
+            }
+        }
+
+        deserializer.deserialize_map(OrderedVisitor(PhantomData))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub enum NutrientUnit {
+    #[serde(rename = "mL")]
+    Ml,
+    #[serde(rename = "g")]
+    G, // Dry nutrients, can't be pumped
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChartNutrient {
+    pub name: String,
+    pub unit: NutrientUnit,
+    #[serde(default)]
+    pub color: String,
+}
+
+/// Feed chart in the web app's format, schedule -> stage -> nutrient -> amount per gallon
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Chart {
+    pub name: String,
+    pub nutrients: Vec<ChartNutrient>,
+    pub charts: OrderedMap<OrderedMap<OrderedMap<f64>>>,
+}
+
+/// How much of a nutrient to add per gallon of water, and which motor pumps
+/// it. Without a motor it goes to the one labeled with `name`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct NutrientInfo {
+    pub name: String,
+    #[serde(flatten)]
+    pub motor: MotorSel,
+    pub ml_per_gal: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GrowStage {
+    pub name: String,
+    pub days: Option<u32>, // Stays in this stage until changed by hand if unset
+    pub nutrients: Vec<NutrientInfo>,
+}
+
+/// One feed schedule out of a chart, with its nutrients mapped to motors
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Grow {
+    pub chart: String,
+    pub schedule: String,
+    pub stages: Vec<GrowStage>,
+    pub started: i64, // Unix time
+    pub stage: usize,
+    pub stage_started: i64,
+}
+
+impl Grow {
+    /// Pick `schedule` out of `chart`, skipping any nutrient that isn't in
+    /// `motors`. Returns the grow and the names of the skipped nutrients.
+    pub fn from_chart(
+        chart: &Chart,
+        schedule: &str,
+        motors: &BTreeMap<String, MotorSel>,
+        stage_days: &BTreeMap<String, u32>,
+        started: i64,
+    ) -> Result<(Self, Vec<String>), String> {
+        let stages = chart
+            .charts
+            .get(schedule)
+            .ok_or_else(|| format!("Chart {} has no schedule {schedule:?}", chart.name))?;
+        if stages.0.is_empty() {
+            return Err(format!("Schedule {schedule:?} has no stages"));
+        }
+        for name in motors.keys() {
+            match chart.nutrients.iter().find(|n| &n.name == name) {
+                Some(n) if n.unit != NutrientUnit::Ml => {
+                    return Err(format!("{name} is a dry nutrient, it can't be pumped"))
+                }
+                Some(_) => {}
+                None => return Err(format!("Chart {} has no nutrient {name:?}", chart.name)),
+            }
+        }
+        if let Some(name) = stage_days.keys().find(|name| stages.get(name).is_none()) {
+            return Err(format!("Schedule {schedule:?} has no stage {name:?}"));
+        }
+
+        let mut skipped = Vec::new();
+        let stages = stages
+            .0
+            .iter()
+            .map(|(stage, amounts)| GrowStage {
+                name: stage.clone(),
+                days: stage_days.get(stage).copied(),
+                nutrients: amounts
+                    .0
+                    .iter()
+                    .filter_map(|(name, ml_per_gal)| match motors.get(name) {
+                        Some(motor) => Some(NutrientInfo {
+                            name: name.clone(),
+                            motor: motor.clone(),
+                            ml_per_gal: *ml_per_gal,
+                        }),
+                        None => {
+                            if !skipped.contains(name) {
+                                skipped.push(name.clone());
+                            }
+                            None
+                        }
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        let grow = Self {
+            chart: chart.name.clone(),
+            schedule: schedule.to_owned(),
+            stages,
+            started,
+            stage: 0,
+            stage_started: started,
+        };
+        Ok((grow, skipped))
+    }
+
+    /// Checks a grow read back from storage, anything else only ever points
+    /// at one of its own stages
+    pub fn validate(&self) -> Result<(), String> {
+        match self.stages.len() {
+            0 => Err("Grow has no stages".to_owned()),
+            n if self.stage >= n => Err(format!("Grow is at stage {} of {n}", self.stage + 1)),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn current(&self) -> &GrowStage {
+        &self.stages[self.stage]
+    }
+
+    /// When the current stage is over, `None` if it doesn't end on its own
+    pub fn stage_ends(&self) -> Option<i64> {
+        match self.stage + 1 < self.stages.len() {
+            true => self.current().days.map(|days| self.stage_started + days as i64 * DAY),
+            false => None,
+        }
+    }
+
+    /// Move on to whatever stage `now` falls in, returns whether the stage changed
+    pub fn advance(&mut self, now: i64) -> bool {
+        let stage = self.stage;
+        while let Some(ends) = self.stage_ends().filter(|ends| *ends <= now) {
+            self.stage += 1;
+            self.stage_started = ends;
+        }
+        self.stage != stage
+    }
+
+    /// Jump to a stage by hand, its duration counts from `now`
+    pub fn set_stage(&mut self, name: &str, now: i64) -> Result<(), String> {
+        self.stage = self
+            .stages
+            .iter()
+            .position(|s| s.name == name)
+            .ok_or_else(|| format!("No stage {name:?}"))?;
+        self.stage_started = now;
+        Ok(())
+    }
+}
+
+/// Named set of nutrients and their strength, so a dose only needs to give
+/// the recipe's name and the amount of water
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct Recipe {
+    pub(crate) name: String,
+    pub(crate) nutrients: Vec<NutrientInfo>,
+}
+
+impl Recipe {
+    fn validate(&self, labels: &[MotorLabel]) -> Result<(), ApiError> {
+        if self.name.is_empty() {
+            return Err(ApiError::invalid("Recipe name can't be empty"));
+        }
+        if self.nutrients.is_empty() {
+            return Err(ApiError::invalid("Recipe has no nutrients"));
+        }
+        let mut violations = Violations::default();
+        for (i, n) in self.nutrients.iter().enumerate() {
+            violations.check(i, n.motor.resolve_nutrient(&n.name, labels));
+            violations.check(i, check_strength(n));
+        }
+        violations.into_result()
+    }
+}
+
+pub(crate) fn check_strength(n: &NutrientInfo) -> Result<(), ApiError> {
+    match n.ml_per_gal.is_finite() && n.ml_per_gal >= 0.0 {
+        true => Ok(()),
+        false => Err(ApiError::invalid_volume(format!("{} has an invalid strength of {} mL/gal", n.name, n.ml_per_gal))),
+    }
+}
+
+pub(crate) async fn list_recipes<S: Stepper>(State(state): State<AppState<S>>) -> Json<Vec<Recipe>> {
+    Json(state.recipes.read().await.clone())
+}
+
+pub(crate) async fn get_recipe<S: Stepper>(
+    State(state): State<AppState<S>>,
+    Path(name): Path<String>,
+) -> Result<Json<Recipe>, ApiError> {
+    match state.recipes.read().await.iter().find(|r| r.name == name) {
+        Some(recipe) => Ok(Json(recipe.clone())),
+        None => Err(ApiError::not_found(format!("No recipe named {name:?}"))),
+    }
+}
+
+/// Adds a recipe, or replaces the one with the same name
+pub(crate) async fn save_recipe<S: Stepper>(
+    State(state): State<AppState<S>>,
+    Json(req): Json<Recipe>,
+) -> Result<Json<Recipe>, ApiError> {
+    let mut req = req;
+    {
+        let labels = state.labels.read().await;
+        req.validate(&labels)?;
+        pin_motors(req.nutrients.iter_mut().map(|n| &mut n.motor), &labels)?;
+    }
+
+    let mut recipes = state.recipes.write().await;
+    let mut updated = recipes.clone();
+    info!("Saving recipe {}", req.name);
+    match updated.iter_mut().find(|r| r.name == req.name) {
+        Some(recipe) => *recipe = req.clone(),
+        None => updated.push(req.clone()),
+    }
+    state.save_recipes(&updated).await?;
+    *recipes = updated;
+    Ok(Json(req))
+}
+
+pub(crate) async fn delete_recipe<S: Stepper>(
+    State(state): State<AppState<S>>,
+    Path(name): Path<String>,
+) -> Result<Json<Recipe>, ApiError> {
+    let used_by = state.schedules.lock().await.iter().find_map(|s| match &s.action {
+        ScheduledAction::Dose(dose) if dose.recipe.as_ref() == Some(&name) => Some(s.name.clone()),
+        _ => None,
+    });
+    if let Some(schedule) = used_by {
+        return Err(ApiError::busy(format!("Recipe is used by schedule {schedule:?}")));
+    }
+
+    let mut recipes = state.recipes.write().await;
+    let Some(pos) = recipes.iter().position(|r| r.name == name) else {
+        return Err(ApiError::not_found(format!("No recipe named {name:?}")));
+    };
+    info!("Removing recipe {name}");
+    let mut updated = recipes.clone();
+    let removed = updated.remove(pos);
+    state.save_recipes(&updated).await?;
+    *recipes = updated;
+    Ok(Json(removed))
+}
+
+#[derive(Serialize)]
+pub(crate) struct GrowStatus {
+    #[serde(flatten)]
+    grow: Grow,
+    stage_name: String,
+    stage_ends: Option<i64>, // Unix time, None if the stage only changes by hand
+    next_stage: Option<String>,
+}
+
+impl GrowStatus {
+    fn new(grow: Grow) -> Self {
+        Self {
+            stage_name: grow.current().name.clone(),
+            stage_ends: grow.stage_ends(),
+            next_stage: grow.stages.get(grow.stage + 1).map(|s| s.name.clone()),
+            grow,
+        }
+    }
+}
+
+fn no_grow() -> ApiError {
+    ApiError::not_found("No feed chart loaded")
+}
+
+pub(crate) async fn get_grow<S: Stepper>(State(state): State<AppState<S>>) -> Result<Json<GrowStatus>, ApiError> {
+    if let Some(now) = clock::now() {
+        state.advance_grow(now).await;
+    }
+    let grow = state.grow.read().await.clone().ok_or_else(no_grow)?;
+    Ok(Json(GrowStatus::new(grow)))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct LoadGrowReq {
+    chart: Chart,
+    schedule: String,                 // e.g. "medium"
+    #[serde(default)]
+    motors: BTreeMap<String, MotorSel>, // Chart nutrient name -> motor, labeled motors are found by name
+    #[serde(default)]
+    stage_days: BTreeMap<String, u32>, // How long each stage lasts, stages left out only change by hand
+    start: Option<i64>,               // Unix time the grow started, defaults to now
+    stage: Option<String>,            // Stage to start in, defaults to the first one
+}
+
+#[derive(Serialize)]
+pub(crate) struct GrowLoaded {
+    skipped: Vec<String>, // Chart nutrients with no motor, they won't be dosed
+}
+
+/// Replaces the current grow with a schedule from an uploaded feed chart
+pub(crate) async fn load_grow<S: Stepper>(
+    State(state): State<AppState<S>>,
+    Json(req): Json<LoadGrowReq>,
+) -> Result<Json<GrowLoaded>, ApiError> {
+    // Grows last for months, so motors are kept by id in case indexes shift
+    let mut motors = BTreeMap::new();
+    {
+        let labels = state.labels.read().await;
+        for (name, sel) in &req.motors {
+            let idx = sel.resolve_nutrient(name, &labels)?;
+            motors.insert(name.clone(), MotorSel::id(labels[idx].id));
+        }
+        for label in labels.iter() {
+            match &label.nutrient {
+                Some(name)
+                    if !motors.contains_key(name)
+                        && req.chart.nutrients.iter().any(|n| &n.name == name && n.unit == NutrientUnit::Ml) =>
+                {
+                    motors.insert(name.clone(), MotorSel::id(label.id));
+                }
+                _ => {}
+            }
+        }
+    }
+    let now = clock::now();
+    let Some(start) = req.start.or(now) else {
+        return Err(ApiError::new(ErrorCode::Unavailable, "Clock isn't set yet, give a start time"));
+    };
+
+    let (mut grow, skipped) = Grow::from_chart(&req.chart, &req.schedule, &motors, &req.stage_days, start)
+        .map_err(ApiError::invalid)?;
+    if let Some(stage) = &req.stage {
+        grow.set_stage(stage, start).map_err(ApiError::invalid)?;
+    }
+    if let Some(now) = now {
+        grow.advance(now);
+    }
+    if !skipped.is_empty() {
+        warn!("No motor for {}, they won't be dosed", skipped.join(", "));
+    }
+
+    info!("Loaded grow {} ({}), in stage {}", grow.chart, grow.schedule, grow.current().name);
+    state.save_grow(Some(&grow)).await?;
+    *state.grow.write().await = Some(grow);
+    Ok(Json(GrowLoaded { skipped }))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct SetStageReq {
+    stage: String,
+}
+
+pub(crate) async fn set_grow_stage<S: Stepper>(
+    State(state): State<AppState<S>>,
+    Json(req): Json<SetStageReq>,
+) -> Result<Json<GrowStatus>, ApiError> {
+    let Some(now) = clock::now() else {
+        return Err(ApiError::no_clock());
+    };
+    let mut grow = state.grow.write().await;
+    let Some(mut updated) = grow.clone() else {
+        return Err(no_grow());
+    };
+    updated.set_stage(&req.stage, now).map_err(ApiError::invalid)?;
+    info!("Grow set to stage {}", req.stage);
+    state.save_grow(Some(&updated)).await?;
+    *grow = Some(updated.clone());
+    Ok(Json(GrowStatus::new(updated)))
+}
+
+pub(crate) async fn delete_grow<S: Stepper>(State(state): State<AppState<S>>) -> Result<Json<Grow>, ApiError> {
+    let mut grow = state.grow.write().await;
+    if grow.is_none() {
+        return Err(no_grow());
+    }
+    info!("Removing grow");
+    state.save_grow(None).await?;
+    grow.take().map(Json).ok_or_else(no_grow)
+}
+
+impl<S: Stepper> AppState<S> {
+    pub(crate) async fn load_recipes(&self) {
+        if let Some(recipes) = self.load(NVS_TAG_RECIPES).await {
+            *self.recipes.write().await = recipes;
+        }
+    }
+
+    pub(crate) async fn save_recipes(&self, recipes: &[Recipe]) -> Result<(), ApiError> {
+        self.store(NVS_TAG_RECIPES, recipes).await
+    }
+
+    pub(crate) async fn load_grow(&self) {
+        let Some(grow) = self.load::<Grow>(NVS_TAG_GROW).await else {
+            return;
+        };
+        match grow.validate() {
+            Ok(()) => *self.grow.write().await = Some(grow),
+            Err(e) => error!("Ignoring stored grow: {e}"),
+        }
+    }
+
+    pub(crate) async fn save_grow(&self, grow: Option<&Grow>) -> Result<(), ApiError> {
+        match grow {
+            Some(grow) => self.store(NVS_TAG_GROW, grow).await,
+            None => match self.nvs.write().await.remove(NVS_TAG_GROW) {
+                Ok(_) => Ok(()),
+                Err(e) => Err(storage_error(NVS_TAG_GROW, e)),
+            },
+        }
+    }
+
+    /// Move the grow on to the stage it should be in by now
+    pub(crate) async fn advance_grow(&self, now: i64) {
+        let mut grow = self.grow.write().await;
+        let Some(g) = grow.as_mut() else { return };
+        if g.advance(now) {
+            info!("Grow advanced to stage {}", g.current().name);
+            self.save_grow(Some(g)).await.ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHART: &str = r##"{
+        "name": "FloraSeries",
+        "nutrients": [
+            { "name": "FloraMicro", "unit": "mL", "color": "#7a3e8c" },
+            { "name": "FloraGro", "unit": "mL" },
+            { "name": "FloraBloom", "unit": "mL" },
+            { "name": "Koolbloom", "unit": "g" }
+        ],
+        "charts": {
+            "medium": {
+                "Seedling": { "FloraMicro": 2, "FloraGro": 2, "FloraBloom": 2 },
+                "Early Growth": { "FloraMicro": 4.2, "FloraGro": 3.8, "FloraBloom": 3 },
+                "Ripen": { "FloraMicro": 3, "FloraBloom": 6, "Koolbloom": 1.5 }
+            },
+            "light": {}
+        }
+    }"##;
+
+    fn chart() -> Chart {
+        serde_json::from_str(CHART).unwrap()
+    }
+
+    fn motors(names: &[&str]) -> BTreeMap<String, MotorSel> {
+        names.iter().enumerate().map(|(i, n)| (n.to_string(), MotorSel::id(i as u32 + 4))).collect()
+    }
+
+    fn days(stages: &[(&str, u32)]) -> BTreeMap<String, u32> {
+        stages.iter().map(|(n, d)| (n.to_string(), *d)).collect()
+    }
+
+    fn grow(stage_days: &[(&str, u32)]) -> Grow {
+        let motors = motors(&["FloraMicro", "FloraGro", "FloraBloom"]);
+        Grow::from_chart(&chart(), "medium", &motors, &days(stage_days), 1000).unwrap().0
+    }
+
+    #[test]
+    fn parses_charts_in_order() {
+        let chart = chart();
+        let medium = chart.charts.get("medium").unwrap();
+        let stages: Vec<_> = medium.0.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(stages, ["Seedling", "Early Growth", "Ripen"]);
+        assert_eq!(medium.get("Early Growth").unwrap().get("FloraGro"), Some(&3.8));
+        assert_eq!(chart.nutrients[1].color, "");
+        assert_eq!(chart.nutrients[3].unit, NutrientUnit::G);
+
+        // Order survives a round trip through storage
+        let json = serde_json::to_string(&chart.charts).unwrap();
+        assert!(json.find("Seedling").unwrap() < json.find("Early Growth").unwrap());
+        assert!(json.find("Early Growth").unwrap() < json.find("Ripen").unwrap());
+    }
+
+    #[test]
+    fn picks_a_schedule() {
+        let motors = motors(&["FloraMicro", "FloraBloom"]);
+        let (grow, skipped) = Grow::from_chart(&chart(), "medium", &motors, &days(&[("Seedling", 14)]), 1000).unwrap();
+        assert_eq!(skipped, ["FloraGro", "Koolbloom"]);
+        assert_eq!(grow.stages.len(), 3);
+        assert_eq!(grow.current().name, "Seedling");
+        assert_eq!(grow.stages[0].days, Some(14));
+        assert_eq!(grow.stages[1].days, None);
+        let ripen: Vec<_> = grow.stages[2].nutrients.iter().map(|n| (n.name.as_str(), n.ml_per_gal)).collect();
+        assert_eq!(ripen, [("FloraMicro", 3.0), ("FloraBloom", 6.0)]);
+        assert!(grow.validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_bad_schedules() {
+        let chart = chart();
+        let none = BTreeMap::new();
+        let ok = motors(&["FloraMicro"]);
+        assert!(Grow::from_chart(&chart, "heavy", &ok, &none, 0).is_err());
+        assert!(Grow::from_chart(&chart, "light", &ok, &none, 0).is_err());
+        assert!(Grow::from_chart(&chart, "medium", &motors(&["Koolbloom"]), &none, 0).is_err());
+        assert!(Grow::from_chart(&chart, "medium", &motors(&["CaliMagic"]), &none, 0).is_err());
+        assert!(Grow::from_chart(&chart, "medium", &ok, &days(&[("Flush", 7)]), 0).is_err());
+    }
+
+    #[test]
+    fn advances_through_stages() {
+        let mut g = grow(&[("Seedling", 14), ("Early Growth", 21)]);
+        assert_eq!(g.stage_ends(), Some(1000 + 14 * DAY));
+        assert!(!g.advance(1000 + 14 * DAY - 1));
+        assert_eq!(g.stage, 0);
+        assert!(g.advance(1000 + 14 * DAY));
+        assert_eq!(g.current().name, "Early Growth");
+        assert_eq!(g.stage_started, 1000 + 14 * DAY);
+
+        // Off for longer than a stage, catches up to where it should be
+        let mut g = grow(&[("Seedling", 14), ("Early Growth", 21)]);
+        assert!(g.advance(1000 + 100 * DAY));
+        assert_eq!(g.current().name, "Ripen");
+        assert_eq!(g.stage_started, 1000 + 35 * DAY);
+        // The last stage never ends
+        assert_eq!(g.stage_ends(), None);
+        assert!(!g.advance(i64::MAX));
+    }
+
+    #[test]
+    fn stages_without_days_stay() {
+        let mut g = grow(&[("Seedling", 14)]);
+        assert!(g.advance(1000 + 20 * DAY));
+        assert_eq!(g.current().name, "Early Growth");
+        assert_eq!(g.stage_ends(), None);
+        assert!(!g.advance(1000 + 1000 * DAY));
+
+        g.set_stage("Seedling", 5000).unwrap();
+        assert_eq!((g.stage, g.stage_started), (0, 5000));
+        assert_eq!(g.stage_ends(), Some(5000 + 14 * DAY));
+        assert!(g.set_stage("Flush", 6000).is_err());
+        assert_eq!((g.stage, g.stage_started), (0, 5000));
+    }
+
+    #[test]
+    fn validates_stored_grows() {
+        let mut g = grow(&[]);
+        g.stage = 2;
+        assert!(g.validate().is_ok());
+        g.stage = 3;
+        assert!(g.validate().is_err());
+        g.stages.clear();
+        g.stage = 0;
+        assert!(g.validate().is_err());
+    }
+}
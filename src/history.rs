@@ -0,0 +1,356 @@
+use serde::{Deserialize, Serialize};
+
+use crate::jobs::{JobKind, JobState};
+
+/// Number of NVS keys the history rotates through, the oldest one gets
+/// overwritten once they're all full
+pub const SLOTS: usize = 6;
+
+// NVS strings top out at 4000 bytes
+const MAX_SLOT_LEN: usize = 3500;
+
+// Free text in a record gets cut down to this many characters if the record
+// wouldn't fit in a slot on its own
+const MAX_TEXT_LEN: usize = 64;
+
+/// Upper bound on a single page of `query`
+pub const MAX_PAGE: usize = 100;
+
+pub fn slot_key(slot: usize) -> String {
+    format!("history{slot}")
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default, Debug)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Trigger {
+    #[default]
+    Api,
+    Schedule,
+}
+
+/// What started a job, kept with its history record
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct Origin {
+    pub trigger: Trigger,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub recipe: Option<String>, // Recipe name, or the feed chart stage a dose came from
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DosedAmount {
+    pub name: String,
+    pub motor_id: u32,
+    pub ml_requested: f64,
+    pub ml_dispensed: f64,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DoseRecord {
+    #[serde(default)]
+    pub seq: u32,
+    pub time: Option<i64>, // Unix time the job finished, None if the clock wasn't set
+    pub kind: JobKind,
+    #[serde(flatten)]
+    pub origin: Origin,
+    pub nutrients: Vec<DosedAmount>,
+    pub result: JobState,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Append-only log of finished doses, split over `SLOTS` NVS keys so only
+/// the newest one is rewritten on each dose
+#[derive(Default)]
+pub struct History {
+    slots: Vec<Vec<DoseRecord>>,
+    current: usize,
+    next_seq: u32,
+}
+
+impl DoseRecord {
+    /// Cuts the free text down until the record fits in a slot by itself.
+    /// There's one nutrient per motor at most, so short names always fit.
+    fn shorten(&mut self) {
+        let fits = |r: &DoseRecord| serde_json::to_string(&[r]).map_or(0, |s| s.len()) <= MAX_SLOT_LEN;
+        if fits(self) {
+            return;
+        }
+        let texts = [&mut self.origin.schedule, &mut self.origin.recipe, &mut self.error];
+        for text in texts.into_iter().flatten() {
+            truncate(text);
+        }
+        for n in &mut self.nutrients {
+            truncate(&mut n.name);
+        }
+    }
+}
+
+fn truncate(text: &mut String) {
+    if let Some((end, _)) = text.char_indices().nth(MAX_TEXT_LEN) {
+        text.truncate(end);
+        text.push('…');
+    }
+}
+
+impl History {
+    /// Rebuild from whatever was in each slot
+    pub fn restore(mut slots: Vec<Vec<DoseRecord>>) -> Self {
+        slots.resize_with(SLOTS, Vec::new);
+        let newest = |slot: &Vec<DoseRecord>| slot.last().map(|r| r.seq);
+        // The first slot on a tie, when nothing was stored yet
+        let current = (0..SLOTS).rev().max_by_key(|&i| newest(&slots[i])).unwrap_or(0);
+        let next_seq = newest(&slots[current]).map_or(0, |seq| seq + 1);
+        Self {
+            slots,
+            current,
+            next_seq,
+        }
+    }
+
+    /// Adds a record, returns the slot that changed and its new contents
+    pub fn push(&mut self, mut record: DoseRecord) -> (usize, String) {
+        if self.slots.is_empty() {
+            self.slots.resize_with(SLOTS, Vec::new);
+        }
+        record.seq = self.next_seq;
+        record.shorten();
+        self.next_seq += 1;
+
+        let slot = &mut self.slots[self.current];
+        slot.push(record);
+        let mut contents = serde_json::to_string(slot).unwrap_or_default();
+        if contents.len() > MAX_SLOT_LEN && slot.len() > 1 {
+            // Start over in the next slot, dropping the oldest records
+            let record = slot.split_off(slot.len() - 1);
+            self.current = (self.current + 1) % SLOTS;
+            self.slots[self.current] = record;
+            contents = serde_json::to_string(&self.slots[self.current]).unwrap_or_default();
+        }
+        (self.current, contents)
+    }
+
+    /// The record pushed last
+    pub fn last(&self) -> Option<&DoseRecord> {
+        self.slots.get(self.current)?.last()
+    }
+
+    /// Records at or after `since` (Unix time) with a sequence number past
+    /// `after`, oldest first. Also returns the cursor for the next page, if
+    /// there are more.
+    pub fn query(&self, since: Option<i64>, after: Option<u32>, limit: usize) -> (Vec<DoseRecord>, Option<u32>) {
+        let mut records: Vec<_> = self
+            .slots
+            .iter()
+            .flatten()
+            .filter(|r| since.is_none_or(|since| r.time.is_some_and(|t| t >= since)))
+            .filter(|r| after.is_none_or(|after| r.seq > after))
+            .collect();
+        records.sort_by_key(|r| r.seq);
+        let more = records.len() > limit;
+        records.truncate(limit);
+        let next = more.then(|| records.last().map(|r| r.seq)).flatten();
+        (records.into_iter().cloned().collect(), next)
+    }
+}
+
+// Same name the JSON API uses
+fn variant_name(v: &impl Serialize) -> String {
+    match serde_json::to_value(v) {
+        Ok(serde_json::Value::String(name)) => name,
+        _ => String::new(),
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    match field.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", field.replace('"', "\"\"")),
+        false => field.to_owned(),
+    }
+}
+
+/// One row per nutrient, `local_time` formats the record's timestamp
+pub fn to_csv(records: &[DoseRecord], local_time: impl Fn(i64) -> String) -> String {
+    let mut csv = "seq,time,local_time,kind,trigger,schedule,recipe,nutrient,motor_id,ml_requested,ml_dispensed,result,error\n".to_owned();
+    for r in records {
+        for n in &r.nutrients {
+            let row = [
+                r.seq.to_string(),
+                r.time.map(|t| t.to_string()).unwrap_or_default(),
+                r.time.map(&local_time).unwrap_or_default(),
+                variant_name(&r.kind),
+                variant_name(&r.origin.trigger),
+                r.origin.schedule.clone().unwrap_or_default(),
+                r.origin.recipe.clone().unwrap_or_default(),
+                n.name.clone(),
+                n.motor_id.to_string(),
+                n.ml_requested.to_string(),
+                n.ml_dispensed.to_string(),
+                variant_name(&r.result),
+                r.error.clone().unwrap_or_default(),
+            ];
+            let row: Vec<_> = row.iter().map(|f| csv_field(f)).collect();
+            csv.push_str(&row.join(","));
+            csv.push('\n');
+        }
+    }
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(time: i64, nutrients: &[&str]) -> DoseRecord {
+        DoseRecord {
+            seq: 0,
+            time: Some(time),
+            kind: JobKind::Dispense,
+            origin: Origin::default(),
+            nutrients: nutrients
+                .iter()
+                .enumerate()
+                .map(|(i, name)| DosedAmount {
+                    name: name.to_string(),
+                    motor_id: i as u32,
+                    ml_requested: 2.5,
+                    ml_dispensed: 2.5,
+                })
+                .collect(),
+            result: JobState::Done,
+            error: None,
+        }
+    }
+
+    /// History as it would be read back from NVS, keeping the last write of each slot
+    fn pushed(count: u32) -> (History, Vec<Vec<DoseRecord>>) {
+        let mut history = History::default();
+        let mut stored = vec![Vec::new(); SLOTS];
+        for i in 0..count {
+            let (slot, contents) = history.push(record(1000 + i as i64, &["FloraGro", "FloraMicro"]));
+            assert!(contents.len() <= MAX_SLOT_LEN);
+            stored[slot] = serde_json::from_str(&contents).unwrap();
+        }
+        (history, stored)
+    }
+
+    fn seqs(records: &[DoseRecord]) -> Vec<u32> {
+        records.iter().map(|r| r.seq).collect()
+    }
+
+    #[test]
+    fn push_rolls_over_to_the_next_slot() {
+        let (history, stored) = pushed(40);
+        assert!(history.current > 0);
+        assert!(stored[1..=history.current].iter().all(|slot| !slot.is_empty()));
+        // Every record ends up in exactly one slot, in order
+        let all: Vec<u32> = stored.iter().flatten().map(|r| r.seq).collect();
+        assert_eq!(all, (0..40).collect::<Vec<_>>());
+        assert_eq!(history.last().unwrap().seq, 39);
+    }
+
+    #[test]
+    fn push_overwrites_the_oldest_slot() {
+        let (history, stored) = pushed(400);
+        let (records, _) = history.query(None, None, usize::MAX);
+        let oldest = records[0].seq;
+        assert!(oldest > 0, "the first records were dropped");
+        assert_eq!(seqs(&records), (oldest..400).collect::<Vec<_>>());
+        assert_eq!(stored.iter().map(Vec::len).sum::<usize>(), records.len());
+    }
+
+    #[test]
+    fn restore_finds_the_newest_slot() {
+        // Wrapped around, so the newest slot isn't the last one
+        let (history, stored) = pushed(400);
+        let mut restored = History::restore(stored);
+        assert_eq!(restored.current, history.current);
+        assert_eq!(restored.next_seq, 400);
+
+        let (slot, _) = restored.push(record(5000, &["CalMag"]));
+        assert_eq!(slot, history.current);
+        assert_eq!(restored.last().unwrap().seq, 400);
+    }
+
+    #[test]
+    fn restore_empty() {
+        let mut history = History::restore(Vec::new());
+        assert!(history.last().is_none());
+        assert_eq!(history.push(record(0, &["CalMag"])).0, 0);
+        assert_eq!(history.last().unwrap().seq, 0);
+    }
+
+    #[test]
+    fn oversized_record_is_shortened() {
+        let mut history = History::default();
+        history.push(record(0, &["CalMag"]));
+        let long = "x".repeat(5000);
+        let mut big = record(1, &[&long, &long, &long, &long, &long]);
+        big.error = Some(long.clone());
+        big.origin.recipe = Some(long.clone());
+
+        let (slot, contents) = history.push(big);
+        assert!(contents.len() <= MAX_SLOT_LEN);
+        let stored: Vec<DoseRecord> = serde_json::from_str(&contents).unwrap();
+        assert_eq!(stored.last().unwrap().seq, 1);
+        assert_eq!(stored.last().unwrap().nutrients.len(), 5);
+        assert!(stored.last().unwrap().error.as_ref().unwrap().starts_with("xxxx"));
+        assert_eq!(slot, 0, "fits next to the first record once shortened");
+
+        // Short records are left alone
+        let mut error = record(2, &["CalMag"]);
+        error.error = Some("y".repeat(100));
+        history.push(error);
+        assert_eq!(history.last().unwrap().error.as_ref().unwrap().len(), 100);
+    }
+
+    #[test]
+    fn query_pages_through_records() {
+        let (history, _) = pushed(25);
+        let (page, next) = history.query(None, None, 10);
+        assert_eq!(seqs(&page), (0..10).collect::<Vec<_>>());
+        assert_eq!(next, Some(9));
+        let (page, next) = history.query(None, next, 10);
+        assert_eq!(seqs(&page), (10..20).collect::<Vec<_>>());
+        let (page, next) = history.query(None, next, 10);
+        assert_eq!(seqs(&page), (20..25).collect::<Vec<_>>());
+        assert_eq!(next, None);
+
+        // Exactly a full page has no next one
+        assert_eq!(history.query(None, Some(14), 10).1, None);
+    }
+
+    #[test]
+    fn query_since() {
+        let (mut history, _) = pushed(10);
+        let mut unset = record(0, &["CalMag"]);
+        unset.time = None;
+        history.push(unset);
+
+        let (records, _) = history.query(Some(1005), None, usize::MAX);
+        assert_eq!(seqs(&records), [5, 6, 7, 8, 9]);
+        let (records, next) = history.query(Some(1005), Some(6), 2);
+        assert_eq!(seqs(&records), [7, 8]);
+        assert_eq!(next, Some(8));
+        assert_eq!(history.query(None, None, usize::MAX).0.len(), 11);
+    }
+
+    #[test]
+    fn csv_quotes_fields() {
+        let mut r = record(1000, &["Flora, \"Bloom\"", "Cal\nMag"]);
+        r.origin.schedule = Some("Morning".to_owned());
+        r.error = Some("a\rb".to_owned());
+        let csv = to_csv(&[r], |t| format!("t{t}"));
+        let mut lines = csv.split_terminator('\n');
+        assert!(lines.next().unwrap().starts_with("seq,time,local_time,"));
+        assert_eq!(
+            lines.next().unwrap(),
+            "0,1000,t1000,DISPENSE,API,Morning,,\"Flora, \"\"Bloom\"\"\",0,2.5,2.5,DONE,\"a\rb\""
+        );
+        assert_eq!(lines.next().unwrap(), "0,1000,t1000,DISPENSE,API,Morning,,\"Cal");
+        assert_eq!(lines.next().unwrap(), "Mag\",1,2.5,2.5,DONE,\"a\rb\"");
+        assert!(lines.next().is_none());
+        assert_eq!(to_csv(&[], |t| t.to_string()).lines().count(), 1);
+    }
+}
@@ -0,0 +1,69 @@
+use esp_idf_svc::{
+    hal::{
+        delay::Ets,
+        gpio::{AnyInputPin, AnyOutputPin, Input, Output, PinDriver},
+        interrupt,
+    },
+    sys::EspError,
+};
+
+use crate::scale::LoadCell;
+
+// Extra clock pulses after the 24 data bits pick the next conversion's
+// input, 1 is channel A at a gain of 128
+const GAIN_PULSES: u32 = 1;
+
+// Both halves of a clock pulse need at least 0.2us, PD_SCK staying high for
+// more than 60us powers the chip down
+const HALF_CLOCK_US: u32 = 1;
+
+pub struct HX711 {
+    pin_dout: PinDriver<'static, AnyInputPin, Input>,
+    pin_sck: PinDriver<'static, AnyOutputPin, Output>,
+}
+
+impl HX711 {
+    pub fn new(pin_dout: AnyInputPin, pin_sck: AnyOutputPin) -> Result<Self, EspError> {
+        let dout = PinDriver::input(pin_dout)?;
+        let mut sck = PinDriver::output(pin_sck)?;
+        sck.set_low()?; // Wakes the chip up if it was powered down
+
+        Ok(Self {
+            pin_dout: dout,
+            pin_sck: sck,
+        })
+    }
+
+    fn pulse(&mut self) -> Result<bool, EspError> {
+        self.pin_sck.set_high()?;
+        Ets::delay_us(HALF_CLOCK_US);
+        let bit = self.pin_dout.is_high();
+        self.pin_sck.set_low()?;
+        Ets::delay_us(HALF_CLOCK_US);
+        Ok(bit)
+    }
+}
+
+impl LoadCell for HX711 {
+    fn read_raw(&mut self) -> anyhow::Result<Option<i32>> {
+        // DOUT goes low once a conversion is ready
+        if self.pin_dout.is_high() {
+            return Ok(None);
+        }
+
+        // An interrupt in the middle of a high clock could power the chip down
+        let raw = interrupt::free(|| -> Result<u32, EspError> {
+            let mut raw = 0;
+            for _ in 0..24 {
+                raw = (raw << 1) | self.pulse()? as u32;
+            }
+            for _ in 0..GAIN_PULSES {
+                self.pulse()?;
+            }
+            Ok(raw)
+        })?;
+
+        // 24 bit two's complement
+        Ok(Some(((raw << 8) as i32) >> 8))
+    }
+}
@@ -0,0 +1,177 @@
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{history::Origin, stepper::MotionCtl};
+
+// Finished jobs are only kept around so clients can poll their final result
+const MAX_FINISHED_JOBS: usize = 16;
+
+pub type JobId = u32;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum JobKind {
+    Dispense,
+    Dose,
+    Unprime,
+    Prime,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum JobState {
+    Queued,
+    Running,
+    Done,
+    Cancelled,
+    Failed, // A motor faulted, `error` says which
+}
+
+impl JobState {
+    pub fn is_finished(&self) -> bool {
+        matches!(self, JobState::Done | JobState::Cancelled | JobState::Failed)
+    }
+}
+
+/// A single motor's share of a job
+#[derive(Debug)]
+pub struct JobEntry {
+    pub name: String,
+    pub motor_idx: usize,
+    pub ml_requested: f64,
+    pub steps_total: f64,
+    pub ml_per_step: f64,
+    pub progress: MotionCtl,
+    pub ml_dispensed: Option<f64>, // set once the motor is done
+}
+
+impl JobEntry {
+    /// Dispense a volume, the steps are filled in once the motor's calibration is known
+    pub fn dispense(name: String, motor_idx: usize, ml: f64) -> Self {
+        Self {
+            name,
+            motor_idx,
+            ml_requested: ml,
+            steps_total: 0.0,
+            ml_per_step: 0.0,
+            progress: MotionCtl::default(),
+            ml_dispensed: None,
+        }
+    }
+
+    /// Move a number of steps that don't count as dispensed volume, e.g. priming
+    pub fn steps(name: String, motor_idx: usize, steps: f64) -> Self {
+        Self {
+            steps_total: steps,
+            ..Self::dispense(name, motor_idx, 0.0)
+        }
+    }
+}
+
+pub struct Job {
+    pub id: JobId,
+    pub kind: JobKind,
+    pub state: JobState,
+    pub entries: Vec<JobEntry>,
+    pub ctl: MotionCtl,
+    pub origin: Origin,
+    pub error: Option<String>, // Why some of it couldn't be done
+}
+
+#[derive(Serialize)]
+pub struct JobEntryStatus {
+    name: String,
+    motor_idx: usize,
+    steps_done: f64,
+    steps_total: f64,
+    ml_requested: f64,
+    ml_dispensed: f64,
+}
+
+#[derive(Serialize)]
+pub struct JobStatus {
+    id: JobId,
+    kind: JobKind,
+    state: JobState,
+    nutrients: Vec<JobEntryStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Job {
+    pub fn status(&self) -> JobStatus {
+        JobStatus {
+            id: self.id,
+            kind: self.kind,
+            state: self.state,
+            nutrients: self
+                .entries
+                .iter()
+                .map(|e| {
+                    let steps_done = e.progress.steps_done();
+                    JobEntryStatus {
+                        name: e.name.clone(),
+                        motor_idx: e.motor_idx,
+                        steps_done,
+                        steps_total: e.steps_total,
+                        ml_requested: e.ml_requested,
+                        ml_dispensed: e.ml_dispensed.unwrap_or(steps_done * e.ml_per_step),
+                    }
+                })
+                .collect(),
+            error: self.error.clone(),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Jobs {
+    next_id: JobId,
+    jobs: VecDeque<Job>,
+}
+
+impl Jobs {
+    /// Register a new queued job, its entries are filled in by the caller.
+    /// `ctl` should be forked from the app's root so an emergency stop reaches it.
+    pub fn create(&mut self, kind: JobKind, ctl: MotionCtl) -> &mut Job {
+        // Drop the oldest finished jobs to bound memory use
+        while self.jobs.iter().filter(|j| j.state.is_finished()).count() >= MAX_FINISHED_JOBS {
+            match self.jobs.iter().position(|j| j.state.is_finished()) {
+                Some(pos) => self.jobs.remove(pos),
+                None => break,
+            };
+        }
+
+        self.next_id = self.next_id.wrapping_add(1);
+        self.jobs.push_back(Job {
+            id: self.next_id,
+            kind,
+            state: JobState::Queued,
+            entries: Vec::new(),
+            ctl,
+            origin: Origin::default(),
+            error: None,
+        });
+        self.jobs.back_mut().unwrap()
+    }
+
+    pub fn get(&self, id: JobId) -> Option<&Job> {
+        self.jobs.iter().find(|j| j.id == id)
+    }
+
+    pub fn get_mut(&mut self, id: JobId) -> Option<&mut Job> {
+        self.jobs.iter_mut().find(|j| j.id == id)
+    }
+
+    /// Cancel every job that hasn't finished yet, including queued ones
+    pub fn cancel_all(&self) {
+        for job in self.jobs.iter().filter(|j| !j.state.is_finished()) {
+            job.ctl.cancel();
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Job> {
+        self.jobs.iter()
+    }
+}
@@ -0,0 +1,88 @@
+use serde::{Deserialize, Serialize};
+
+use crate::error::{ApiError, ErrorCode};
+
+/// What a motor is hooked up to. Kept apart from the motors themselves so
+/// requests can be resolved while a job holds the motors.
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct MotorLabel {
+    pub id: u32,
+    #[serde(default)]
+    pub nutrient: Option<String>, // Name of the nutrient in the bottle
+    #[serde(default)]
+    pub color: Option<String>,
+    #[serde(default)]
+    pub bottle_ml: Option<f64>,
+    #[serde(default)]
+    pub level_ml: Option<f64>, // What's left in the bottle, not tracked until it's refilled
+    #[serde(default)]
+    pub notes: String,
+}
+
+/// Which motor a request is for, by index, by id (EN pin #), or by the
+/// nutrient it holds. Indexes can shift when motors are removed, the other
+/// two don't.
+#[derive(Serialize, Deserialize, Clone, Default, Debug)]
+pub struct MotorSel {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub motor_idx: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub motor_id: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub motor: Option<String>,
+}
+
+impl MotorSel {
+    pub fn id(motor_id: u32) -> Self {
+        Self {
+            motor_id: Some(motor_id),
+            ..Default::default()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.motor_idx.is_none() && self.motor_id.is_none() && self.motor.is_none()
+    }
+
+    /// Index of the selected motor in `labels`
+    pub fn resolve(&self, labels: &[MotorLabel]) -> Result<usize, ApiError> {
+        let idx = match (self.motor_idx, self.motor_id, &self.motor) {
+            (Some(idx), None, None) => (idx < labels.len()).then_some(idx),
+            (None, Some(id), None) => labels.iter().position(|l| l.id == id),
+            (None, None, Some(name)) => labels.iter().position(|l| l.nutrient.as_ref() == Some(name)),
+            (None, None, None) => return Err(ApiError::invalid("No motor given")),
+            _ => return Err(ApiError::invalid("Give only one of motor_idx, motor_id or motor")),
+        };
+        idx.ok_or_else(|| ApiError::new(ErrorCode::UnknownMotor, format!("No motor {self}")))
+    }
+
+    /// Like `resolve`, but for a nutrient going into a dose. Finds the motor
+    /// by `nutrient` if none is given, and refuses a motor that's labeled
+    /// with a different nutrient.
+    pub fn resolve_nutrient(&self, nutrient: &str, labels: &[MotorLabel]) -> Result<usize, ApiError> {
+        if self.is_empty() {
+            return labels
+                .iter()
+                .position(|l| l.nutrient.as_deref() == Some(nutrient))
+                .ok_or_else(|| ApiError::new(ErrorCode::UnknownMotor, format!("No motor holds {nutrient}")));
+        }
+        let idx = self.resolve(labels)?;
+        match &labels[idx].nutrient {
+            Some(loaded) if loaded != nutrient => Err(ApiError::invalid(format!(
+                "Motor {self} holds {loaded}, not {nutrient}"
+            ))),
+            _ => Ok(idx),
+        }
+    }
+}
+
+impl std::fmt::Display for MotorSel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.motor_idx, self.motor_id, &self.motor) {
+            (Some(idx), _, _) => write!(f, "#{idx}"),
+            (_, Some(id), _) => write!(f, "with id {id}"),
+            (_, _, Some(name)) => write!(f, "{name:?}"),
+            _ => write!(f, "(none)"),
+        }
+    }
+}
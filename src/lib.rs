@@ -0,0 +1,27 @@
+pub mod app;
+pub mod calibration;
+pub mod clock;
+pub mod discovery;
+pub mod error;
+pub mod feedchart;
+pub mod history;
+#[cfg(target_os = "espidf")]
+pub mod hx711;
+pub mod jobs;
+pub mod labels;
+pub mod mqtt;
+pub mod network;
+#[cfg(target_os = "espidf")]
+mod ota;
+pub mod positions;
+pub mod provision;
+#[cfg(target_os = "espidf")]
+pub mod rmt_drv8825;
+pub mod scale;
+pub mod schedule;
+#[cfg(not(target_os = "espidf"))]
+pub mod sim_stepper;
+pub mod stepper;
+pub mod storage;
+#[cfg(target_os = "espidf")]
+pub mod util;
@@ -1,29 +1,55 @@
-mod app;
-mod rmt_drv8825;
-mod util;
-
-use std::sync::{Arc, Mutex};
+#[cfg(target_os = "espidf")]
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
+#[cfg(target_os = "espidf")]
+use anyhow::bail;
+#[cfg(target_os = "espidf")]
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
     hal::{
         gpio::AnyOutputPin,
         prelude::Peripherals,
+        reset::restart,
         rmt::{PinState, TxRmtConfig, TxRmtDriver},
     },
     io::vfs::MountedEventfs,
     netif::{EspNetif, NetifStack},
-    nvs::EspDefaultNvsPartition,
+    nvs::{EspCustomNvs, EspCustomNvsPartition, EspDefaultNvsPartition},
+    sntp::EspSntp,
     sys::EspError,
     timer::EspTimerService,
-    wifi::{AsyncWifi, ClientConfiguration, Configuration, EspWifi, WifiDriver},
+    wifi::{AccessPointConfiguration, AsyncWifi, AuthMethod, ClientConfiguration, Configuration, EspWifi, WifiDriver},
+};
+#[cfg(target_os = "espidf")]
+use log::{error, info, warn};
+#[cfg(target_os = "espidf")]
+use nutrient_doser::{
+    app::{self, NVS_NS},
+    discovery,
+    network::{self, NetworkConfig},
+    provision::{self, Network, RadioRequest, WifiCreds, AP_SSID_PREFIX, MAX_CONNECT_ATTEMPTS, PROVISION_TIMEOUT},
+    rmt_drv8825::DRV8825,
+    scale::LoadCell,
+    storage::Storage,
+    util,
 };
-use log::{info, warn};
+#[cfg(all(target_os = "espidf", feature = "hx711"))]
+use {esp_idf_svc::hal::gpio::AnyInputPin, nutrient_doser::hx711::HX711};
+#[cfg(target_os = "espidf")]
 use smart_leds::{brightness, colors, gamma};
+#[cfg(target_os = "espidf")]
+use tokio::{
+    sync::mpsc,
+    time::{sleep, timeout},
+};
+#[cfg(target_os = "espidf")]
 use ws2812_esp32_rmt_driver::Ws2812Esp32Rmt;
 
-use crate::rmt_drv8825::{MicroSteps, DRV8825};
-
+// Compile time fallback for when no network has been provisioned
+#[cfg(target_os = "espidf")]
 #[toml_cfg::toml_config]
 pub struct Config {
     #[default("")]
@@ -32,6 +58,13 @@ pub struct Config {
     wifi_pass: &'static str,
 }
 
+#[cfg(not(target_os = "espidf"))]
+fn main() {
+    eprintln!("nutrient-doser only runs on the ESP32-C6, use the doser-sim binary on the host");
+    std::process::exit(1);
+}
+
+#[cfg(target_os = "espidf")]
 fn main() -> anyhow::Result<()> {
     esp_idf_svc::sys::link_patches();
     esp_idf_svc::log::EspLogger::initialize_default();
@@ -47,7 +80,7 @@ fn main() -> anyhow::Result<()> {
     let wifi = AsyncWifi::wrap(
         EspWifi::wrap_all(
             WifiDriver::new(peripherals.modem, sys_loop.clone(), Some(nvs.clone()))?,
-            util::get_netif_with_hostname(nvs.clone())?,
+            EspNetif::new(NetifStack::Sta)?, // Replaced by `WifiLoop::configure`
             EspNetif::new(NetifStack::Ap)?,
         )?,
         sys_loop,
@@ -75,86 +108,168 @@ fn main() -> anyhow::Result<()> {
             AnyOutputPin::from(peripherals.pins.gpio4),
             AnyOutputPin::from(peripherals.pins.gpio5),
             tx.clone(),
-            MicroSteps::M32,
         )?,
         DRV8825::new(
             AnyOutputPin::from(peripherals.pins.gpio6),
             AnyOutputPin::from(peripherals.pins.gpio7),
             tx.clone(),
-            MicroSteps::M32,
         )?,
         DRV8825::new(
             AnyOutputPin::from(peripherals.pins.gpio0),
             AnyOutputPin::from(peripherals.pins.gpio1),
             tx.clone(),
-            MicroSteps::M32,
         )?,
         DRV8825::new(
             AnyOutputPin::from(peripherals.pins.gpio23),
             AnyOutputPin::from(peripherals.pins.gpio22),
             tx.clone(),
-            MicroSteps::M32,
         )?,
         DRV8825::new(
             AnyOutputPin::from(peripherals.pins.gpio21),
             AnyOutputPin::from(peripherals.pins.gpio20),
             tx.clone(),
-            MicroSteps::M32,
         )?,
     ];
 
+    // Load cell for gravimetric calibration, DOUT on GPIO2 and PD_SCK on GPIO3
+    #[cfg(feature = "hx711")]
+    let scale: Option<Box<dyn LoadCell>> = Some(Box::new(HX711::new(
+        AnyInputPin::from(peripherals.pins.gpio2),
+        AnyOutputPin::from(peripherals.pins.gpio3),
+    )?));
+    #[cfg(not(feature = "hx711"))]
+    let scale: Option<Box<dyn LoadCell>> = None;
+
     // status LED
     let user_led = Ws2812Esp32Rmt::new(peripherals.rmt.channel0, peripherals.pins.gpio8)?;
 
+    let mut storage = EspCustomNvs::new(EspCustomNvsPartition::take("nvs")?, NVS_NS, true)?;
+    let fallback = WifiCreds {
+        ssid: CONFIG.wifi_ssid.to_owned(),
+        pass: CONFIG.wifi_pass.to_owned(),
+    };
+    let mut config = network::with_fallback(network::load_config(&storage), fallback.clone());
+
     tokio::runtime::Builder::new_current_thread()
         .thread_stack_size(6 * 1024)
         .enable_all()
         .build()?
         .block_on(async move {
             // Start wifi loop first
-            let mut wifi_loop = WifiLoop { wifi, user_led };
-            wifi_loop.configure().await?;
-            wifi_loop.initial_connect().await?;
+            let mut wifi_loop = WifiLoop {
+                wifi,
+                user_led,
+                networks: Vec::new(),
+            };
+            let mut connected = wifi_loop.connect(&config).await?;
+            if connected {
+                if let Err(e) = network::confirm_config(&mut storage) {
+                    error!("Failed to confirm the network config: {e}");
+                }
+            } else {
+                match network::restore_previous(&mut storage) {
+                    Ok(Some(prev)) => {
+                        let prev = network::with_fallback(prev, fallback);
+                        warn!("Network config didn't connect, going back to the previous one");
+                        connected = wifi_loop.connect(&prev).await?;
+                        config = prev;
+                    }
+                    Ok(None) => {}
+                    Err(e) => error!("Failed to restore the previous network config: {e}"),
+                }
+            }
+            if !connected {
+                // Reboots once there's a network to join
+                return wifi_loop.provision(Box::new(storage), !config.networks.is_empty()).await;
+            }
+
+            // Wall clock for schedules, keeps re-syncing in the background
+            let _sntp = EspSntp::new_default()?;
+
+            // Lets the web app find the doser on the LAN
+            let device_id = discovery::device_id(wifi_loop.wifi.wifi().sta_netif().get_mac()?);
+            let hostname = discovery::mdns_hostname(config.hostname.as_deref(), &device_id);
+            let _mdns = match util::advertise(&hostname, &device_id, drivers.len()) {
+                Ok(mdns) => Some(mdns),
+                Err(e) => {
+                    error!("mDNS advertisement failed: {e}");
+                    None
+                }
+            };
 
             // Launch all other tasks
-            tokio::spawn(app::run(drivers));
+            tokio::spawn(app::run(drivers, scale, Box::new(storage), device_id, app::PORT));
 
             // Keep this task on the wifi loop
-            wifi_loop.stay_connected().await
+            wifi_loop.stay_connected().await?;
+            anyhow::Ok(())
         })?;
 
     Ok(())
 }
 
 // From https://github.com/jasta/esp32-tokio-demo
+#[cfg(target_os = "espidf")]
 pub struct WifiLoop<'a> {
     wifi: AsyncWifi<EspWifi<'a>>,
     user_led: Ws2812Esp32Rmt<'a>,
+    networks: Vec<WifiCreds>, // In order of priority
 }
 
+#[cfg(target_os = "espidf")]
 impl WifiLoop<'_> {
-    pub async fn configure(&mut self) -> Result<(), EspError> {
-        info!("Setting Wi-Fi credentials...");
+    /// Joins one of the networks of `config`, returns whether it connected
+    pub async fn connect(&mut self, config: &NetworkConfig) -> anyhow::Result<bool> {
+        if config.networks.is_empty() {
+            warn!("No Wi-Fi networks stored");
+            return Ok(false);
+        }
+        self.configure(config).await?;
+        Ok(self.initial_connect().await?)
+    }
+
+    pub async fn configure(&mut self, config: &NetworkConfig) -> anyhow::Result<()> {
+        if self.wifi.is_started()? {
+            self.wifi.stop().await?;
+        }
+        self.wifi.wifi_mut().swap_netif_sta(util::sta_netif(config)?)?;
+        self.networks = config.networks.clone();
+        self.select(0)?;
+
+        info!("Starting Wi-Fi driver...");
+        Ok(self.wifi.start().await?)
+    }
+
+    fn select(&mut self, idx: usize) -> Result<(), EspError> {
+        let creds = &self.networks[idx];
+        info!("Setting Wi-Fi credentials for {}...", creds.ssid);
         self.wifi
             .set_configuration(&Configuration::Client(ClientConfiguration {
-                ssid: CONFIG.wifi_ssid.try_into().unwrap(),
-                password: CONFIG.wifi_pass.try_into().unwrap(),
+                ssid: creds.ssid.as_str().try_into().unwrap(),
+                password: creds.pass.as_str().try_into().unwrap(),
+                auth_method: match creds.pass.is_empty() {
+                    true => AuthMethod::None,
+                    false => AuthMethod::WPA2Personal,
+                },
                 ..Default::default()
-            }))?;
-
-        info!("Starting Wi-Fi driver...");
-        self.wifi.start().await
+            }))
     }
 
-    pub async fn initial_connect(&mut self) -> Result<(), EspError> {
-        self.do_connect_loop(true).await
+    /// Returns whether it connected before running out of attempts
+    pub async fn initial_connect(&mut self) -> Result<bool, EspError> {
+        self.do_connect_loop(Some(MAX_CONNECT_ATTEMPTS)).await
     }
 
     pub async fn stay_connected(mut self) -> Result<(), EspError> {
-        self.do_connect_loop(false).await
+        self.do_connect_loop(None).await.map(|_| ())
     }
 
-    async fn do_connect_loop(&mut self, exit_after_first_connect: bool) -> Result<(), EspError> {
+    /// Moves on to the next network after every failed attempt. With
+    /// `max_attempts` it returns after the first connect, or once every
+    /// network has failed that many times in a row.
+    async fn do_connect_loop(&mut self, max_attempts: Option<u32>) -> Result<bool, EspError> {
+        let mut failures = 0;
+        let mut current = 0;
         loop {
             // Wait for disconnect before trying to connect again.  This loop ensures
             // we stay connected and is commonly missing from trivial examples as it's
@@ -168,8 +283,17 @@ impl WifiLoop<'_> {
                 .write_nocopy(brightness(gamma([colors::ORANGE].into_iter()), 64))
                 .unwrap();
             match self.wifi.connect().await {
-                Ok(_) => (),
+                Ok(_) => failures = 0,
                 Err(e) => {
+                    failures += 1;
+                    if max_attempts.is_some_and(|max| failures >= max * self.networks.len() as u32) {
+                        warn!("Error while connecting: {e}. Giving up after {failures} attempts");
+                        return Ok(false);
+                    }
+                    if self.networks.len() > 1 {
+                        current = (current + 1) % self.networks.len();
+                        self.select(current)?;
+                    }
                     warn!("Error while connecting: {e}. Retrying");
                     continue;
                 }
@@ -183,9 +307,71 @@ impl WifiLoop<'_> {
             self.user_led
                 .write_nocopy(brightness(gamma([colors::LIME].into_iter()), 64))
                 .unwrap();
-            if exit_after_first_connect {
-                return Ok(());
+            if max_attempts.is_some() {
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Opens a SoftAP with a page to pick a network, then reboots into
+    /// station mode once its credentials are saved. With `retry` it also
+    /// reboots when nobody has used the page for a while, to try the stored
+    /// network again.
+    pub async fn provision(mut self, storage: Box<dyn Storage>, retry: bool) -> anyhow::Result<()> {
+        let mac = self.wifi.wifi().ap_netif().get_mac()?;
+        let ssid = format!("{AP_SSID_PREFIX}-{:02x}{:02x}", mac[4], mac[5]);
+        info!("Starting provisioning SoftAP {ssid}...");
+        if self.wifi.is_started()? {
+            self.wifi.stop().await?;
+        }
+        // Mixed mode keeps the station side around for scanning
+        self.wifi.set_configuration(&Configuration::Mixed(
+            ClientConfiguration::default(),
+            AccessPointConfiguration {
+                ssid: ssid.as_str().try_into().unwrap(),
+                auth_method: AuthMethod::None,
+                ..Default::default()
+            },
+        ))?;
+        self.wifi.start().await?;
+        self.user_led
+            .write_nocopy(brightness(gamma([colors::BLUE].into_iter()), 64))
+            .unwrap();
+
+        let ip = self.wifi.wifi().ap_netif().get_ip_info()?.ip;
+        let (tx, mut rx) = mpsc::channel(1);
+        tokio::spawn(async move {
+            if let Err(e) = provision::serve(tx, storage, ip).await {
+                error!("Provisioning server failed: {e}");
+            }
+        });
+
+        loop {
+            match timeout(PROVISION_TIMEOUT, rx.recv()).await {
+                Ok(Some(RadioRequest::Scan(reply))) => {
+                    reply.send(self.scan().await).ok();
+                }
+                Ok(Some(RadioRequest::Saved)) => {
+                    info!("Rebooting to join the new network...");
+                    sleep(Duration::from_secs(1)).await; // Lets the response go out
+                    restart();
+                }
+                Ok(None) => bail!("Provisioning server stopped"),
+                Err(_) if retry => {
+                    info!("Nobody provisioned the doser, rebooting to try the stored network again");
+                    restart();
+                }
+                Err(_) => {}
             }
         }
     }
+
+    async fn scan(&mut self) -> anyhow::Result<Vec<Network>> {
+        let seen = self.wifi.scan().await?;
+        Ok(provision::dedup_networks(seen.into_iter().map(|ap| Network {
+            ssid: ap.ssid.to_string(),
+            rssi: ap.signal_strength,
+            secured: ap.auth_method.is_some_and(|auth| auth != AuthMethod::None),
+        })))
+    }
 }
@@ -0,0 +1,777 @@
+// Bare MQTT 3.1.1 client, QoS 0 only, plus the topics and Home Assistant
+// discovery config the doser publishes. `AppState::run_mqtt` below drives it
+// and the `/config/mqtt` endpoints set it up.
+
+use std::{io, time::Duration};
+
+use anyhow::bail;
+use axum::extract::State;
+use log::{error, info, warn};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{tcp::OwnedWriteHalf, TcpStream},
+    sync::{broadcast, mpsc},
+    time::{interval, timeout},
+};
+
+use crate::{
+    app::{
+        dispense, dose_solution, emergency_stop, storage_error, unprime, unprime_all, AppEvent, AppState, AppStatus,
+        JobResponse, PrimeState,
+    },
+    error::{ApiError, Json},
+    stepper::Stepper,
+};
+
+const NVS_TAG_MQTT: &str = "mqtt";
+
+pub const KEEP_ALIVE: Duration = Duration::from_secs(30);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Wait between attempts to reach the broker
+const MQTT_RETRY: Duration = Duration::from_secs(30);
+
+// Commands and discovery configs are small, anything past this is skipped
+const MAX_PACKET: usize = 8 * 1024;
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const SUBSCRIBE: u8 = 0x82; // Reserved flags must be 0b0010
+const PINGREQ: u8 = 0xc0;
+const DISCONNECT: u8 = 0xe0;
+
+/// Commands taken on `<base>/cmd/<command>`
+pub const COMMANDS: [&str; 4] = ["dose", "dispense", "unprime", "stop"];
+
+/// Broker to connect to and where to publish, stored under `mqtt`
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+pub struct MqttConfig {
+    pub enabled: bool,
+    pub host: String,
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub base_topic: Option<String>, // Defaults to nutrient-doser/<device id>
+    pub discovery_prefix: Option<String>, // None turns Home Assistant discovery off
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            host: String::new(),
+            port: 1883,
+            username: None,
+            password: None,
+            base_topic: None,
+            discovery_prefix: Some("homeassistant".to_owned()),
+        }
+    }
+}
+
+impl MqttConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.enabled && self.host.is_empty() {
+            return Err("Give the broker's host".to_owned());
+        }
+        if self.port == 0 {
+            return Err("port can't be 0".to_owned());
+        }
+        if self.password.is_some() && self.username.is_none() {
+            return Err("A password needs a username".to_owned());
+        }
+        for (field, topic) in [("base_topic", &self.base_topic), ("discovery_prefix", &self.discovery_prefix)] {
+            if let Some(topic) = topic {
+                if topic.is_empty() || topic.starts_with('/') || topic.ends_with('/') || topic.contains(['+', '#']) {
+                    return Err(format!("Invalid {field} {topic:?}, no wildcards or leading/trailing slashes"));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where everything about one doser goes
+pub struct Topics {
+    pub base: String,
+    pub state: String,        // Retained JSON with the status, prime states and levels
+    pub event: String,        // Finished doses
+    pub reply: String,        // Outcome of each command
+    pub availability: String, // "online", or "offline" through the will
+    pub node_id: String,      // Identifies the doser to Home Assistant
+}
+
+impl Topics {
+    pub fn new(config: &MqttConfig, device_id: &str) -> Self {
+        let base = match &config.base_topic {
+            Some(base) => base.clone(),
+            None => format!("nutrient-doser/{device_id}"),
+        };
+        Self {
+            state: format!("{base}/state"),
+            event: format!("{base}/event"),
+            reply: format!("{base}/reply"),
+            availability: format!("{base}/availability"),
+            node_id: format!("nutrient_doser_{device_id}"),
+            base,
+        }
+    }
+
+    pub fn commands(&self) -> String {
+        format!("{}/cmd/+", self.base)
+    }
+
+    /// Which command a message is, if it's one
+    pub fn command<'a>(&self, topic: &'a str) -> Option<&'a str> {
+        let cmd = topic.strip_prefix(&self.base)?.strip_prefix("/cmd/")?;
+        COMMANDS.contains(&cmd).then_some(cmd)
+    }
+}
+
+/// Home Assistant discovery configs, as (topic, payload). Every motor gets a
+/// prime state and level sensor and an unprime button, `motors` has the id
+/// and name of each.
+pub fn discovery(prefix: &str, topics: &Topics, motors: &[(u32, String)]) -> Vec<(String, String)> {
+    let node = &topics.node_id;
+    let device = json!({
+        "identifiers": [node],
+        "name": "Nutrient doser",
+        "model": "Nutrient doser",
+        "sw_version": env!("CARGO_PKG_VERSION"),
+    });
+    let entity = |component: &str, object: &str, mut config: serde_json::Value| {
+        config["unique_id"] = json!(format!("{node}_{object}"));
+        config["object_id"] = json!(format!("{node}_{object}"));
+        config["availability_topic"] = json!(topics.availability);
+        config["device"] = device.clone();
+        (format!("{prefix}/{component}/{node}/{object}/config"), config.to_string())
+    };
+
+    let mut configs = vec![
+        entity(
+            "sensor",
+            "status",
+            json!({
+                "name": "Status",
+                "state_topic": topics.state,
+                "value_template": "{{ value_json.status }}",
+                "icon": "mdi:water-pump",
+            }),
+        ),
+        entity(
+            "button",
+            "stop",
+            json!({
+                "name": "Emergency stop",
+                "command_topic": format!("{}/cmd/stop", topics.base),
+                "icon": "mdi:stop-circle",
+            }),
+        ),
+    ];
+    for (idx, (id, name)) in motors.iter().enumerate() {
+        configs.push(entity(
+            "sensor",
+            &format!("motor{idx}_prime"),
+            json!({
+                "name": format!("{name} prime"),
+                "state_topic": topics.state,
+                "value_template": format!("{{{{ value_json.motors[{idx}].prime_state }}}}"),
+                "icon": "mdi:pipe",
+            }),
+        ));
+        configs.push(entity(
+            "sensor",
+            &format!("motor{idx}_level"),
+            json!({
+                "name": format!("{name} level"),
+                "state_topic": topics.state,
+                // null until the bottle is refilled through the API, which HA shows as unknown
+                "value_template": format!("{{{{ value_json.motors[{idx}].level_ml }}}}"),
+                "unit_of_measurement": "mL",
+                "device_class": "volume_storage",
+                "state_class": "measurement",
+            }),
+        ));
+        configs.push(entity(
+            "button",
+            &format!("motor{idx}_unprime"),
+            json!({
+                "name": format!("Unprime {name}"),
+                "command_topic": format!("{}/cmd/unprime", topics.base),
+                // Buttons outlive the current order of the motors
+                "payload_press": json!({ "motor_id": id }).to_string(),
+                "icon": "mdi:pipe-disconnected",
+            }),
+        ));
+    }
+    configs
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    pub topic: String,
+    pub payload: Vec<u8>,
+    pub retain: bool,
+}
+
+pub struct Options<'a> {
+    pub host: &'a str,
+    pub port: u16,
+    pub client_id: &'a str,
+    pub username: Option<&'a str>,
+    pub password: Option<&'a str>,
+    pub will: Option<Message>, // Published by the broker if the connection drops
+}
+
+/// Connection to a broker. Messages for the subscriptions come in through
+/// `recv`, which stops returning them once the connection is gone.
+pub struct Client {
+    writer: OwnedWriteHalf,
+    incoming: mpsc::Receiver<Message>,
+    next_id: u16,
+}
+
+impl Client {
+    pub async fn connect(opts: &Options<'_>) -> anyhow::Result<Self> {
+        let stream = timeout(CONNECT_TIMEOUT, TcpStream::connect((opts.host, opts.port))).await??;
+        let (mut reader, mut writer) = stream.into_split();
+        writer.write_all(&connect_packet(opts)).await?;
+
+        let (kind, body) = timeout(CONNECT_TIMEOUT, read_packet(&mut reader)).await??;
+        let body = body.unwrap_or_default();
+        if kind != CONNACK || body.len() != 2 {
+            bail!("Expected CONNACK, got packet type {:#x}", kind >> 4);
+        }
+        match body[1] {
+            0 => {}
+            1 => bail!("Broker doesn't speak MQTT 3.1.1"),
+            2 => bail!("Broker rejected the client id"),
+            4 | 5 => bail!("Broker rejected the username or password"),
+            rc => bail!("Broker refused the connection ({rc})"),
+        }
+
+        let (tx, incoming) = mpsc::channel(8);
+        tokio::spawn(async move {
+            loop {
+                match read_packet(&mut reader).await {
+                    Ok((kind, Some(body))) if kind & 0xf0 == PUBLISH => {
+                        let Some(msg) = parse_publish(kind, &body) else {
+                            warn!("Dropping malformed PUBLISH");
+                            continue;
+                        };
+                        if tx.send(msg).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok((kind, None)) if kind & 0xf0 == PUBLISH => warn!("Dropping PUBLISH over {MAX_PACKET} bytes"),
+                    Ok(_) => {} // SUBACK and PINGRESP need no answer
+                    Err(e) => {
+                        warn!("MQTT connection closed: {e}");
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            writer,
+            incoming,
+            next_id: 1,
+        })
+    }
+
+    pub async fn publish(&mut self, topic: &str, payload: &[u8], retain: bool) -> io::Result<()> {
+        let mut body = Vec::with_capacity(topic.len() + payload.len() + 2);
+        put_str(&mut body, topic);
+        body.extend_from_slice(payload);
+        self.writer.write_all(&packet(PUBLISH | retain as u8, &body)).await
+    }
+
+    pub async fn subscribe(&mut self, filter: &str) -> io::Result<()> {
+        let mut body = self.next_id.to_be_bytes().to_vec();
+        self.next_id = self.next_id.checked_add(1).unwrap_or(1);
+        put_str(&mut body, filter);
+        body.push(0); // QoS 0
+        self.writer.write_all(&packet(SUBSCRIBE, &body)).await
+    }
+
+    /// Has to be sent at least every `KEEP_ALIVE` without other traffic
+    pub async fn ping(&mut self) -> io::Result<()> {
+        self.writer.write_all(&packet(PINGREQ, &[])).await
+    }
+
+    pub async fn recv(&mut self) -> Option<Message> {
+        self.incoming.recv().await
+    }
+
+    /// Clean disconnect, the will isn't published
+    pub async fn disconnect(mut self) {
+        self.writer.write_all(&packet(DISCONNECT, &[])).await.ok();
+    }
+}
+
+fn put_str(buf: &mut Vec<u8>, s: &str) {
+    buf.extend_from_slice(&(s.len() as u16).to_be_bytes());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+/// Fixed header with the remaining length as a varint, then the body
+fn packet(kind: u8, body: &[u8]) -> Vec<u8> {
+    let mut out = vec![kind];
+    let mut len = body.len();
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+        if len == 0 {
+            out.push(byte);
+            break;
+        }
+        out.push(byte | 0x80);
+    }
+    out.extend_from_slice(body);
+    out
+}
+
+fn connect_packet(opts: &Options) -> Vec<u8> {
+    let mut flags = 0x02; // Clean session
+    let mut body = Vec::new();
+    put_str(&mut body, "MQTT");
+    body.push(4); // 3.1.1
+    let flags_at = body.len();
+    body.push(0);
+    body.extend_from_slice(&(KEEP_ALIVE.as_secs() as u16).to_be_bytes());
+    put_str(&mut body, opts.client_id);
+    if let Some(will) = &opts.will {
+        flags |= 0x04 | if will.retain { 0x20 } else { 0 };
+        put_str(&mut body, &will.topic);
+        body.extend_from_slice(&(will.payload.len() as u16).to_be_bytes());
+        body.extend_from_slice(&will.payload);
+    }
+    if let Some(username) = opts.username {
+        flags |= 0x80;
+        put_str(&mut body, username);
+    }
+    if let Some(password) = opts.password {
+        flags |= 0x40;
+        put_str(&mut body, password);
+    }
+    body[flags_at] = flags;
+    packet(CONNECT, &body)
+}
+
+/// Packet type and body of the next packet. A body over `MAX_PACKET` is read
+/// past and comes back as `None`, the connection stays usable.
+async fn read_packet(reader: &mut (impl AsyncRead + Unpin)) -> io::Result<(u8, Option<Vec<u8>>)> {
+    let kind = reader.read_u8().await?;
+    let mut len = 0;
+    for i in 0..4 {
+        let byte = reader.read_u8().await?;
+        len |= ((byte & 0x7f) as usize) << (7 * i);
+        if byte & 0x80 == 0 {
+            break;
+        }
+        if i == 3 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Remaining length too long"));
+        }
+    }
+    if len > MAX_PACKET {
+        let skipped = tokio::io::copy(&mut (&mut *reader).take(len as u64), &mut tokio::io::sink()).await?;
+        if skipped < len as u64 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        return Ok((kind, None));
+    }
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body).await?;
+    Ok((kind, Some(body)))
+}
+
+fn parse_publish(kind: u8, body: &[u8]) -> Option<Message> {
+    let len = u16::from_be_bytes([*body.first()?, *body.get(1)?]) as usize;
+    let topic = std::str::from_utf8(body.get(2..2 + len)?).ok()?.to_owned();
+    // Only QoS 0 is subscribed to, but a packet id may still be there
+    let payload_at = match (kind >> 1) & 0x03 {
+        0 => 2 + len,
+        _ => 4 + len,
+    };
+    Some(Message {
+        topic,
+        payload: body.get(payload_at..)?.to_vec(),
+        retain: kind & 0x01 != 0,
+    })
+}
+
+#[derive(Serialize)]
+struct MqttMotor {
+    id: u32,
+    nutrient: Option<String>,
+    prime_state: PrimeState,
+    level_ml: Option<f64>,
+    level_low: bool,
+}
+
+#[derive(Serialize)]
+struct MqttState {
+    status: AppStatus,
+    motors: Vec<MqttMotor>,
+}
+
+impl<S: Stepper> AppState<S> {
+    /// Stays off without a stored config
+    pub(crate) async fn load_mqtt(&self) {
+        if let Some(config) = self.load(NVS_TAG_MQTT).await {
+            *self.mqtt.write().await = config;
+        }
+    }
+
+    /// Keeps a connection to the configured broker, retrying after failures
+    /// and reconnecting whenever the config changes
+    pub(crate) async fn run_mqtt(&self, mut reconfigure_rx: mpsc::Receiver<()>) {
+        loop {
+            let config = self.mqtt.read().await.clone();
+            if !config.enabled {
+                reconfigure_rx.recv().await;
+                continue;
+            }
+            let res = self.mqtt_session(&config, &mut reconfigure_rx).await;
+            *self.mqtt_connected.write().await = false;
+            if let Err(e) = res {
+                error!("MQTT session with {}:{} ended: {e}", config.host, config.port);
+                // Try again in a bit, or right away with a new config
+                timeout(MQTT_RETRY, reconfigure_rx.recv()).await.ok();
+            }
+        }
+    }
+
+    /// Publishes state and takes commands until the connection drops, or
+    /// returns `Ok` once the config changed
+    pub(crate) async fn mqtt_session(&self, config: &MqttConfig, reconfigure_rx: &mut mpsc::Receiver<()>) -> anyhow::Result<()> {
+        let topics = Topics::new(config, &self.device_id);
+        let mut events = self.events.subscribe();
+        let client_id = format!("nutrient-doser-{}", self.device_id);
+        let mut client = Client::connect(&Options {
+            host: &config.host,
+            port: config.port,
+            client_id: &client_id,
+            username: config.username.as_deref(),
+            password: config.password.as_deref(),
+            will: Some(Message {
+                topic: topics.availability.clone(),
+                payload: b"offline".to_vec(),
+                retain: true,
+            }),
+        })
+        .await?;
+        info!("Connected to MQTT broker {}:{}, publishing to {}", config.host, config.port, topics.base);
+
+        client.subscribe(&topics.commands()).await?;
+        if let Some(prefix) = &config.discovery_prefix {
+            let motors: Vec<_> = self
+                .labels
+                .read()
+                .await
+                .iter()
+                .enumerate()
+                .map(|(idx, l)| (l.id, l.nutrient.clone().unwrap_or_else(|| format!("Motor {idx}"))))
+                .collect();
+            for (topic, payload) in discovery(prefix, &topics, &motors) {
+                client.publish(&topic, payload.as_bytes(), true).await?;
+            }
+        }
+        client.publish(&topics.availability, b"online", true).await?;
+        client.publish(&topics.state, &self.mqtt_state().await, true).await?;
+        *self.mqtt_connected.write().await = true;
+
+        // Commands run on their own, a dose shouldn't hold up the connection
+        let (reply_tx, mut reply_rx) = mpsc::channel::<Vec<u8>>(4);
+        let mut ping = interval(KEEP_ALIVE / 2);
+        loop {
+            tokio::select! {
+                msg = client.recv() => {
+                    let Some(msg) = msg else {
+                        anyhow::bail!("Connection lost");
+                    };
+                    match topics.command(&msg.topic) {
+                        Some(cmd) => self.mqtt_command(cmd.to_owned(), msg.payload, reply_tx.clone()),
+                        None => warn!("Ignoring MQTT message on {}", msg.topic),
+                    }
+                }
+                event = events.recv() => match event {
+                    Ok(AppEvent::Dosed(record)) => {
+                        client.publish(&topics.event, &serde_json::to_vec(&record)?, false).await?;
+                    }
+                    // Missed events are covered by the full state too
+                    Ok(AppEvent::Status(_) | AppEvent::Motors) | Err(broadcast::error::RecvError::Lagged(_)) => {
+                        client.publish(&topics.state, &self.mqtt_state().await, true).await?;
+                    }
+                    Ok(_) => {}
+                    Err(broadcast::error::RecvError::Closed) => anyhow::bail!("App stopped"),
+                },
+                Some(reply) = reply_rx.recv() => client.publish(&topics.reply, &reply, false).await?,
+                _ = ping.tick() => client.ping().await?,
+                _ = reconfigure_rx.recv() => {
+                    info!("MQTT config changed, reconnecting");
+                    client.publish(&topics.availability, b"offline", true).await?;
+                    client.disconnect().await;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Retained state, every motor in the same order as the API's. Prime
+    /// states are as of the last motion that finished.
+    pub(crate) async fn mqtt_state(&self) -> Vec<u8> {
+        let motors = self.motor_snapshots();
+        let labels = self.labels.read().await.clone();
+        let low_ml = self.level_policy.read().await.low_ml;
+        let state = MqttState {
+            status: *self.status.read().await,
+            motors: motors
+                .iter()
+                .zip(labels)
+                .map(|(m, label)| MqttMotor {
+                    id: m.id,
+                    nutrient: label.nutrient,
+                    prime_state: m.prime_state,
+                    level_ml: label.level_ml,
+                    level_low: label.level_ml.is_some_and(|ml| ml < low_ml),
+                })
+                .collect(),
+        };
+        serde_json::to_vec(&state).unwrap_or_default()
+    }
+
+    /// Runs a command the same way its endpoint would and answers on the
+    /// reply topic, with the job it started or what was wrong with it
+    pub(crate) fn mqtt_command(&self, cmd: String, payload: Vec<u8>, reply_tx: mpsc::Sender<Vec<u8>>) {
+        fn parse<T: DeserializeOwned>(payload: &[u8]) -> Result<T, ApiError> {
+            serde_json::from_slice(payload).map_err(|e| ApiError::invalid(format!("Invalid payload: {e}")))
+        }
+        let job = |res: JobResponse| res.map(|(_, Json(created))| Some(created.job_id));
+
+        info!("MQTT command {cmd}");
+        let state = self.clone();
+        tokio::spawn(async move {
+            let res = match cmd.as_str() {
+                "dispense" => match parse(&payload) {
+                    Ok(req) => job(dispense(State(state), Json(req)).await),
+                    Err(e) => Err(e),
+                },
+                "dose" => match parse(&payload) {
+                    Ok(req) => job(dose_solution(State(state), Json(req)).await),
+                    Err(e) => Err(e),
+                },
+                // Every motor without a payload, like the HA button for the whole doser would send
+                "unprime" if payload.is_empty() => job(unprime_all(State(state)).await),
+                "unprime" => match parse(&payload) {
+                    Ok(req) => unprime(State(state), Json(req)).await.map(|_| None),
+                    Err(e) => Err(e),
+                },
+                "stop" => emergency_stop(State(state)).await.map(|_| None),
+                other => Err(ApiError::invalid(format!("Unknown command {other}"))),
+            };
+            let reply = match res {
+                Ok(job_id) => serde_json::json!({ "command": cmd, "job_id": job_id }),
+                Err(e) => {
+                    warn!("MQTT command {cmd} failed: {e}");
+                    serde_json::json!({ "command": cmd, "error": e })
+                }
+            };
+            reply_tx.send(reply.to_string().into_bytes()).await.ok();
+        });
+    }
+}
+
+#[derive(Serialize)]
+pub(crate) struct MqttStatus {
+    #[serde(flatten)]
+    config: MqttConfig, // without the password
+    has_password: bool,
+    connected: bool,
+}
+
+pub(crate) async fn get_mqtt_config<S: Stepper>(State(state): State<AppState<S>>) -> Json<MqttStatus> {
+    let mut config = state.mqtt.read().await.clone();
+    Json(MqttStatus {
+        has_password: config.password.take().is_some(),
+        config,
+        connected: *state.mqtt_connected.read().await,
+    })
+}
+
+/// Stores the broker config and reconnects with it. Leaving out the password
+/// keeps the stored one, an empty one clears it.
+pub(crate) async fn put_mqtt_config<S: Stepper>(
+    State(state): State<AppState<S>>,
+    Json(mut req): Json<MqttConfig>,
+) -> Result<Json<MqttStatus>, ApiError> {
+    req.password = match req.password {
+        Some(pass) if pass.is_empty() => None,
+        Some(pass) => Some(pass),
+        // The stored password only goes with the user it was set for
+        None => {
+            let current = state.mqtt.read().await;
+            match req.username.is_some() && req.username == current.username {
+                true => current.password.clone(),
+                false => None,
+            }
+        }
+    };
+    req.validate().map_err(ApiError::invalid)?;
+    // Not through `store`, that would log the password
+    let config = serde_json::to_string(&req).map_err(|e| storage_error(NVS_TAG_MQTT, e))?;
+    state
+        .nvs
+        .write()
+        .await
+        .set_str(NVS_TAG_MQTT, &config)
+        .map_err(|e| storage_error(NVS_TAG_MQTT, e))?;
+    info!("MQTT config saved, broker {}:{}", req.host, req.port);
+    *state.mqtt.write().await = req;
+    state.mqtt_tx.try_send(()).ok();
+    Ok(get_mqtt_config(State(state)).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    fn block_on<F: Future>(f: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(f)
+    }
+
+    /// Stand-in broker for a single client: accepts it, records what it
+    /// sends and echoes every PUBLISH back, like a subscription to `#`
+    async fn fake_broker(listener: TcpListener, rc: u8, seen: mpsc::Sender<(u8, Vec<u8>)>) {
+        let (stream, _) = listener.accept().await.unwrap();
+        let (mut reader, mut writer) = stream.into_split();
+        while let Ok((kind, Some(body))) = read_packet(&mut reader).await {
+            match kind & 0xf0 {
+                CONNECT => writer.write_all(&packet(CONNACK, &[0, rc])).await.unwrap(),
+                PUBLISH => writer.write_all(&packet(kind, &body)).await.unwrap(),
+                _ => {}
+            }
+            if seen.send((kind, body)).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    fn options(port: u16) -> Options<'static> {
+        Options {
+            host: "127.0.0.1",
+            port,
+            client_id: "doser-test",
+            username: Some("doser"),
+            password: Some("hunter22"),
+            will: Some(Message {
+                topic: "doser/availability".to_owned(),
+                payload: b"offline".to_vec(),
+                retain: true,
+            }),
+        }
+    }
+
+    #[test]
+    fn connects_and_echoes() {
+        block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let (tx, mut seen) = mpsc::channel(8);
+            tokio::spawn(fake_broker(listener, 0, tx));
+
+            let mut client = Client::connect(&options(port)).await.unwrap();
+            let (kind, connect) = seen.recv().await.unwrap();
+            assert_eq!(kind, CONNECT);
+            assert_eq!(&connect[..7], b"\0\x04MQTT\x04");
+            assert_eq!(connect[7], 0x80 | 0x40 | 0x20 | 0x04 | 0x02);
+            assert!(connect.ends_with(b"\0\x05doser\0\x08hunter22"));
+
+            client.subscribe("doser/cmd/+").await.unwrap();
+            let (kind, sub) = seen.recv().await.unwrap();
+            assert_eq!(kind, SUBSCRIBE);
+            assert_eq!(sub, b"\0\x01\0\x0bdoser/cmd/+\0");
+
+            // Long enough for a two byte remaining length
+            let payload = vec![b'x'; 300];
+            client.publish("doser/state", &payload, true).await.unwrap();
+            let msg = client.recv().await.unwrap();
+            assert_eq!(
+                msg,
+                Message {
+                    topic: "doser/state".to_owned(),
+                    payload,
+                    retain: true,
+                }
+            );
+        });
+    }
+
+    #[test]
+    fn refused_connection() {
+        block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let (tx, _seen) = mpsc::channel(8);
+            tokio::spawn(fake_broker(listener, 5, tx));
+
+            let err = Client::connect(&options(port)).await.err().unwrap();
+            assert!(err.to_string().contains("username or password"));
+        });
+    }
+
+    #[test]
+    fn skips_oversized_packets() {
+        block_on(async {
+            let mut topic = Vec::new();
+            put_str(&mut topic, "doser/cmd/dose");
+            let big = [topic.clone(), vec![b'x'; MAX_PACKET]].concat();
+            let small = [topic, b"{}".to_vec()].concat();
+            let stream = [packet(PUBLISH, &big), packet(PUBLISH, &small)].concat();
+
+            let mut reader = stream.as_slice();
+            assert_eq!(read_packet(&mut reader).await.unwrap(), (PUBLISH, None));
+            assert_eq!(read_packet(&mut reader).await.unwrap(), (PUBLISH, Some(small)));
+            assert!(read_packet(&mut reader).await.is_err());
+
+            // Cut off in the middle of the skipped body
+            let stream = packet(PUBLISH, &big);
+            let mut reader = &stream[..stream.len() - 1];
+            assert!(read_packet(&mut reader).await.is_err());
+        });
+    }
+
+    #[test]
+    fn topics() {
+        let topics = Topics::new(&MqttConfig::default(), "aabbccddeeff");
+        assert_eq!(topics.state, "nutrient-doser/aabbccddeeff/state");
+        assert_eq!(topics.commands(), "nutrient-doser/aabbccddeeff/cmd/+");
+        assert_eq!(topics.command("nutrient-doser/aabbccddeeff/cmd/dose"), Some("dose"));
+        assert_eq!(topics.command("nutrient-doser/aabbccddeeff/cmd/reboot"), None);
+        assert_eq!(topics.command("nutrient-doser/other/cmd/stop"), None);
+
+        let configs = discovery("homeassistant", &topics, &[(4, "CalMag".to_owned())]);
+        assert_eq!(configs.len(), 5);
+        let (topic, payload) = &configs[3];
+        assert_eq!(topic, "homeassistant/sensor/nutrient_doser_aabbccddeeff/motor0_level/config");
+        let payload: serde_json::Value = serde_json::from_str(payload).unwrap();
+        assert_eq!(payload["name"], "CalMag level");
+        assert_eq!(payload["value_template"], "{{ value_json.motors[0].level_ml }}");
+        let payload: serde_json::Value = serde_json::from_str(&configs[4].1).unwrap();
+        assert_eq!(payload["command_topic"], "nutrient-doser/aabbccddeeff/cmd/unprime");
+        assert_eq!(payload["payload_press"], r#"{"motor_id":4}"#);
+    }
+}
@@ -0,0 +1,385 @@
+use std::net::Ipv4Addr;
+
+use axum::extract::State;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    app::{storage_error, AppState},
+    error::{ApiError, Json},
+    provision::WifiCreds,
+    stepper::Stepper,
+    storage::Storage,
+};
+
+pub const NVS_TAG_NETWORK: &str = "network";
+// Config from before the last change, kept until the new one connects
+pub const NVS_TAG_NETWORK_PREV: &str = "network_prev";
+// Written by older firmware, only read to build the first config
+const NVS_TAG_HOSTNAME: &str = "HOSTNAME";
+
+pub const MAX_NETWORKS: usize = 5;
+
+/// How the doser joins Wi-Fi, applied by `WifiLoop::configure` when it boots
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct NetworkConfig {
+    pub networks: Vec<WifiCreds>, // Tried in order
+    pub ip: IpConfig,
+    pub hostname: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Default, PartialEq, Debug)]
+#[serde(tag = "mode", rename_all = "lowercase")]
+pub enum IpConfig {
+    #[default]
+    Dhcp,
+    Static {
+        ip: Ipv4Addr,
+        prefix: u8, // e.g. 24 for 255.255.255.0
+        gateway: Ipv4Addr,
+        dns: Option<Ipv4Addr>, // Defaults to the gateway
+    },
+}
+
+impl NetworkConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.networks.is_empty() {
+            return Err("Give at least one network".to_owned());
+        }
+        if self.networks.len() > MAX_NETWORKS {
+            return Err(format!("At most {MAX_NETWORKS} networks can be stored"));
+        }
+        for (i, n) in self.networks.iter().enumerate() {
+            n.validate().map_err(|e| format!("{}: {e}", n.ssid))?;
+            if self.networks[..i].iter().any(|other| other.ssid == n.ssid) {
+                return Err(format!("{} is listed twice", n.ssid));
+            }
+        }
+
+        if let Some(hostname) = &self.hostname {
+            let valid_chars = hostname.chars().all(|c| c.is_ascii_alphanumeric() || c == '-');
+            if !(1..=30).contains(&hostname.len()) || !valid_chars || hostname.starts_with('-') || hostname.ends_with('-') {
+                return Err(format!("Invalid hostname {hostname:?}, use up to 30 letters, digits and dashes"));
+            }
+        }
+
+        if let IpConfig::Static { ip, prefix, gateway, .. } = self.ip {
+            if !(8..=30).contains(&prefix) {
+                return Err(format!("prefix must be between 8 and 30, got {prefix}"));
+            }
+            let mask = u32::MAX << (32 - prefix);
+            let host = u32::from(ip) & !mask;
+            if host == 0 || host == !mask {
+                return Err(format!("{ip} isn't a host address in a /{prefix} network"));
+            }
+            if ip == gateway || u32::from(ip) & mask != u32::from(gateway) & mask {
+                return Err(format!("Gateway {gateway} isn't another address in {ip}/{prefix}"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Stored config, or one put together from the hostname older firmware left
+/// behind. Falls back to the previous config if the stored one is unreadable.
+pub fn load_config(storage: &dyn Storage) -> NetworkConfig {
+    for key in [NVS_TAG_NETWORK, NVS_TAG_NETWORK_PREV] {
+        match storage.get_str(key) {
+            Ok(Some(config)) => match serde_json::from_str(&config) {
+                Ok(config) => return config,
+                Err(e) => error!("Failed to parse {key}: {e}"),
+            },
+            Ok(None) => {}
+            Err(e) => error!("Failed to read {key} from nvs: {e}"),
+        }
+    }
+
+    let hostname = match option_env!("HOSTNAME") {
+        Some(hostname) => Some(hostname.to_owned()),
+        None => storage.get_str(NVS_TAG_HOSTNAME).ok().flatten(),
+    };
+    NetworkConfig {
+        hostname: hostname.filter(|h| !h.is_empty()),
+        ..Default::default()
+    }
+}
+
+/// `config`, or `fallback` if it has no networks. Boards flashed with Wi-Fi
+/// credentials run on those until a network is stored, also after going back
+/// to a previous config that was using them.
+pub fn with_fallback(mut config: NetworkConfig, fallback: WifiCreds) -> NetworkConfig {
+    if config.networks.is_empty() && fallback.validate().is_ok() {
+        config.networks.push(fallback);
+    }
+    config
+}
+
+/// Stores `config` for the next connect, keeping the last config that
+/// connected to go back to if this one doesn't
+pub fn stage_config(storage: &mut dyn Storage, config: &NetworkConfig) -> anyhow::Result<()> {
+    if storage.get_str(NVS_TAG_NETWORK_PREV)?.is_none() {
+        let current = load_config(storage);
+        storage.set_str(NVS_TAG_NETWORK_PREV, &serde_json::to_string(&current)?)?;
+    }
+    storage.set_str(NVS_TAG_NETWORK, &serde_json::to_string(config)?)
+}
+
+/// Whether the stored config hasn't connected yet
+pub fn is_pending(storage: &dyn Storage) -> bool {
+    matches!(storage.get_str(NVS_TAG_NETWORK_PREV), Ok(Some(_)))
+}
+
+/// The stored config connected, there's nothing to go back to anymore
+pub fn confirm_config(storage: &mut dyn Storage) -> anyhow::Result<()> {
+    if storage.remove(NVS_TAG_NETWORK_PREV)? {
+        info!("New network config connected");
+    }
+    Ok(())
+}
+
+/// Puts the config from before the last change back in place, if the
+/// change hasn't connected yet
+pub fn restore_previous(storage: &mut dyn Storage) -> anyhow::Result<Option<NetworkConfig>> {
+    let Some(prev) = storage.get_str(NVS_TAG_NETWORK_PREV)? else {
+        return Ok(None);
+    };
+    let config = serde_json::from_str(&prev)?;
+    storage.set_str(NVS_TAG_NETWORK, &prev)?;
+    storage.remove(NVS_TAG_NETWORK_PREV)?;
+    Ok(Some(config))
+}
+
+/// Puts a network first in line, replacing what was stored for its SSID.
+/// Whatever gets provisioned by hand also replaces a change that's pending.
+pub fn add_network(storage: &mut dyn Storage, creds: WifiCreds) -> anyhow::Result<()> {
+    let mut config = load_config(storage);
+    config.networks.retain(|n| n.ssid != creds.ssid);
+    config.networks.insert(0, creds);
+    config.networks.truncate(MAX_NETWORKS);
+    storage.set_str(NVS_TAG_NETWORK, &serde_json::to_string(&config)?)?;
+    storage.remove(NVS_TAG_NETWORK_PREV)?;
+    Ok(())
+}
+
+/// Stored network, password left out
+#[derive(Serialize, Deserialize)]
+struct NetworkEntry {
+    ssid: String,
+    #[serde(skip_deserializing)]
+    secured: bool,
+    #[serde(skip_serializing)]
+    pass: Option<String>, // Left out to keep the stored password
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct NetworkConfigBody {
+    networks: Vec<NetworkEntry>,
+    #[serde(default)]
+    ip: IpConfig,
+    #[serde(default)]
+    hostname: Option<String>,
+    #[serde(skip_deserializing)]
+    pending: bool, // Not connected yet, the previous config comes back if it can't
+}
+
+pub(crate) async fn get_network_config<S: Stepper>(State(state): State<AppState<S>>) -> Json<NetworkConfigBody> {
+    let nvs = state.nvs.read().await;
+    let config = load_config(&**nvs);
+    Json(NetworkConfigBody {
+        networks: config
+            .networks
+            .into_iter()
+            .map(|n| NetworkEntry {
+                secured: !n.pass.is_empty(),
+                ssid: n.ssid,
+                pass: None,
+            })
+            .collect(),
+        ip: config.ip,
+        hostname: config.hostname,
+        pending: is_pending(&**nvs),
+    })
+}
+
+/// Stores the networks to try in order, addressing and hostname for the next
+/// boot. If they don't connect, the config from before comes back.
+pub(crate) async fn put_network_config<S: Stepper>(
+    State(state): State<AppState<S>>,
+    Json(req): Json<NetworkConfigBody>,
+) -> Result<Json<NetworkConfigBody>, ApiError> {
+    let mut nvs = state.nvs.write().await;
+    let stored = load_config(&**nvs).networks;
+    let mut networks = Vec::with_capacity(req.networks.len());
+    for n in req.networks {
+        let pass = match n.pass {
+            Some(pass) => pass,
+            None => match stored.iter().find(|s| s.ssid == n.ssid) {
+                Some(s) => s.pass.clone(),
+                None => return Err(ApiError::invalid(format!("No stored password for {}, give a pass", n.ssid))),
+            },
+        };
+        networks.push(WifiCreds { ssid: n.ssid, pass });
+    }
+    let config = NetworkConfig {
+        networks,
+        ip: req.ip,
+        hostname: req.hostname,
+    };
+    config.validate().map_err(ApiError::invalid)?;
+    stage_config(&mut **nvs, &config).map_err(|e| storage_error(NVS_TAG_NETWORK, e))?;
+    info!("Network config saved, applied after the next reboot");
+    drop(nvs);
+    Ok(get_network_config(State(state)).await)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::FileStorage;
+
+    fn creds(ssid: &str) -> WifiCreds {
+        WifiCreds {
+            ssid: ssid.to_owned(),
+            pass: "password123".to_owned(),
+        }
+    }
+
+    fn storage(name: &str) -> FileStorage {
+        let path = std::env::temp_dir().join(format!("doser-network-{name}-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        FileStorage::open(path).unwrap()
+    }
+
+    fn ssids(config: &NetworkConfig) -> Vec<&str> {
+        config.networks.iter().map(|n| n.ssid.as_str()).collect()
+    }
+
+    #[test]
+    fn restores_the_config_that_connected() {
+        let mut storage = storage("restore");
+        add_network(&mut storage, creds("home")).unwrap();
+        assert!(!is_pending(&storage));
+
+        let bad = NetworkConfig {
+            networks: vec![creds("typo")],
+            ..Default::default()
+        };
+        stage_config(&mut storage, &bad).unwrap();
+        assert!(is_pending(&storage));
+        assert_eq!(ssids(&load_config(&storage)), ["typo"]);
+
+        // A second change before rebooting still goes back to the one that connected
+        stage_config(&mut storage, &bad).unwrap();
+        let prev = restore_previous(&mut storage).unwrap().unwrap();
+        assert_eq!(ssids(&prev), ["home"]);
+        assert_eq!(ssids(&load_config(&storage)), ["home"]);
+        assert!(!is_pending(&storage));
+        assert!(restore_previous(&mut storage).unwrap().is_none());
+    }
+
+    #[test]
+    fn restores_the_compiled_in_network() {
+        // Nothing stored, the board was running on the credentials it was flashed with
+        let mut storage = storage("fallback");
+        let fallback = creds("flashed");
+        assert_eq!(ssids(&with_fallback(load_config(&storage), fallback.clone())), ["flashed"]);
+
+        let bad = NetworkConfig {
+            networks: vec![creds("typo")],
+            ..Default::default()
+        };
+        stage_config(&mut storage, &bad).unwrap();
+        assert_eq!(ssids(&with_fallback(load_config(&storage), fallback.clone())), ["typo"]);
+
+        let prev = restore_previous(&mut storage).unwrap().unwrap();
+        assert_eq!(ssids(&with_fallback(prev, fallback.clone())), ["flashed"]);
+        assert_eq!(ssids(&with_fallback(load_config(&storage), fallback)), ["flashed"]);
+    }
+
+    fn static_ip(ip: [u8; 4], prefix: u8, gateway: [u8; 4]) -> NetworkConfig {
+        NetworkConfig {
+            networks: vec![creds("home")],
+            ip: IpConfig::Static {
+                ip: ip.into(),
+                prefix,
+                gateway: gateway.into(),
+                dns: None,
+            },
+            hostname: None,
+        }
+    }
+
+    #[test]
+    fn validates_static_addressing() {
+        assert!(static_ip([192, 168, 1, 50], 24, [192, 168, 1, 1]).validate().is_ok());
+        assert!(static_ip([10, 0, 3, 7], 22, [10, 0, 0, 1]).validate().is_ok());
+        assert!(static_ip([10, 0, 0, 2], 30, [10, 0, 0, 1]).validate().is_ok());
+
+        // Prefix out of range
+        assert!(static_ip([192, 168, 1, 50], 7, [192, 168, 1, 1]).validate().is_err());
+        assert!(static_ip([192, 168, 1, 50], 31, [192, 168, 1, 1]).validate().is_err());
+        // Network and broadcast addresses
+        assert!(static_ip([192, 168, 1, 0], 24, [192, 168, 1, 1]).validate().is_err());
+        assert!(static_ip([192, 168, 1, 255], 24, [192, 168, 1, 1]).validate().is_err());
+        assert!(static_ip([10, 0, 3, 255], 22, [10, 0, 0, 1]).validate().is_err());
+        assert!(static_ip([10, 0, 0, 3], 30, [10, 0, 0, 1]).validate().is_err());
+        // Gateway in another network, or the doser itself
+        assert!(static_ip([192, 168, 1, 50], 24, [192, 168, 2, 1]).validate().is_err());
+        assert!(static_ip([10, 0, 4, 7], 22, [10, 0, 0, 1]).validate().is_err());
+        assert!(static_ip([192, 168, 1, 50], 24, [192, 168, 1, 50]).validate().is_err());
+    }
+
+    #[test]
+    fn validates_hostnames() {
+        let with_hostname = |hostname: &str| NetworkConfig {
+            networks: vec![creds("home")],
+            hostname: Some(hostname.to_owned()),
+            ..Default::default()
+        };
+        assert!(with_hostname("doser").validate().is_ok());
+        assert!(with_hostname("nutrient-doser-2").validate().is_ok());
+        assert!(with_hostname(&"a".repeat(30)).validate().is_ok());
+
+        assert!(with_hostname("").validate().is_err());
+        assert!(with_hostname(&"a".repeat(31)).validate().is_err());
+        assert!(with_hostname("-doser").validate().is_err());
+        assert!(with_hostname("doser-").validate().is_err());
+        assert!(with_hostname("doser.lan").validate().is_err());
+        assert!(with_hostname("my doser").validate().is_err());
+        assert!(with_hostname("dosér").validate().is_err());
+    }
+
+    #[test]
+    fn validates_networks() {
+        assert!(NetworkConfig::default().validate().is_err());
+        let listed_twice = NetworkConfig {
+            networks: vec![creds("home"), creds("home")],
+            ..Default::default()
+        };
+        assert!(listed_twice.validate().is_err());
+        let too_many = NetworkConfig {
+            networks: (0..=MAX_NETWORKS).map(|i| creds(&format!("net{i}"))).collect(),
+            ..Default::default()
+        };
+        assert!(too_many.validate().is_err());
+    }
+
+    #[test]
+    fn confirm_drops_the_previous_config() {
+        let mut storage = storage("confirm");
+        add_network(&mut storage, creds("home")).unwrap();
+        stage_config(
+            &mut storage,
+            &NetworkConfig {
+                networks: vec![creds("new")],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        confirm_config(&mut storage).unwrap();
+        assert!(!is_pending(&storage));
+        assert!(restore_previous(&mut storage).unwrap().is_none());
+        assert_eq!(ssids(&load_config(&storage)), ["new"]);
+    }
+}
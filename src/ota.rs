@@ -0,0 +1,134 @@
+use embedded_svc::http::{
+    client::{Client, Response},
+    Headers, Method,
+};
+use esp_idf_svc::{
+    http::client::{Configuration, EspHttpConnection},
+    ota::{EspOta, FirmwareInfo},
+    sys::{EspError, ESP_ERR_IMAGE_INVALID, ESP_ERR_INVALID_RESPONSE, ESP_FAIL},
+};
+use http::{header::ACCEPT, Uri};
+use log::{error, info};
+use mime::APPLICATION_OCTET_STREAM;
+use tokio::sync::oneshot;
+
+#[macro_export]
+macro_rules! esp_err {
+    ($x:ident) => {
+        Err(EspError::from_infallible::<$x>())
+    };
+}
+
+const FIRMWARE_DOWNLOAD_CHUNK_SIZE: usize = 1024 * 20;
+const FIRMWARE_MAX_SIZE: usize  = 0x3f0000; // Max size of each app partition
+const FIRMWARE_MIN_SIZE: usize  = size_of::<FirmwareInfo>() + 1024;
+
+/// `progress` gets the percentage written so far, whenever it goes up by one
+fn handle_ota_resp(mut resp: Response<&mut EspHttpConnection>, progress: impl Fn(u8)) -> Result<(), EspError> {
+    if resp.status() != 200 {
+        error!("Unexpected HTTP response: {}", resp.status());
+        return esp_err!(ESP_ERR_INVALID_RESPONSE);
+    }
+
+    // Check firmware size
+    let file_size = resp.content_len().unwrap_or(0) as usize;
+    if file_size <= FIRMWARE_MIN_SIZE {
+        error!("Firmware size ({file_size}) is too small!");
+        return esp_err!(ESP_ERR_IMAGE_INVALID);
+    }
+    if file_size > FIRMWARE_MAX_SIZE {
+        error!("Firmware size ({file_size}) is too large!");
+        return esp_err!(ESP_ERR_IMAGE_INVALID);
+    }
+
+    // Start OTA
+    let mut ota = EspOta::new()?;
+
+    // For some reason the call to EspOta::get_firmware_info inside these raises ESP_ERR_INVALID_SIZE now???
+    // just logging these, so commenting out for now
+    // info!(
+    //     "CURRENT SLOTS (BOOT, RUN, UPD): ({}, {}, {})",
+    //     ota.get_boot_slot()?.label,
+    //     ota.get_running_slot()?.label,
+    //     ota.get_update_slot()?.label
+    // );
+
+    let mut upd = ota.initiate_update()?;
+    let mut buf = vec![0; FIRMWARE_DOWNLOAD_CHUNK_SIZE];
+    let mut total: usize = 0;
+    let mut percent = 0;
+    progress(percent);
+    let ota_res = loop {
+        let n = resp.read(&mut buf).unwrap_or_default();
+        total += n;
+
+        if n > 0 {
+            if let Err(e) = upd.write(&buf[..n]) {
+                error!("Failed to write OTA chunk: {e:?}");
+                break Err(e);
+            }
+            info!(
+                "OTA progress: {:.2}%",
+                100.0 * total as f32 / file_size as f32
+            );
+            let now = (100 * total.min(file_size) / file_size) as u8;
+            if now > percent {
+                percent = now;
+                progress(percent);
+            }
+        }
+
+        if total >= file_size {
+            break Ok(());
+        }
+    };
+
+    // TODO: checksum
+
+    if ota_res.is_err() || total < file_size {
+        error!("Error while writing OTA, aborting");
+        error!("Total of {total} out of {file_size} bytes received");
+        return upd.abort();
+    }
+
+    // OTA was successful if we reach this
+    upd.complete()
+}
+
+pub async fn do_ota(uri: Uri, progress: impl Fn(u8) + Send + 'static) -> Result<(), EspError> {
+    let (signal_tx, signal_rx) = oneshot::channel();
+    let req_task = tokio::task::spawn_blocking(move || -> Result<(), EspError> {
+        let mut client = Client::wrap(EspHttpConnection::new(&Configuration {
+            buffer_size: Some(4096),
+            ..Default::default()
+        })?);
+
+        let uri_str = uri.to_string();
+        let headers = [(ACCEPT.as_str(), APPLICATION_OCTET_STREAM.as_ref())];
+        let res = match client.request(Method::Get, &uri_str, &headers) {
+            Ok(req) => match req.submit() {
+                Ok(resp) => handle_ota_resp(resp, progress),
+                Err(e) => {
+                    error!("Failed to send request! {e:?}");
+                    esp_err!(ESP_FAIL)
+                }
+            },
+            Err(e) => {
+                error!("Failed to build request! {e:?}");
+                esp_err!(ESP_FAIL)
+            }
+        };
+
+        // Signal the outer await to exit before this thread is done
+        signal_tx.send(res).unwrap();
+        res
+    });
+
+    // await a signal instead of waiting on the thread so other tasks can keep running
+    let ota_success = signal_rx.await.unwrap();
+    if let Err(e) = req_task.await {
+        error!("Blocking OTA task didn't join properly ???: {e:?}");
+    }
+
+    ota_success
+}
@@ -0,0 +1,104 @@
+use std::{collections::BTreeSet, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+/// How long to wait after the last motion before writing final positions,
+/// so a burst of jobs only costs a single flash write
+pub const FLUSH_DELAY: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+pub struct SavedPosition {
+    pub id: u32,
+    pub position: i64,
+    pub moving: bool, // the motor moved since, or its position isn't known at all
+}
+
+/// Decides when motor positions need to be written to flash. Motors are
+/// marked as moving before their first motion and only get their final
+/// position written once things have settled down, so a reboot in between
+/// is detected without writing on every single move.
+#[derive(Default)]
+pub struct PositionLog {
+    moving: BTreeSet<u32>,
+    last_written: Option<Vec<SavedPosition>>,
+}
+
+impl PositionLog {
+    /// Mark motors as about to move, returns the record to write if they
+    /// weren't already marked
+    pub fn begin(
+        &mut self,
+        ids: impl IntoIterator<Item = u32>,
+        current: impl IntoIterator<Item = SavedPosition>,
+    ) -> Option<Vec<SavedPosition>> {
+        let before = self.moving.len();
+        self.moving.extend(ids);
+        match self.moving.len() > before {
+            true => self.update(current),
+            false => None,
+        }
+    }
+
+    /// Motion has settled, returns the record to write if anything changed
+    pub fn finish(&mut self, current: impl IntoIterator<Item = SavedPosition>) -> Option<Vec<SavedPosition>> {
+        self.moving.clear();
+        self.update(current)
+    }
+
+    /// Start from what was read back from flash at boot
+    pub fn restored(&mut self, saved: Vec<SavedPosition>) {
+        self.last_written = Some(saved);
+    }
+
+    fn update(&mut self, current: impl IntoIterator<Item = SavedPosition>) -> Option<Vec<SavedPosition>> {
+        let record: Vec<_> = current
+            .into_iter()
+            .map(|p| SavedPosition {
+                moving: p.moving || self.moving.contains(&p.id),
+                ..p
+            })
+            .collect();
+        if self.last_written.as_ref() == Some(&record) {
+            return None;
+        }
+        self.last_written = Some(record.clone());
+        Some(record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(id: u32, position: i64) -> SavedPosition {
+        SavedPosition {
+            id,
+            position,
+            moving: false,
+        }
+    }
+
+    #[test]
+    fn marks_motors_once_per_motion() {
+        let mut log = PositionLog::default();
+        let record = log.begin([4], [at(4, 100), at(6, 0)]).unwrap();
+        assert_eq!(record, [SavedPosition { moving: true, ..at(4, 100) }, at(6, 0)]);
+        assert_eq!(log.begin([4], [at(4, 150), at(6, 0)]), None, "already marked");
+        let record = log.begin([6], [at(4, 150), at(6, 0)]).unwrap();
+        assert!(record.iter().all(|p| p.moving));
+
+        let record = log.finish([at(4, 200), at(6, 50)]).unwrap();
+        assert_eq!(record, [at(4, 200), at(6, 50)]);
+        assert_eq!(log.finish([at(4, 200), at(6, 50)]), None, "nothing moved since");
+    }
+
+    #[test]
+    fn keeps_an_unknown_position_unknown() {
+        let unknown = SavedPosition { moving: true, ..at(4, 0) };
+        let mut log = PositionLog::default();
+        log.restored(vec![unknown]);
+        assert_eq!(log.finish([unknown]), None);
+        assert_eq!(log.begin([4], [unknown]), None, "already written as moving");
+        assert_eq!(log.finish([at(4, 0)]), Some(vec![at(4, 0)]));
+    }
+}
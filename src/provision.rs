@@ -0,0 +1,376 @@
+use std::{net::Ipv4Addr, sync::Arc, time::Duration};
+
+use axum::{
+    extract::State,
+    response::{Html, Redirect},
+    routing::{get, post},
+    Router,
+};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    sync::{mpsc, oneshot, RwLock},
+};
+
+use crate::{
+    app::PORT,
+    error::{ApiError, ErrorCode, Json},
+    network,
+    storage::Storage,
+};
+
+// Failed connects in a row to each stored network before falling back to the SoftAP
+pub const MAX_CONNECT_ATTEMPTS: u32 = 5;
+
+// Without anyone using the page, reboot and try the stored network again, it
+// may have only been down for a bit
+pub const PROVISION_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+// SoftAP name, followed by the end of the MAC to tell dosers apart
+pub const AP_SSID_PREFIX: &str = "nutrient-doser";
+
+/// Network to join in station mode. Not `Debug`, so the password can't end
+/// up in the logs.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WifiCreds {
+    pub ssid: String,
+    #[serde(default)]
+    pub pass: String, // Empty for an open network
+}
+
+impl WifiCreds {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.ssid.is_empty() || self.ssid.len() > 32 {
+            return Err("SSID must be 1 to 32 bytes".to_owned());
+        }
+        if !self.pass.is_empty() && !(8..=64).contains(&self.pass.len()) {
+            return Err("Password must be 8 to 64 characters, or empty for an open network".to_owned());
+        }
+        Ok(())
+    }
+}
+
+/// Network seen by a scan
+#[derive(Serialize, Clone)]
+pub struct Network {
+    pub ssid: String,
+    pub rssi: i8,
+    pub secured: bool,
+}
+
+/// One entry per SSID with its strongest access point, strongest first.
+/// Hidden networks are left out, they can still be typed in.
+pub fn dedup_networks(seen: impl IntoIterator<Item = Network>) -> Vec<Network> {
+    let mut networks: Vec<Network> = Vec::new();
+    for n in seen.into_iter().filter(|n| !n.ssid.is_empty()) {
+        match networks.iter_mut().find(|known| known.ssid == n.ssid) {
+            Some(known) if known.rssi < n.rssi => *known = n,
+            Some(_) => {}
+            None => networks.push(n),
+        }
+    }
+    networks.sort_by_key(|n| std::cmp::Reverse(n.rssi));
+    networks
+}
+
+/// Network that got stored, the doser reboots to join it
+#[derive(Serialize)]
+struct Saved {
+    ssid: String,
+    secured: bool,
+}
+
+/// What the provisioning server needs from whoever owns the Wi-Fi driver
+pub enum RadioRequest {
+    Scan(oneshot::Sender<anyhow::Result<Vec<Network>>>),
+    Saved, // Credentials are stored, time to reboot into station mode
+}
+
+#[derive(Clone)]
+struct ProvisionState {
+    radio: mpsc::Sender<RadioRequest>,
+    storage: Arc<RwLock<Box<dyn Storage>>>,
+}
+
+/// Serves the provisioning page and a DNS server pointing every name at
+/// `ip`, so phones joining the SoftAP open the page as a captive portal
+pub async fn serve(radio: mpsc::Sender<RadioRequest>, storage: Box<dyn Storage>, ip: Ipv4Addr) -> anyhow::Result<()> {
+    tokio::spawn(async move {
+        if let Err(e) = run_dns(ip).await {
+            error!("Captive portal DNS stopped: {e}");
+        }
+    });
+
+    let state = ProvisionState {
+        radio,
+        storage: Arc::new(RwLock::new(storage)),
+    };
+    let app = Router::new()
+        .route("/", get(page))
+        .route("/wifi/scan", get(scan))
+        .route("/wifi", post(save))
+        // Connectivity checks like /generate_204 land here and pop up the portal
+        .fallback(move || async move { Redirect::temporary(&format!("http://{ip}/")) })
+        .with_state(state);
+
+    info!("Provisioning page at http://{ip}/");
+    let listener = TcpListener::bind(format!("0.0.0.0:{PORT}")).await?;
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+async fn page() -> Html<&'static str> {
+    Html(PAGE)
+}
+
+async fn scan(State(state): State<ProvisionState>) -> Result<Json<Vec<Network>>, ApiError> {
+    let unavailable = |e: String| ApiError::new(ErrorCode::Unavailable, format!("Scan failed: {e}"));
+    let (tx, rx) = oneshot::channel();
+    state
+        .radio
+        .send(RadioRequest::Scan(tx))
+        .await
+        .map_err(|e| unavailable(e.to_string()))?;
+    match rx.await {
+        Ok(Ok(networks)) => Ok(Json(networks)),
+        Ok(Err(e)) => Err(unavailable(e.to_string())),
+        Err(e) => Err(unavailable(e.to_string())),
+    }
+}
+
+/// Stores the network to join first and reboots into station mode
+async fn save(State(state): State<ProvisionState>, Json(req): Json<WifiCreds>) -> Result<Json<Saved>, ApiError> {
+    req.validate().map_err(ApiError::invalid)?;
+    let saved = Saved {
+        ssid: req.ssid.clone(),
+        secured: !req.pass.is_empty(),
+    };
+    if let Err(e) = network::add_network(&mut **state.storage.write().await, req) {
+        error!("Failed to write Wi-Fi credentials to nvs: {e}");
+        return Err(ApiError::new(ErrorCode::StorageError, format!("Couldn't save {}: {e}", network::NVS_TAG_NETWORK)));
+    }
+    info!("Saved Wi-Fi credentials for {}", saved.ssid);
+    if state.radio.send(RadioRequest::Saved).await.is_err() {
+        warn!("Nobody to reboot the doser, reset it by hand");
+    }
+    Ok(Json(saved))
+}
+
+async fn run_dns(ip: Ipv4Addr) -> anyhow::Result<()> {
+    let socket = UdpSocket::bind("0.0.0.0:53").await?;
+    let mut buf = [0; 512];
+    loop {
+        let (len, from) = socket.recv_from(&mut buf).await?;
+        if let Some(reply) = dns_reply(&buf[..len], ip) {
+            socket.send_to(&reply, from).await.ok();
+        }
+    }
+}
+
+/// Answers a query for an A record with `ip`, any other type of record gets
+/// an empty answer. Only plain queries with a single question are answered.
+fn dns_reply(query: &[u8], ip: Ipv4Addr) -> Option<Vec<u8>> {
+    let is_query = query.len() > 12 && query[2] & 0xf8 == 0; // QR and opcode are 0
+    if !is_query || query[4..6] != [0, 1] {
+        return None;
+    }
+
+    // The name is a list of length prefixed labels ending in 0
+    let mut end = 12;
+    loop {
+        let len = *query.get(end)? as usize;
+        end += 1;
+        match len {
+            0 => break,
+            1..=63 => end += len,
+            _ => return None, // Compression doesn't belong in a question
+        }
+    }
+    let question = query.get(12..end + 4)?; // Name, type and class
+    let is_a = question[question.len() - 4..] == [0, 1, 0, 1];
+
+    let mut reply = Vec::with_capacity(question.len() + 28);
+    reply.extend_from_slice(&query[..2]); // Same id
+    reply.extend_from_slice(&[0x84 | (query[2] & 0x01), 0x80]); // Authoritative answer, no error
+    reply.extend_from_slice(&[0, 1, 0, is_a as u8, 0, 0, 0, 0]);
+    reply.extend_from_slice(question);
+    if is_a {
+        reply.extend_from_slice(&[0xc0, 0x0c]); // Name points back at the question
+        reply.extend_from_slice(&[0, 1, 0, 1]);
+        reply.extend_from_slice(&60_u32.to_be_bytes()); // TTL
+        reply.extend_from_slice(&[0, 4]);
+        reply.extend_from_slice(&ip.octets());
+    }
+    Some(reply)
+}
+
+const PAGE: &str = r#"<!DOCTYPE html>
+<html>
+<head>
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>Nutrient doser Wi-Fi setup</title>
+<style>
+body { font-family: sans-serif; max-width: 24em; margin: 1em auto; padding: 0 1em; }
+input, button { width: 100%; padding: .5em; margin: .25em 0; box-sizing: border-box; }
+li { cursor: pointer; padding: .25em 0; }
+</style>
+</head>
+<body>
+<h2>Wi-Fi setup</h2>
+<ul id="networks"><li>Scanning...</li></ul>
+<button onclick="scan()">Scan again</button>
+<form onsubmit="save(event)">
+<input id="ssid" placeholder="Network name" required maxlength="32">
+<input id="pass" type="password" placeholder="Password, empty if open" maxlength="64">
+<button>Save and reboot</button>
+</form>
+<p id="msg"></p>
+<script>
+const msg = t => document.getElementById("msg").textContent = t;
+async function scan() {
+  const list = document.getElementById("networks");
+  const resp = await fetch("/wifi/scan");
+  const body = await resp.json();
+  if (!resp.ok) { list.innerHTML = ""; return msg(body.message); }
+  list.innerHTML = "";
+  for (const n of body) {
+    const li = document.createElement("li");
+    li.textContent = `${n.ssid} (${n.rssi} dBm${n.secured ? ", secured" : ""})`;
+    li.onclick = () => document.getElementById("ssid").value = n.ssid;
+    list.appendChild(li);
+  }
+}
+async function save(e) {
+  e.preventDefault();
+  const resp = await fetch("/wifi", {
+    method: "POST",
+    headers: { "content-type": "application/json" },
+    body: JSON.stringify({ ssid: ssid.value, pass: pass.value }),
+  });
+  msg(resp.ok ? "Saved, rebooting to join the network..." : (await resp.json()).message);
+}
+scan();
+</script>
+</body>
+</html>
+"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IP: Ipv4Addr = Ipv4Addr::new(192, 168, 71, 1);
+
+    /// Query with one question for `name` of record type `qtype`
+    fn query(name: &str, qtype: u16) -> Vec<u8> {
+        let mut q = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]; // Recursion desired
+        for label in name.split('.') {
+            q.push(label.len() as u8);
+            q.extend_from_slice(label.as_bytes());
+        }
+        q.push(0);
+        q.extend_from_slice(&qtype.to_be_bytes());
+        q.extend_from_slice(&[0, 1]); // IN
+        q
+    }
+
+    #[test]
+    fn answers_a_queries() {
+        let q = query("connectivitycheck.gstatic.com", 1);
+        let reply = dns_reply(&q, IP).unwrap();
+        assert_eq!(reply[..2], [0x12, 0x34]);
+        assert_eq!(reply[2..4], [0x85, 0x80]);
+        assert_eq!(reply[4..12], [0, 1, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(reply[12..q.len()], q[12..]);
+        let answer = &reply[q.len()..];
+        assert_eq!(answer[..6], [0xc0, 0x0c, 0, 1, 0, 1]);
+        assert_eq!(answer[10..], [0, 4, 192, 168, 71, 1]);
+    }
+
+    #[test]
+    fn other_records_get_no_answer() {
+        let q = query("doser.local", 28); // AAAA
+        let reply = dns_reply(&q, IP).unwrap();
+        assert_eq!(reply[4..12], [0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(reply.len(), q.len());
+        assert_eq!(reply[12..], q[12..]);
+    }
+
+    #[test]
+    fn ignores_what_it_cant_answer() {
+        // Compressed name
+        let mut q = query("doser.local", 1);
+        q.truncate(12);
+        q.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1]);
+        assert_eq!(dns_reply(&q, IP), None);
+
+        // Two questions
+        let mut q = query("doser.local", 1);
+        q[5] = 2;
+        q.extend_from_within(12..);
+        assert_eq!(dns_reply(&q, IP), None);
+
+        // Not a query
+        let mut q = query("doser.local", 1);
+        q[2] |= 0x80;
+        assert_eq!(dns_reply(&q, IP), None);
+        let mut q = query("doser.local", 1);
+        q[2] |= 0x10; // Opcode 2, status
+        assert_eq!(dns_reply(&q, IP), None);
+    }
+
+    #[test]
+    fn ignores_truncated_packets() {
+        let q = query("doser.local", 1);
+        for len in 0..q.len() {
+            assert_eq!(dns_reply(&q[..len], IP), None, "{len} bytes");
+        }
+        assert!(dns_reply(&q, IP).is_some());
+
+        // A label running past the end
+        let mut q = query("doser.local", 1);
+        q[12] = 63;
+        assert_eq!(dns_reply(&q, IP), None);
+    }
+
+    fn network(ssid: &str, rssi: i8) -> Network {
+        Network {
+            ssid: ssid.to_owned(),
+            rssi,
+            secured: true,
+        }
+    }
+
+    #[test]
+    fn keeps_the_strongest_of_each_network() {
+        let seen = [
+            network("Shed", -80),
+            network("Greenhouse", -70),
+            network("", -30),
+            network("Shed", -50),
+            network("Greenhouse", -75),
+            network("Neighbour", -90),
+        ];
+        let networks: Vec<_> = dedup_networks(seen).into_iter().map(|n| (n.ssid, n.rssi)).collect();
+        assert_eq!(
+            networks,
+            [("Shed".to_owned(), -50), ("Greenhouse".to_owned(), -70), ("Neighbour".to_owned(), -90)]
+        );
+    }
+
+    #[test]
+    fn validates_creds() {
+        let creds = |ssid: &str, pass: &str| WifiCreds {
+            ssid: ssid.to_owned(),
+            pass: pass.to_owned(),
+        };
+        assert!(creds("Greenhouse", "").validate().is_ok());
+        assert!(creds("Greenhouse", "hunter22").validate().is_ok());
+        assert!(creds(&"x".repeat(32), &"x".repeat(64)).validate().is_ok());
+        assert!(creds("", "hunter22").validate().is_err());
+        assert!(creds(&"x".repeat(33), "hunter22").validate().is_err());
+        assert!(creds("Greenhouse", "hunter2").validate().is_err());
+        assert!(creds("Greenhouse", &"x".repeat(65)).validate().is_err());
+    }
+}
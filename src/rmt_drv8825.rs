@@ -10,30 +10,28 @@ use std::{
     sync::{Arc, Mutex},
     time::Duration,
 };
-use stepgen::Stepgen;
 
-const MAX_STEP_FREQ: Hertz = Hertz(250000);
+use crate::stepper::{motion_profile, pulse_train, steps_to_position, MotionCtl, MotionProfile, Stepper};
+
 const STEP_PULSE: Duration = Duration::from_micros(2);
 const DIR_SETUP: Duration = Duration::from_nanos(650);
 const EN_SETUP: Duration = Duration::from_nanos(650);
 
-// TODO: make this configurable for different motor models
-const MAX_RPM: f64 = 400.0;
-const MAX_ACCEL: f64 = 400.0;
-
-#[derive(Copy, Clone, Debug)]
-pub enum MicroSteps {
-    M1 = 1,
-    M2 = 2,
-    M4 = 4,
-    M8 = 8,
-    M16 = 16,
-    M32 = 32,
+#[derive(Debug)]
+pub enum DriverError {
+    Esp(EspError),
+    Stepgen(stepgen::Error), // Profile stepgen can't generate
 }
 
-impl MicroSteps {
-    fn scale(&self, val: f64) -> f64 {
-        val * (*self as u32) as f64
+impl From<EspError> for DriverError {
+    fn from(e: EspError) -> Self {
+        DriverError::Esp(e)
+    }
+}
+
+impl From<stepgen::Error> for DriverError {
+    fn from(e: stepgen::Error) -> Self {
+        DriverError::Stepgen(e)
     }
 }
 
@@ -42,8 +40,8 @@ pub struct DRV8825 {
     pin_dir: PinDriver<'static, AnyOutputPin, Output>,
     tx: Arc<Mutex<TxRmtDriver<'static>>>,
     clock: Hertz,
-    position: i32,
-    microsteps: MicroSteps,
+    position: i64, // see USTEPS_PER_STEP
+    profile: MotionProfile,
 }
 
 impl DRV8825 {
@@ -51,7 +49,6 @@ impl DRV8825 {
         pin_en: AnyOutputPin,
         pin_dir: AnyOutputPin,
         tx: Arc<Mutex<TxRmtDriver<'static>>>,
-        microsteps: MicroSteps,
     ) -> Result<Self, EspError> {
         let clock = tx.lock().unwrap().counter_clock()?;
         let mut en = PinDriver::output(pin_en)?;
@@ -64,34 +61,41 @@ impl DRV8825 {
             tx,
             clock,
             position: 0,
-            microsteps,
+            profile: MotionProfile::default(),
         })
     }
 
-    pub fn id(&self) -> u32 {
-        self.pin_en.pin() as u32
-    }
-
-    fn gen_steps(&self, steps: f64) -> Result<impl Iterator<Item = Symbol>, EspError> {
+    fn gen_steps(&self, steps: f64, ctl: &MotionCtl) -> Result<impl Iterator<Item = Symbol>, DriverError> {
         let one_step_ticks = PulseTicks::new_with_duration(self.clock, &STEP_PULSE)?;
 
-        let mut sg = Stepgen::new(self.clock.0);
-        sg.set_acceleration((self.microsteps.scale(MAX_ACCEL) as u32) << 8).unwrap();
-        sg.set_target_speed((self.microsteps.scale(MAX_RPM / 60.0 * 200.0) as u32) << 8).unwrap();
-        sg.set_target_step(self.microsteps.scale(steps) as u32).unwrap();
+        // Symbols are pulled lazily by the RMT driver, which only buffers a
+        // handful ahead, so a cancelled motion starts ramping down right away
+        let sg = motion_profile(self.clock.0, &self.profile, steps, ctl)?;
 
         // The generated delays are ticks between the rising edges of two pulses,
-        // need to make sure the length of the high pulse is subtracted from the
-        // low pulse when converting these to signals
-        Ok(sg.map(move |delay| {
-            Symbol::new(
-                Pulse::new(PinState::High, one_step_ticks),
-                Pulse::new(PinState::Low, PulseTicks::new((delay >> 8) as u16 - one_step_ticks.ticks()).expect("gen_low_pulse")),
-            )
+        // the low part gets spread over as many symbols as it needs
+        Ok(pulse_train(sg, one_step_ticks.ticks()).map(|[(l0, t0), (l1, t1)]| {
+            Symbol::new(to_pulse(l0, t0), to_pulse(l1, t1))
         }))
     }
+}
+
+fn to_pulse(high: bool, ticks: u16) -> Pulse {
+    let level = match high {
+        true => PinState::High,
+        false => PinState::Low,
+    };
+    Pulse::new(level, PulseTicks::new(ticks).expect("pulse_train keeps pulses in range"))
+}
+
+impl Stepper for DRV8825 {
+    type Error = DriverError;
+
+    fn id(&self) -> u32 {
+        self.pin_en.pin() as u32
+    }
 
-    pub async fn step_by(&mut self, steps: f64) -> Result<(), EspError> {
+    async fn move_by(&mut self, steps: f64, ctl: &MotionCtl) -> Result<f64, DriverError> {
         // Setup, then wait 650ns
         self.pin_dir.set_level(match steps {
             ..=0.0 => Level::Low,
@@ -101,12 +105,12 @@ impl DRV8825 {
         tokio::time::sleep(EN_SETUP).await;
 
         // Generate and send pulses to the stepper motor
-        let res = match self.gen_steps(steps.abs()) {
+        let res = match self.gen_steps(steps.abs(), ctl) {
             Ok(syms) => {
                 let _tx = Arc::clone(&self.tx);
                 tokio::task::spawn_blocking(move || {
                     _tx.lock().unwrap().start_iter_blocking(syms)
-                }).await.expect("Failed to send pulses")
+                }).await.expect("Failed to send pulses").map_err(DriverError::from)
             }
             Err(e) => Err(e),
         };
@@ -114,19 +118,26 @@ impl DRV8825 {
         // Done, de-energize coils
         self.pin_en.set_high()?;
 
-        self.position = self.position.saturating_add(steps.round() as i32);
-        res
+        let moved = ctl.steps_done().copysign(steps);
+        self.position = self.position.saturating_add(steps_to_position(moved));
+        res.map(|_| moved)
     }
 
-    pub async fn goto(&mut self, target_pos: i32) -> Result<(), EspError> {
-        self.step_by(target_pos.saturating_sub(self.position) as f64).await
+    fn get_position(&self) -> i64 {
+        self.position
     }
 
-    pub fn get_position(&self) -> i32 {
-        self.position
+    fn set_position(&mut self, position: i64) {
+        self.position = position;
+    }
+
+    fn profile(&self) -> &MotionProfile {
+        &self.profile
     }
 
-    pub fn reset_position(&mut self) {
-        self.position = 0;
+    fn set_profile(&mut self, profile: MotionProfile) -> Result<(), String> {
+        profile.validate(self.clock.0)?;
+        self.profile = profile;
+        Ok(())
     }
 }
@@ -0,0 +1,256 @@
+use std::{future::Future, time::Duration};
+
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use tokio::time::{sleep, Instant};
+
+// Longest wait for a single conversion, the HX711 is at 10Hz at its slowest
+const READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Raw readings of a load cell amplifier, so the weighing logic in [`Scale`]
+/// doesn't depend on the GPIO types of a specific driver
+pub trait LoadCell: Send {
+    /// Latest conversion in ADC counts, `None` while the next one isn't ready
+    fn read_raw(&mut self) -> anyhow::Result<Option<i32>>;
+}
+
+/// Maps raw counts to grams, `counts_per_gram` is 0 until a known weight
+/// has been put on the scale
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug)]
+#[serde(default)]
+pub struct ScaleCal {
+    pub tare: f64, // counts with the empty cup on it
+    pub counts_per_gram: f64,
+}
+
+impl ScaleCal {
+    pub fn is_calibrated(&self) -> bool {
+        self.counts_per_gram.is_finite() && self.counts_per_gram != 0.0
+    }
+}
+
+/// When a reading counts as settled
+#[derive(Clone, Copy, Debug)]
+pub struct Settle {
+    pub samples: usize, // readings averaged together
+    pub tolerance_g: f64, // largest spread within those readings
+    pub timeout: Duration,
+    pub poll: Duration, // how often to check whether a conversion is ready
+}
+
+impl Default for Settle {
+    fn default() -> Self {
+        // The HX711 converts at 10Hz with RATE pulled low
+        Self {
+            samples: 10,
+            tolerance_g: 0.05,
+            timeout: Duration::from_secs(15),
+            poll: Duration::from_millis(10),
+        }
+    }
+}
+
+pub struct Scale {
+    cell: Box<dyn LoadCell>,
+    pub cal: ScaleCal,
+    pub settle: Settle,
+}
+
+impl Scale {
+    pub fn new(cell: Box<dyn LoadCell>) -> Self {
+        Self {
+            cell,
+            cal: ScaleCal::default(),
+            settle: Settle::default(),
+        }
+    }
+
+    async fn read(&mut self) -> anyhow::Result<i32> {
+        let deadline = Instant::now() + READ_TIMEOUT;
+        loop {
+            if let Some(raw) = self.cell.read_raw()? {
+                return Ok(raw);
+            }
+            if Instant::now() >= deadline {
+                bail!("Scale isn't responding");
+            }
+            sleep(self.settle.poll).await;
+        }
+    }
+
+    /// Average of the first window of readings that doesn't move by more
+    /// than the tolerance. Before calibration there's no telling what the
+    /// tolerance is in counts, so the first window is taken as is.
+    async fn stable_raw(&mut self) -> anyhow::Result<f64> {
+        let deadline = Instant::now() + self.settle.timeout;
+        let tolerance = self.settle.tolerance_g * self.cal.counts_per_gram.abs();
+        let mut window = Vec::with_capacity(self.settle.samples.max(1));
+        loop {
+            window.clear();
+            while window.len() < window.capacity() {
+                window.push(self.read().await? as f64);
+            }
+            let (min, max) = window
+                .iter()
+                .fold((f64::MAX, f64::MIN), |(min, max), &r| (min.min(r), max.max(r)));
+            if !self.cal.is_calibrated() || max - min <= tolerance {
+                return Ok(window.iter().sum::<f64>() / window.len() as f64);
+            }
+            if Instant::now() >= deadline {
+                bail!("Scale didn't settle, readings kept moving by {:.2} g", (max - min) / self.cal.counts_per_gram.abs());
+            }
+        }
+    }
+
+    /// Settled weight on the scale
+    pub async fn weigh(&mut self) -> anyhow::Result<f64> {
+        if !self.cal.is_calibrated() {
+            bail!("Scale isn't calibrated");
+        }
+        Ok((self.stable_raw().await? - self.cal.tare) / self.cal.counts_per_gram)
+    }
+
+    /// Zero the scale with whatever is on it
+    pub async fn tare(&mut self) -> anyhow::Result<()> {
+        self.cal.tare = self.stable_raw().await?;
+        Ok(())
+    }
+
+    /// Work out the scale factor from a known weight put on the tared scale
+    pub async fn calibrate(&mut self, grams: f64) -> anyhow::Result<()> {
+        if !(grams.is_finite() && grams > 0.0) {
+            bail!("Reference weight must be positive");
+        }
+        let counts = self.stable_raw().await? - self.cal.tare;
+        let counts_per_gram = counts / grams;
+        // Less than a count per gram means nothing was put on the scale,
+        // a negative factor is fine, it only means the cell is mounted upside down
+        if counts_per_gram.abs() < 1.0 {
+            bail!("Scale barely moved, is the weight on it?");
+        }
+        self.cal.counts_per_gram = counts_per_gram;
+        Ok(())
+    }
+
+    /// Weighs before and after running `dispense`, returns its output and the
+    /// grams it added
+    pub async fn weigh_dispense<T>(&mut self, dispense: impl Future<Output = T>) -> anyhow::Result<(T, f64)> {
+        let before = self.weigh().await?;
+        let out = dispense.await;
+        let after = self.weigh().await?;
+        Ok((out, after - before))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+
+    const TARE: i32 = 81_250;
+    const COUNTS_PER_GRAM: f64 = -420.0;
+
+    /// Turns what's on it into counts, with a repeating bit of noise. Every
+    /// other read isn't ready, like the real chip between conversions.
+    struct FakeCell {
+        grams: Arc<Mutex<f64>>,
+        noise: [i32; 4],
+        reads: usize,
+    }
+
+    impl LoadCell for FakeCell {
+        fn read_raw(&mut self) -> anyhow::Result<Option<i32>> {
+            self.reads += 1;
+            if self.reads % 2 == 0 {
+                return Ok(None);
+            }
+            let grams = *self.grams.lock().unwrap();
+            let noise = self.noise[self.reads / 2 % self.noise.len()];
+            Ok(Some(TARE + (grams * COUNTS_PER_GRAM) as i32 + noise))
+        }
+    }
+
+    fn fake_scale(noise: [i32; 4]) -> (Scale, Arc<Mutex<f64>>) {
+        let grams = Arc::new(Mutex::new(0.0));
+        let cell = FakeCell {
+            grams: grams.clone(),
+            noise,
+            reads: 0,
+        };
+        let mut scale = Scale::new(Box::new(cell));
+        scale.settle = Settle {
+            samples: 4,
+            tolerance_g: 0.05,
+            timeout: Duration::from_millis(200),
+            poll: Duration::from_millis(1),
+        };
+        (scale, grams)
+    }
+
+    fn block_on<F: Future>(f: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap()
+            .block_on(f)
+    }
+
+    #[test]
+    fn tare_and_calibrate() {
+        let (mut scale, grams) = fake_scale([0, 3, -3, 0]);
+        block_on(async {
+            assert!(scale.weigh().await.is_err());
+            *grams.lock().unwrap() = 12.5; // the empty cup
+            scale.tare().await.unwrap();
+            *grams.lock().unwrap() += 100.0;
+            scale.calibrate(100.0).await.unwrap();
+            assert!((scale.cal.counts_per_gram - COUNTS_PER_GRAM).abs() < 0.1);
+            assert!((scale.weigh().await.unwrap() - 100.0).abs() < 0.01);
+
+            *grams.lock().unwrap() -= 100.0;
+            assert!(scale.weigh().await.unwrap().abs() < 0.01);
+        });
+    }
+
+    #[test]
+    fn calibrate_needs_a_weight() {
+        let (mut scale, _) = fake_scale([0; 4]);
+        block_on(async {
+            scale.tare().await.unwrap();
+            assert!(scale.calibrate(50.0).await.is_err());
+            assert!(scale.calibrate(-1.0).await.is_err());
+            assert!(!scale.cal.is_calibrated());
+        });
+    }
+
+    #[test]
+    fn unsettled_reading_times_out() {
+        // 0.5 g of spread, ten times the tolerance
+        let (mut scale, _) = fake_scale([0, 210, -210, 0]);
+        scale.cal = ScaleCal {
+            tare: TARE as f64,
+            counts_per_gram: COUNTS_PER_GRAM,
+        };
+        let err = block_on(scale.weigh()).unwrap_err();
+        assert!(err.to_string().contains("didn't settle"));
+    }
+
+    #[test]
+    fn weighs_a_dispense() {
+        let (mut scale, grams) = fake_scale([1, -1, 0, 0]);
+        scale.cal = ScaleCal {
+            tare: TARE as f64,
+            counts_per_gram: COUNTS_PER_GRAM,
+        };
+        *grams.lock().unwrap() = 30.0; // whatever was already in the cup
+        let pumped = grams.clone();
+        let (out, added) = block_on(scale.weigh_dispense(async move {
+            *pumped.lock().unwrap() += 5.2;
+            "done"
+        }))
+        .unwrap();
+        assert_eq!(out, "done");
+        assert!((added - 5.2).abs() < 0.01);
+    }
+}
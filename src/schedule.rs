@@ -0,0 +1,447 @@
+use axum::extract::State;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    app::{pin_motors, AppState, DispenseReq, DoseSolutionReq},
+    clock,
+    error::{ApiError, Json, Path},
+    history::{Origin, Trigger},
+    jobs::JobKind,
+    labels::MotorSel,
+    stepper::Stepper,
+};
+
+pub type ScheduleId = u32;
+
+const NVS_TAG_SCHEDULES: &str = "schedules";
+
+/// A missed run is only caught up on if it's at most this late, in seconds.
+/// Past that the next regular run is close enough anyway.
+pub const MAX_CATCH_UP: i64 = 24 * 60 * 60;
+
+/// How late a run can start and still count as on time, covers the
+/// scheduler's polling interval and short hiccups
+pub const LATE_TOLERANCE: i64 = 5 * 60;
+
+// Same order as tm_wday
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum Weekday {
+    Sun,
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+}
+
+/// What to do about runs that were missed while the device was off
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Default, Debug)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum MissedRun {
+    #[default]
+    Skip,
+    RunOnce, // Run once as soon as possible, no matter how many were missed
+}
+
+/// Runs `action` at a fixed local time of day
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Schedule<A> {
+    #[serde(default)]
+    pub id: ScheduleId,
+    pub name: String,
+    pub hour: u32,
+    pub minute: u32,
+    #[serde(default)]
+    pub days: Vec<Weekday>, // Empty runs every day
+    #[serde(default)]
+    pub missed_run: MissedRun,
+    pub action: A,
+    #[serde(default)]
+    pub last_run: i64, // Unix time this was last run or skipped
+}
+
+/// What the scheduler decided about a schedule that came due
+#[derive(PartialEq, Debug)]
+pub enum Due {
+    Run,
+    Skip,
+}
+
+impl<A> Schedule<A> {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.hour > 23 || self.minute > 59 {
+            return Err(format!("Invalid time of day {}:{:02}", self.hour, self.minute));
+        }
+        Ok(())
+    }
+
+    fn runs_on(&self, weekday: u32) -> bool {
+        self.days.is_empty() || self.days.iter().any(|d| *d as u32 == weekday)
+    }
+
+    /// Occurrences on the days around `time`, in local time
+    fn occurrences<'a>(
+        &'a self,
+        time: i64,
+        days: impl Iterator<Item = i32> + 'a,
+    ) -> impl Iterator<Item = i64> + 'a {
+        let date = clock::to_local(time);
+        days.map(move |offset| {
+            clock::from_local(date.year, date.month, date.day as i32 + offset, self.hour, self.minute)
+        })
+        .filter(|t| self.runs_on(clock::to_local(*t).weekday))
+    }
+
+    /// First run strictly after `time`
+    pub fn next_run(&self, time: i64) -> Option<i64> {
+        self.occurrences(time, 0..=7).find(|t| *t > time)
+    }
+
+    /// Latest run at or before `time`
+    pub fn prev_run(&self, time: i64) -> Option<i64> {
+        self.occurrences(time, (-7..=0).rev()).find(|t| *t <= time)
+    }
+
+    /// Whether a run came due since the last one, and if it should still go
+    /// ahead given how late it is
+    pub fn check_due(&self, now: i64) -> Option<Due> {
+        let due_at = self.next_run(self.last_run).filter(|t| *t <= now)?;
+        if now - due_at <= LATE_TOLERANCE {
+            return Some(Due::Run);
+        }
+        let latest = self.prev_run(now).unwrap_or(due_at);
+        match self.missed_run {
+            MissedRun::RunOnce if now - latest <= MAX_CATCH_UP => Some(Due::Run),
+            _ => Some(Due::Skip),
+        }
+    }
+}
+
+/// Job a schedule starts, same body as the matching endpoint
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub(crate) enum ScheduledAction {
+    Dose(DoseSolutionReq),
+    Dispense(DispenseReq),
+}
+
+impl ScheduledAction {
+    pub(crate) fn motors_mut(&mut self) -> Vec<&mut MotorSel> {
+        match self {
+            Self::Dose(req) => req.nutrients.iter_mut().map(|n| &mut n.motor).collect(),
+            Self::Dispense(req) => req.reqs.iter_mut().map(|r| &mut r.motor).collect(),
+        }
+    }
+}
+
+pub(crate) type DoseSchedule = Schedule<ScheduledAction>;
+
+#[derive(Serialize)]
+pub(crate) struct ScheduleStatus {
+    #[serde(flatten)]
+    schedule: DoseSchedule,
+    next_run: Option<NextRun>,
+}
+
+pub(crate) async fn list_schedules<S: Stepper>(State(state): State<AppState<S>>) -> Json<Vec<ScheduleStatus>> {
+    let now = clock::now();
+    Json(
+        state
+            .schedules
+            .lock()
+            .await
+            .iter()
+            .map(|s| ScheduleStatus {
+                schedule: s.clone(),
+                next_run: now.and_then(|now| s.next_run(now)).map(|at| NextRun::new(s.id, &s.name, at)),
+            })
+            .collect(),
+    )
+}
+
+#[derive(Serialize)]
+pub(crate) struct ScheduleCreated {
+    id: ScheduleId,
+}
+
+pub(crate) async fn create_schedule<S: Stepper>(
+    State(state): State<AppState<S>>,
+    Json(mut req): Json<DoseSchedule>,
+) -> Result<Json<ScheduleCreated>, ApiError> {
+    req.validate().map_err(ApiError::invalid)?;
+    match &req.action {
+        ScheduledAction::Dispense(dispense) => {
+            state.dispense_entries(dispense).await?;
+        }
+        ScheduledAction::Dose(DoseSolutionReq { recipe: Some(name), .. }) => {
+            if !state.recipes.read().await.iter().any(|r| &r.name == name) {
+                return Err(ApiError::not_found(format!("No recipe named {name:?}")));
+            }
+        }
+        _ => {}
+    }
+    pin_motors(req.action.motors_mut().into_iter(), &state.labels.read().await)?;
+    // Runs are counted from the last one, so a schedule needs a clock to start from
+    let Some(now) = clock::now() else {
+        return Err(ApiError::no_clock());
+    };
+
+    let mut schedules = state.schedules.lock().await;
+    req.id = schedules.iter().map(|s| s.id).max().unwrap_or(0) + 1;
+    req.last_run = now;
+    info!("Adding schedule {} ({})", req.id, req.name);
+    let id = req.id;
+    let mut updated = schedules.clone();
+    updated.push(req);
+    state.save_schedules(&updated).await?;
+    *schedules = updated;
+    Ok(Json(ScheduleCreated { id }))
+}
+
+pub(crate) async fn delete_schedule<S: Stepper>(
+    State(state): State<AppState<S>>,
+    Path(id): Path<ScheduleId>,
+) -> Result<Json<DoseSchedule>, ApiError> {
+    let mut schedules = state.schedules.lock().await;
+    let Some(pos) = schedules.iter().position(|s| s.id == id) else {
+        return Err(ApiError::not_found(format!("No schedule {id}")));
+    };
+    info!("Removing schedule {id} ({})", schedules[pos].name);
+    let mut updated = schedules.clone();
+    let removed = updated.remove(pos);
+    state.save_schedules(&updated).await?;
+    *schedules = updated;
+    Ok(Json(removed))
+}
+
+#[derive(Serialize)]
+pub(crate) struct NextRun {
+    id: ScheduleId,
+    name: String,
+    pub(crate) at: i64, // Unix time
+    at_local: String,
+}
+
+impl NextRun {
+    pub(crate) fn new(id: ScheduleId, name: &str, at: i64) -> Self {
+        Self {
+            id,
+            name: name.to_owned(),
+            at,
+            at_local: clock::to_local(at).to_string(),
+        }
+    }
+}
+
+impl<S: Stepper> AppState<S> {
+    pub(crate) async fn load_schedules(&self) {
+        if let Some(schedules) = self.load(NVS_TAG_SCHEDULES).await {
+            *self.schedules.lock().await = schedules;
+        }
+    }
+
+    /// Persist the schedules and wake the scheduler so it picks up changes
+    pub(crate) async fn save_schedules(&self, schedules: &[DoseSchedule]) -> Result<(), ApiError> {
+        let res = self.store(NVS_TAG_SCHEDULES, schedules).await;
+        self.schedule_tx.try_send(()).ok();
+        res
+    }
+
+    /// Start the jobs of every schedule that came due, missed runs are
+    /// handled according to each schedule's policy
+    pub(crate) async fn run_due_schedules(&self, now: i64) {
+        let mut due = Vec::new();
+        {
+            let mut schedules = self.schedules.lock().await;
+            let mut changed = false;
+            for schedule in schedules.iter_mut() {
+                match schedule.check_due(now) {
+                    Some(Due::Run) => due.push((schedule.name.clone(), schedule.action.clone())),
+                    Some(Due::Skip) => warn!("Skipping missed run of schedule {}", schedule.name),
+                    None => continue,
+                }
+                schedule.last_run = now;
+                changed = true;
+            }
+            if changed {
+                self.save_schedules(&schedules).await.ok();
+            }
+        }
+
+        for (name, action) in due {
+            if self.is_stopped() {
+                warn!("Not running schedule {name} during an emergency stop");
+                continue;
+            }
+            let origin = |recipe| Origin {
+                trigger: Trigger::Schedule,
+                schedule: Some(name.clone()),
+                recipe,
+            };
+            let id = match action {
+                ScheduledAction::Dose(req) => match self.dose_entries(&req).await {
+                    Ok((entries, recipe)) => self.start_job(JobKind::Dose, entries, origin(recipe)).await,
+                    Err(e) => {
+                        error!("Schedule {name} can't dose: {e}");
+                        continue;
+                    }
+                },
+                ScheduledAction::Dispense(req) => {
+                    match self.dispense_entries(&req).await {
+                        Ok(entries) if self.check_levels(&entries).await.is_ok() => {
+                            self.start_job(JobKind::Dispense, entries, origin(None)).await
+                        }
+                        Ok(_) => {
+                            error!("Schedule {name} can't dispense, not enough left in the bottles");
+                            continue;
+                        }
+                        Err(e) => {
+                            error!("Schedule {name} can't dispense: {e}");
+                            continue;
+                        }
+                    }
+                }
+            };
+            info!("Schedule {name} started job {id}");
+        }
+    }
+
+    /// The soonest upcoming run across all schedules
+    pub(crate) async fn next_schedule(&self, now: i64) -> Option<NextRun> {
+        self.schedules
+            .lock()
+            .await
+            .iter()
+            .filter_map(|s| s.next_run(now).map(|at| NextRun::new(s.id, &s.name, at)))
+            .min_by_key(|next| next.at)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: i64 = 60 * 60;
+    const DAY: i64 = 24 * HOUR;
+
+    // DST starts 2025-03-09 02:00 and ends 2025-11-02 02:00
+    fn eastern() {
+        clock::set_timezone("EST5EDT,M3.2.0,M11.1.0").unwrap();
+    }
+
+    fn local(month: u32, day: i32, hour: u32, minute: u32) -> i64 {
+        clock::from_local(2025, month, day, hour, minute)
+    }
+
+    fn schedule(hour: u32, minute: u32, days: &[Weekday], missed_run: MissedRun) -> Schedule<()> {
+        Schedule {
+            id: 1,
+            name: "Feed".to_owned(),
+            hour,
+            minute,
+            days: days.to_vec(),
+            missed_run,
+            action: (),
+            last_run: 0,
+        }
+    }
+
+    #[test]
+    fn runs_at_local_time() {
+        eastern();
+        let s = schedule(7, 30, &[], MissedRun::Skip);
+        let jan = s.next_run(local(1, 10, 12, 0)).unwrap();
+        assert_eq!(jan, local(1, 11, 7, 30));
+        assert_eq!(jan % DAY, 12 * HOUR + 30 * 60, "7:30 EST is 12:30 UTC");
+        let jul = s.next_run(local(7, 10, 12, 0)).unwrap();
+        assert_eq!(jul % DAY, 11 * HOUR + 30 * 60, "7:30 EDT is 11:30 UTC");
+
+        // Exactly at a run is the previous run, not the next one
+        assert_eq!(s.next_run(jan), Some(jan + DAY));
+        assert_eq!(s.prev_run(jan), Some(jan));
+        assert_eq!(s.prev_run(jan - 1), Some(jan - DAY));
+    }
+
+    #[test]
+    fn weekday_filter() {
+        eastern();
+        // 2025-01-04 is a Saturday
+        let s = schedule(7, 0, &[Weekday::Mon, Weekday::Wed], MissedRun::Skip);
+        assert_eq!(s.next_run(local(1, 4, 12, 0)), Some(local(1, 6, 7, 0)));
+        assert_eq!(s.next_run(local(1, 6, 7, 0)), Some(local(1, 8, 7, 0)));
+        assert_eq!(s.next_run(local(1, 8, 7, 0)), Some(local(1, 13, 7, 0)));
+        assert_eq!(s.prev_run(local(1, 7, 12, 0)), Some(local(1, 6, 7, 0)));
+        assert_eq!(s.prev_run(local(1, 5, 12, 0)), Some(local(1, 1, 7, 0)));
+
+        let weekly = schedule(20, 0, &[Weekday::Sun], MissedRun::Skip);
+        assert_eq!(weekly.next_run(local(1, 5, 21, 0)), Some(local(1, 12, 20, 0)));
+        assert_eq!(weekly.prev_run(local(1, 5, 19, 0)), Some(clock::from_local(2024, 12, 29, 20, 0)));
+    }
+
+    #[test]
+    fn dst_gap() {
+        eastern();
+        // 2:30 doesn't exist on 2025-03-09, it still runs once that day
+        let s = schedule(2, 30, &[], MissedRun::Skip);
+        let before = local(3, 8, 12, 0);
+        let run = s.next_run(before).unwrap();
+        assert!(run > local(3, 9, 0, 0) && run < local(3, 9, 5, 0), "{}", clock::to_local(run));
+        let after = s.next_run(run).unwrap();
+        assert_eq!(after, local(3, 10, 2, 30));
+        assert_eq!(s.prev_run(after - 1), Some(run));
+    }
+
+    #[test]
+    fn dst_overlap() {
+        eastern();
+        // 1:30 happens twice on 2025-11-02, it only runs once
+        let s = schedule(1, 30, &[], MissedRun::Skip);
+        let run = s.next_run(local(11, 1, 12, 0)).unwrap();
+        assert_eq!(clock::to_local(run).day, 2);
+        let next = s.next_run(run).unwrap();
+        assert_eq!(clock::to_local(next).day, 3);
+        assert_eq!(next, local(11, 3, 1, 30));
+
+        // The days around the change are still 24 hours apart at the same wall time
+        let s = schedule(9, 0, &[], MissedRun::Skip);
+        let sat = s.next_run(local(11, 1, 0, 0)).unwrap();
+        assert_eq!(s.next_run(sat), Some(sat + DAY + HOUR));
+    }
+
+    #[test]
+    fn due_within_tolerance() {
+        eastern();
+        let mut s = schedule(7, 0, &[], MissedRun::Skip);
+        s.last_run = local(1, 9, 7, 0);
+        let due = local(1, 10, 7, 0);
+        assert_eq!(s.check_due(due - 1), None);
+        assert_eq!(s.check_due(due), Some(Due::Run));
+        assert_eq!(s.check_due(due + LATE_TOLERANCE), Some(Due::Run));
+        assert_eq!(s.check_due(due + LATE_TOLERANCE + 1), Some(Due::Skip));
+
+        s.last_run = due;
+        assert_eq!(s.check_due(due + HOUR), None);
+    }
+
+    #[test]
+    fn missed_runs() {
+        eastern();
+        // Off for three days, back two hours after today's run
+        let now = local(1, 10, 9, 0);
+        let mut s = schedule(7, 0, &[], MissedRun::RunOnce);
+        s.last_run = local(1, 7, 7, 0);
+        assert_eq!(s.check_due(now), Some(Due::Run));
+        s.missed_run = MissedRun::Skip;
+        assert_eq!(s.check_due(now), Some(Due::Skip));
+
+        // Back a day and two hours after the only run it missed, too late to catch up
+        let mut s = schedule(7, 0, &[Weekday::Mon], MissedRun::RunOnce);
+        s.last_run = local(1, 6, 7, 0);
+        assert_eq!(s.check_due(local(1, 13, 6, 59)), None);
+        assert_eq!(s.check_due(local(1, 13, 7, 0) + MAX_CATCH_UP), Some(Due::Run));
+        assert_eq!(s.check_due(local(1, 13, 7, 0) + MAX_CATCH_UP + 1), Some(Due::Skip));
+    }
+}
@@ -0,0 +1,107 @@
+use std::time::{Duration, Instant};
+
+use crate::stepper::{motion_profile, steps_to_position, MotionCtl, MotionProfile, Stepper};
+
+// Same counter clock the RMT driver runs at on the device (80MHz / 40)
+const CLOCK_HZ: u32 = 2_000_000;
+
+// How often a simulated motion wakes up to report progress and check for cancellation
+const TICK: Duration = Duration::from_millis(50);
+
+/// A single motion commanded to a [`SimStepper`]
+#[derive(Clone, Debug)]
+pub struct SimMove {
+    pub steps: f64,
+    pub started: Instant,
+    pub duration: Duration,
+}
+
+/// Stepper backend with no hardware behind it, it only keeps track of the
+/// position and records every motion it was asked to do. Each motion takes
+/// as long as the same move would on a real motor.
+pub struct SimStepper {
+    id: u32,
+    position: i64, // see USTEPS_PER_STEP
+    profile: MotionProfile,
+    moves: Vec<SimMove>,
+}
+
+impl SimStepper {
+    pub fn new(id: u32) -> Self {
+        Self {
+            id,
+            position: 0,
+            profile: MotionProfile::default(),
+            moves: Vec::new(),
+        }
+    }
+
+    pub fn moves(&self) -> &[SimMove] {
+        &self.moves
+    }
+
+    /// Sum of all commanded steps, ignoring direction
+    pub fn total_steps(&self) -> f64 {
+        self.moves.iter().map(|m| m.steps.abs()).sum()
+    }
+
+    /// How long the pulse train for a move would take, summed from the same
+    /// acceleration profile the hardware driver generates
+    pub fn move_duration(&self, steps: f64) -> Result<Duration, stepgen::Error> {
+        let ticks: u64 = motion_profile(CLOCK_HZ, &self.profile, steps.abs(), &MotionCtl::default())?
+            .map(|delay| (delay >> 8) as u64)
+            .sum();
+        Ok(Duration::from_secs_f64(ticks as f64 / CLOCK_HZ as f64))
+    }
+}
+
+impl Stepper for SimStepper {
+    type Error = stepgen::Error;
+
+    fn id(&self) -> u32 {
+        self.id
+    }
+
+    async fn move_by(&mut self, steps: f64, ctl: &MotionCtl) -> Result<f64, Self::Error> {
+        let started = Instant::now();
+        let tick_ticks = TICK.as_secs_f64() * CLOCK_HZ as f64;
+
+        // Walk the profile in real time, one tick at a time
+        let mut pending_ticks = 0.0;
+        for delay in motion_profile(CLOCK_HZ, &self.profile, steps.abs(), ctl)? {
+            pending_ticks += (delay >> 8) as f64;
+            if pending_ticks >= tick_ticks {
+                tokio::time::sleep(Duration::from_secs_f64(pending_ticks / CLOCK_HZ as f64)).await;
+                pending_ticks = 0.0;
+            }
+        }
+        tokio::time::sleep(Duration::from_secs_f64(pending_ticks / CLOCK_HZ as f64)).await;
+
+        let moved = ctl.steps_done().copysign(steps);
+        self.position = self.position.saturating_add(steps_to_position(moved));
+        self.moves.push(SimMove {
+            steps: moved,
+            started,
+            duration: started.elapsed(),
+        });
+        Ok(moved)
+    }
+
+    fn get_position(&self) -> i64 {
+        self.position
+    }
+
+    fn set_position(&mut self, position: i64) {
+        self.position = position;
+    }
+
+    fn profile(&self) -> &MotionProfile {
+        &self.profile
+    }
+
+    fn set_profile(&mut self, profile: MotionProfile) -> Result<(), String> {
+        profile.validate(CLOCK_HZ)?;
+        self.profile = profile;
+        Ok(())
+    }
+}
@@ -0,0 +1,439 @@
+use std::{
+    fmt::Debug,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use serde::{Deserialize, Serialize};
+use stepgen::Stepgen;
+
+/// Fastest the DRV8825 accepts pulses on its STEP input
+pub const MAX_STEP_FREQ: u32 = 250_000;
+
+/// RMT symbol durations are 15 bits wide, so no single pulse can be longer
+pub const MAX_PULSE_TICKS: u32 = 32_767;
+
+/// stepgen works with 24.8 fixed point delays in a u32
+const MAX_STEPGEN_TICKS: u32 = (1 << 24) - 1;
+
+/// Same goes for the acceleration, in pulses/s^2
+const MAX_STEPGEN_ACCEL: u32 = (1 << 24) - 1;
+
+/// Positions are counted in microsteps of the finest mode, so they stay exact
+/// whichever mode a motor runs in
+pub const USTEPS_PER_STEP: i64 = MicroSteps::M32 as i64;
+
+/// Hardware-independent RMT symbol, two pulses of (pin high, ticks)
+pub type PulsePair = [(bool, u16); 2];
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "u32", into = "u32")]
+pub enum MicroSteps {
+    M1 = 1,
+    M2 = 2,
+    M4 = 4,
+    M8 = 8,
+    M16 = 16,
+    M32 = 32,
+}
+
+impl MicroSteps {
+    pub fn scale(&self, val: f64) -> f64 {
+        val * (*self as u32) as f64
+    }
+
+    /// Round a number of full steps down to whole microsteps, the smallest
+    /// move the driver can make in this mode
+    pub fn quantize(&self, steps: f64) -> f64 {
+        self.scale(steps).floor() / self.scale(1.0)
+    }
+}
+
+impl TryFrom<u32> for MicroSteps {
+    type Error = String;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(MicroSteps::M1),
+            2 => Ok(MicroSteps::M2),
+            4 => Ok(MicroSteps::M4),
+            8 => Ok(MicroSteps::M8),
+            16 => Ok(MicroSteps::M16),
+            32 => Ok(MicroSteps::M32),
+            _ => Err(format!("Invalid microstep mode: {value}")),
+        }
+    }
+}
+
+impl From<MicroSteps> for u32 {
+    fn from(value: MicroSteps) -> Self {
+        value as u32
+    }
+}
+
+/// Speed and acceleration limits of a single motor. The microstep mode has to
+/// match how the driver's MODE pins are wired, it only changes how many pulses
+/// are sent per full step.
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MotionProfile {
+    pub max_rpm: f64,
+    pub max_accel: f64, // full steps/s^2
+    pub microsteps: MicroSteps,
+    pub steps_per_rev: u32,
+}
+
+impl Default for MotionProfile {
+    fn default() -> Self {
+        Self {
+            max_rpm: 400.0,
+            max_accel: 400.0,
+            microsteps: MicroSteps::M32,
+            steps_per_rev: 200,
+        }
+    }
+}
+
+impl MotionProfile {
+    /// Top speed in pulses per second
+    fn pulse_freq(&self) -> f64 {
+        self.microsteps.scale(self.max_rpm / 60.0 * self.steps_per_rev as f64)
+    }
+
+    /// Acceleration in pulses per second^2
+    fn pulse_accel(&self) -> f64 {
+        self.microsteps.scale(self.max_accel)
+    }
+
+    /// Check that stepgen can generate every step of this profile with a clock
+    /// of `clock_hz`, and that the driver can keep up with it
+    pub fn validate(&self, clock_hz: u32) -> Result<(), String> {
+        if !(self.max_rpm.is_finite() && self.max_rpm > 0.0) {
+            return Err(format!("max_rpm must be positive, got {}", self.max_rpm));
+        }
+        if !(self.max_accel.is_finite() && self.max_accel > 0.0) {
+            return Err(format!("max_accel must be positive, got {}", self.max_accel));
+        }
+        if self.steps_per_rev == 0 {
+            return Err("steps_per_rev must be positive".to_owned());
+        }
+        // Shifted into 24.8 fixed point for stepgen, which has to fit a u32
+        let accel = self.pulse_accel();
+        if accel > MAX_STEPGEN_ACCEL as f64 {
+            return Err(format!(
+                "Acceleration of {accel:.0} pulses/s^2 is above the {MAX_STEPGEN_ACCEL} stepgen supports"
+            ));
+        }
+
+        let freq = self.pulse_freq();
+        if freq > MAX_STEP_FREQ as f64 {
+            return Err(format!(
+                "Step rate of {freq:.0}Hz is above the driver's limit of {MAX_STEP_FREQ}Hz"
+            ));
+        }
+
+        // The slowest pulses are either the cruising speed, or the very first
+        // step stepgen emits when starting from a standstill
+        let cruise_ticks = clock_hz as f64 / freq;
+        let first_ticks = 0.676 * clock_hz as f64 * (2.0 / self.pulse_accel()).sqrt();
+        let slowest = cruise_ticks.max(first_ticks);
+        if slowest > MAX_STEPGEN_TICKS as f64 {
+            return Err(format!(
+                "Slowest step takes {slowest:.0} ticks, more than the {MAX_STEPGEN_TICKS} stepgen supports"
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Step delays for a move of `steps` full steps, in 24.8 fixed point ticks of
+/// `clock_hz`. Progress is reported through `ctl`, and once it's cancelled the
+/// motor ramps down at the usual acceleration instead of stopping dead.
+/// Fails if stepgen refuses the profile, which [`MotionProfile::validate`] should have caught.
+pub fn motion_profile(
+    clock_hz: u32,
+    profile: &MotionProfile,
+    steps: f64,
+    ctl: &MotionCtl,
+) -> Result<impl Iterator<Item = u32> + Send + 'static, stepgen::Error> {
+    let mut sg = Stepgen::new(clock_hz);
+    sg.set_acceleration((profile.pulse_accel() as u32).min(MAX_STEPGEN_ACCEL) << 8)?;
+    sg.set_target_speed((profile.pulse_freq() as u32) << 8)?;
+    // Rounded rather than truncated, callers pass whole microsteps that might
+    // come out a hair below the integer after scaling
+    sg.set_target_step(profile.microsteps.scale(steps).round() as u32)?;
+
+    let ctl = ctl.clone();
+    let microsteps = profile.microsteps.scale(1.0);
+    let mut pulses = 0_u32;
+    let mut stopping = false;
+    ctl.set_steps_done(0.0);
+    Ok(std::iter::from_fn(move || {
+        if !stopping && ctl.is_cancelled() {
+            // A target behind the current step makes stepgen decelerate to a stop
+            stopping = true;
+            sg.set_target_step(0).ok();
+        }
+
+        let delay = sg.next()?;
+        pulses += 1;
+        ctl.set_steps_done(pulses as f64 / microsteps);
+        Some(delay)
+    }))
+}
+
+/// Turn step delays from [`motion_profile`] into RMT symbols, each step being a
+/// `high_ticks` long pulse followed by a low period that fills up the delay.
+/// Low periods longer than a single pulse can hold are spread over extra
+/// all-low symbols, so arbitrarily slow speeds still come out right.
+pub fn pulse_train(
+    delays: impl Iterator<Item = u32>,
+    high_ticks: u16,
+) -> impl Iterator<Item = PulsePair> {
+    // Carry the fractional ticks over so rounding doesn't add up across steps
+    let mut frac = 0_u64;
+    delays.flat_map(move |delay| {
+        let fixed = delay as u64 + frac;
+        frac = fixed & 0xff;
+        step_symbols((fixed >> 8) as u32, high_ticks)
+    })
+}
+
+fn step_symbols(period: u32, high_ticks: u16) -> impl Iterator<Item = PulsePair> {
+    // A zero length pulse ends the transmission, so never emit one
+    let low = period.saturating_sub(high_ticks as u32).max(1);
+
+    // The first symbol holds the step pulse and one low pulse and every extra
+    // symbol two low pulses, so the low time is split into an odd count
+    let mut count = low.div_ceil(MAX_PULSE_TICKS);
+    if count % 2 == 0 {
+        count += 1;
+    }
+    let (each, extra) = (low / count, low % count);
+    let low_at = move |i: u32| (each + (i < extra) as u32) as u16;
+
+    (0..count.div_ceil(2)).map(move |sym| match sym {
+        0 => [(true, high_ticks), (false, low_at(0))],
+        _ => [(false, low_at(2 * sym - 1)), (false, low_at(2 * sym))],
+    })
+}
+
+/// Shared handle to a running motion, used to cancel it from another task and
+/// to watch how far it got. Clones refer to the same motion.
+///
+/// Every handle derived from the same root also shares an emergency stop flag,
+/// which cancels all of them at once until it's cleared.
+#[derive(Clone, Default, Debug)]
+pub struct MotionCtl {
+    cancelled: Arc<AtomicBool>,
+    stopped: Arc<AtomicBool>,
+    steps_done: Arc<AtomicU64>, // f64 bits, full steps
+}
+
+impl MotionCtl {
+    /// A new progress tracker that is cancelled together with this one
+    pub fn child(&self) -> Self {
+        Self {
+            cancelled: self.cancelled.clone(),
+            stopped: self.stopped.clone(),
+            steps_done: Default::default(),
+        }
+    }
+
+    /// A new handle that can be cancelled on its own, only the emergency stop
+    /// is shared with this one
+    pub fn fork(&self) -> Self {
+        Self {
+            cancelled: Default::default(),
+            stopped: self.stopped.clone(),
+            steps_done: Default::default(),
+        }
+    }
+
+    /// Whether both handles control the same motion
+    pub fn is_same(&self, other: &MotionCtl) -> bool {
+        Arc::ptr_eq(&self.cancelled, &other.cancelled)
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed) || self.is_stopped()
+    }
+
+    /// Emergency stop every motion sharing this handle's root
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+
+    pub fn clear_stop(&self) {
+        self.stopped.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+
+    /// Full steps emitted so far by the last motion run with this handle
+    pub fn steps_done(&self) -> f64 {
+        f64::from_bits(self.steps_done.load(Ordering::Relaxed))
+    }
+
+    pub fn set_steps_done(&self, steps: f64) {
+        self.steps_done.store(steps.to_bits(), Ordering::Relaxed);
+    }
+}
+
+/// Hardware-agnostic interface to a single stepper motor, so the dosing logic
+/// in `app` doesn't depend on the RMT/GPIO types of a specific driver.
+/// Step counts are in full steps and can be fractional down to a microstep,
+/// positions are in 1/[`USTEPS_PER_STEP`] steps.
+pub trait Stepper: Send + 'static {
+    type Error: Debug + Send;
+
+    /// Stable identifier for the motor, used to match persisted config entries
+    fn id(&self) -> u32;
+
+    /// Move by a relative number of steps, negative values reverse direction.
+    /// Ramps down early if `ctl` gets cancelled, returns the signed number of
+    /// steps that were actually moved.
+    fn move_by(
+        &mut self,
+        steps: f64,
+        ctl: &MotionCtl,
+    ) -> impl Future<Output = Result<f64, Self::Error>> + Send;
+
+    fn get_position(&self) -> i64;
+
+    /// Overwrite the tracked position without moving, e.g. to restore it after a reboot
+    fn set_position(&mut self, position: i64);
+
+    fn reset_position(&mut self) {
+        self.set_position(0);
+    }
+
+    fn profile(&self) -> &MotionProfile;
+
+    /// Replace the motion profile, rejecting it if this driver can't run it
+    fn set_profile(&mut self, profile: MotionProfile) -> Result<(), String>;
+
+    /// Move by a relative number of steps without any way to interrupt it
+    fn step_by(&mut self, steps: f64) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        async move { self.move_by(steps, &MotionCtl::default()).await.map(|_| ()) }
+    }
+
+    /// Position in full steps
+    fn position_steps(&self) -> f64 {
+        self.get_position() as f64 / USTEPS_PER_STEP as f64
+    }
+
+    /// Move to an absolute position
+    fn goto(&mut self, target_pos: i64) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        let steps = target_pos.saturating_sub(self.get_position()) as f64 / USTEPS_PER_STEP as f64;
+        self.step_by(steps)
+    }
+}
+
+/// Convert a relative move in full steps to the units positions are kept in
+pub fn steps_to_position(steps: f64) -> i64 {
+    (steps * USTEPS_PER_STEP as f64).round() as i64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CLOCK_HZ: u32 = 2_000_000;
+    const HIGH_TICKS: u16 = 4;
+
+    fn profile_ticks(profile: &MotionProfile, steps: f64) -> u64 {
+        motion_profile(CLOCK_HZ, profile, steps, &MotionCtl::default())
+            .unwrap()
+            .map(|delay| delay as u64)
+            .sum::<u64>()
+            >> 8
+    }
+
+    fn check_train(profile: MotionProfile, steps: f64) {
+        profile.validate(CLOCK_HZ).unwrap();
+        let train: Vec<PulsePair> = pulse_train(
+            motion_profile(CLOCK_HZ, &profile, steps, &MotionCtl::default()).unwrap(),
+            HIGH_TICKS,
+        )
+        .collect();
+
+        let pulses = || train.iter().flatten();
+        assert!(pulses().all(|&(_, t)| t > 0 && t as u32 <= MAX_PULSE_TICKS));
+
+        let steps_sent = pulses().filter(|&&(high, _)| high).count();
+        assert_eq!(steps_sent as f64, profile.microsteps.scale(steps));
+
+        // Only the fractional tick of the very last step may be dropped
+        let total: u64 = pulses().map(|&(_, t)| t as u64).sum();
+        assert!(total.abs_diff(profile_ticks(&profile, steps)) <= 1);
+    }
+
+    #[test]
+    fn default_profile_matches_stepgen() {
+        check_train(MotionProfile::default(), 2000.0);
+    }
+
+    #[test]
+    fn slow_profile_splits_long_steps() {
+        // ~3 steps/s, every step is way longer than a single pulse can be
+        let profile = MotionProfile {
+            max_rpm: 1.0,
+            max_accel: 20.0,
+            microsteps: MicroSteps::M1,
+            ..Default::default()
+        };
+        check_train(profile, 10.0);
+    }
+
+    #[test]
+    fn rejects_accel_stepgen_cant_hold() {
+        let profile = |max_accel| MotionProfile {
+            max_accel,
+            microsteps: MicroSteps::M32,
+            ..Default::default()
+        };
+        // 2^24 pulses/s^2 would lose its top bits once shifted into 24.8
+        assert!(profile(MAX_STEPGEN_ACCEL as f64 / 32.0).validate(CLOCK_HZ).is_ok());
+        assert!(profile(524_288.0).validate(CLOCK_HZ).is_err());
+        assert!(profile(1e12).validate(CLOCK_HZ).is_err());
+        assert!(MotionProfile {
+            microsteps: MicroSteps::M1,
+            ..profile(524_288.0)
+        }
+        .validate(CLOCK_HZ)
+        .is_ok());
+    }
+
+    #[test]
+    fn quantized_moves_keep_position_exact() {
+        let ms = MicroSteps::M8;
+        assert_eq!(ms.quantize(156.25), 156.25);
+        assert_eq!(ms.quantize(156.3), 156.25);
+
+        // A thousand small moves end up exactly where one big one would
+        let position: i64 = (0..1000).map(|_| steps_to_position(ms.quantize(0.5 / 0.0032))).sum();
+        assert_eq!(position, steps_to_position(156_250.0));
+    }
+
+    #[test]
+    fn step_symbols_keep_period() {
+        for period in [5, 100, MAX_PULSE_TICKS, MAX_PULSE_TICKS + 1, 3 * MAX_PULSE_TICKS + 7, 1_000_000] {
+            let syms: Vec<PulsePair> = step_symbols(period, HIGH_TICKS).collect();
+            assert_eq!(syms[0][0], (true, HIGH_TICKS));
+            assert!(syms.iter().skip(1).flatten().all(|&(high, _)| !high));
+
+            let total: u32 = syms.iter().flatten().map(|&(_, t)| t as u32).sum();
+            assert_eq!(total, period);
+        }
+    }
+}
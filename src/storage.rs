@@ -0,0 +1,87 @@
+/// Minimal key/value store the app persists its config in. On the device this
+/// is backed by NVS, the simulator keeps everything in a JSON file instead.
+pub trait Storage: Send + Sync {
+    fn get_str(&self, key: &str) -> anyhow::Result<Option<String>>;
+
+    fn set_str(&mut self, key: &str, value: &str) -> anyhow::Result<()>;
+
+    /// Returns whether the key existed
+    fn remove(&mut self, key: &str) -> anyhow::Result<bool>;
+}
+
+#[cfg(target_os = "espidf")]
+mod nvs {
+    use esp_idf_svc::nvs::EspCustomNvs;
+
+    use super::Storage;
+
+    impl Storage for EspCustomNvs {
+        fn get_str(&self, key: &str) -> anyhow::Result<Option<String>> {
+            match self.str_len(key)? {
+                Some(len) => {
+                    let mut buf = vec![0_u8; len];
+                    Ok(EspCustomNvs::get_str(self, key, buf.as_mut_slice())?.map(str::to_owned))
+                }
+                None => Ok(None),
+            }
+        }
+
+        fn set_str(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+            Ok(EspCustomNvs::set_str(self, key, value)?)
+        }
+
+        fn remove(&mut self, key: &str) -> anyhow::Result<bool> {
+            Ok(EspCustomNvs::remove(self, key)?)
+        }
+    }
+}
+
+#[cfg(not(target_os = "espidf"))]
+pub use file::FileStorage;
+
+#[cfg(not(target_os = "espidf"))]
+mod file {
+    use std::{collections::BTreeMap, fs, io::ErrorKind, path::PathBuf};
+
+    use super::Storage;
+
+    /// Stores every key in a single JSON object, rewritten on each change
+    pub struct FileStorage {
+        path: PathBuf,
+        entries: BTreeMap<String, String>,
+    }
+
+    impl FileStorage {
+        pub fn open(path: impl Into<PathBuf>) -> anyhow::Result<Self> {
+            let path = path.into();
+            let entries = match fs::read_to_string(&path) {
+                Ok(contents) => serde_json::from_str(&contents)?,
+                Err(e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+                Err(e) => return Err(e.into()),
+            };
+            Ok(Self { path, entries })
+        }
+
+        fn flush(&self) -> anyhow::Result<()> {
+            fs::write(&self.path, serde_json::to_string_pretty(&self.entries)?)?;
+            Ok(())
+        }
+    }
+
+    impl Storage for FileStorage {
+        fn get_str(&self, key: &str) -> anyhow::Result<Option<String>> {
+            Ok(self.entries.get(key).cloned())
+        }
+
+        fn set_str(&mut self, key: &str, value: &str) -> anyhow::Result<()> {
+            self.entries.insert(key.to_owned(), value.to_owned());
+            self.flush()
+        }
+
+        fn remove(&mut self, key: &str) -> anyhow::Result<bool> {
+            let existed = self.entries.remove(key).is_some();
+            self.flush()?;
+            Ok(existed)
+        }
+    }
+}
@@ -1,18 +1,20 @@
 use esp_idf_svc::{
     ipv4::{
-        ClientConfiguration as IpClientConfiguration, Configuration as IpConfiguration,
-        DHCPClientSettings,
+        ClientConfiguration as IpClientConfiguration, ClientSettings as IpClientSettings,
+        Configuration as IpConfiguration, DHCPClientSettings, Mask, Subnet,
     },
-    netif::{EspNetif, NetifConfiguration, NetifStack},
-    nvs::{EspDefaultNvs, EspDefaultNvsPartition},
+    mdns::EspMdns,
+    netif::{EspNetif, NetifConfiguration},
     ota::EspOta,
 };
 
-use log::warn;
+use log::info;
 
-use crate::app::NVS_NS;
-
-const HOSTNAME_KEY: &str = "HOSTNAME";
+use crate::{
+    app::PORT,
+    discovery::{self, SERVICE_PROTO, SERVICE_TYPE},
+    network::{IpConfig, NetworkConfig},
+};
 
 pub fn set_ota_valid() {
     let mut ota = EspOta::new().expect("Instantiate EspOta");
@@ -20,35 +22,51 @@ pub fn set_ota_valid() {
         .expect("Mark app slot as valid");
 }
 
-pub fn get_netif_with_hostname(nvsp: EspDefaultNvsPartition) -> anyhow::Result<EspNetif> {
-    let mut nvs = EspDefaultNvs::new(nvsp, NVS_NS, true)?;
-    let hostname = match option_env!("HOSTNAME") {
-        Some(h) => h.to_owned(),
-        None => match nvs.str_len(HOSTNAME_KEY)? {
-            Some(len) => {
-                let mut buf = vec![0_u8; len];
-                match nvs.get_str(HOSTNAME_KEY, buf.as_mut_slice())? {
-                    Some(h) => h.to_owned(),
-                    None => String::new(),
-                }
-            }
-            None => String::new(),
-        },
+/// Station interface with the addressing and hostname of `config`
+pub fn sta_netif(config: &NetworkConfig) -> anyhow::Result<EspNetif> {
+    let hostname = match &config.hostname {
+        Some(h) => {
+            info!("Setting hostname to {h}");
+            Some(h.as_str().try_into().map_err(|_| anyhow::anyhow!("Hostname {h} is too long"))?)
+        }
+        None => None,
     };
-
-    if !hostname.is_empty() {
-        warn!("Setting hostname to {hostname}");
-        nvs.set_str(HOSTNAME_KEY, hostname.as_str())?;
-
-        Ok(EspNetif::new_with_conf(&NetifConfiguration {
-            ip_configuration: Some(IpConfiguration::Client(IpClientConfiguration::DHCP(
-                DHCPClientSettings {
-                    hostname: Some(hostname.as_str().try_into().unwrap()),
+    let ip_configuration = match config.ip {
+        IpConfig::Dhcp => IpClientConfiguration::DHCP(DHCPClientSettings { hostname }),
+        IpConfig::Static { ip, prefix, gateway, dns } => {
+            info!("Using static address {ip}/{prefix}");
+            IpClientConfiguration::Fixed(IpClientSettings {
+                ip,
+                subnet: Subnet {
+                    gateway,
+                    mask: Mask(prefix),
                 },
-            ))),
-            ..NetifConfiguration::wifi_default_client()
-        })?)
-    } else {
-        Ok(EspNetif::new(NetifStack::Sta)?)
+                dns: Some(dns.unwrap_or(gateway)),
+                secondary_dns: None,
+            })
+        }
+    };
+
+    let mut netif = EspNetif::new_with_conf(&NetifConfiguration {
+        ip_configuration: Some(IpConfiguration::Client(ip_configuration)),
+        ..NetifConfiguration::wifi_default_client()
+    })?;
+    // Only DHCP takes the hostname from the settings
+    if let (IpConfig::Static { .. }, Some(h)) = (config.ip, &config.hostname) {
+        netif.set_hostname(h)?;
     }
+    Ok(netif)
+}
+
+/// Advertises the API over mDNS, only for as long as the returned handle lives
+pub fn advertise(hostname: &str, device_id: &str, motors: usize) -> anyhow::Result<EspMdns> {
+    let txt = discovery::txt_records(device_id, motors);
+    let txt: Vec<(&str, &str)> = txt.iter().map(|(k, v)| (*k, v.as_str())).collect();
+
+    let mut mdns = EspMdns::take()?;
+    mdns.set_hostname(hostname)?;
+    mdns.set_instance_name(hostname)?;
+    mdns.add_service(Some(hostname), SERVICE_TYPE, SERVICE_PROTO, PORT, &txt)?;
+    info!("Advertising {hostname}.local as {SERVICE_TYPE}.{SERVICE_PROTO}");
+    Ok(mdns)
 }